@@ -0,0 +1,308 @@
+//! The decoder as it was before `BcDecoder`, kept to measure against.  It re-runs the parser over
+//! a growing buffer, reading only as many more bytes as nom asks for each time, and copies each
+//! binary payload out of that buffer.  The parser is the crate's own from before the change; only
+//! the private pieces it used (the header, the context, the XML "encryption" and telling the two
+//! kinds of top level XML apart) are copied in too.
+use bytes::Bytes;
+use err_derive::Error;
+use neolink::bc::model::*;
+use neolink::bc::xml::{BcXml, Extension};
+use nom::IResult;
+use nom::{bytes::streaming::take, combinator::*, number::streaming::*, sequence::*};
+use std::collections::HashSet;
+use std::io::Read;
+
+const MAGIC_HEADER: u32 = 0xabcdef0;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "Parsing error")]
+    NomError(&'static str),
+    #[error(display = "I/O error")]
+    IoError(#[error(source)] std::io::Error),
+}
+
+type NomErrorTuple<'a> = (&'a [u8], nom::error::ErrorKind);
+
+impl<'a> From<nom::Err<NomErrorTuple<'a>>> for Error {
+    fn from(k: nom::Err<NomErrorTuple<'a>>) -> Self {
+        let reason = match k {
+            nom::Err::Error(_) => "Nom Error",
+            nom::Err::Failure(_) => "Nom Failure",
+            _ => "Unknown Nom error",
+        };
+        Error::NomError(reason)
+    }
+}
+
+/// The exchanges, by message ID and number, that have switched to sending binary
+#[derive(Default)]
+pub struct Context {
+    in_bin_mode: HashSet<(u32, u16)>,
+}
+
+struct Header {
+    body_len: u32,
+    msg_id: u32,
+    channel_id: u8,
+    stream_type: u8,
+    msg_num: u16,
+    encrypted: bool,
+    class: u16,
+    bin_offset: Option<u32>,
+}
+
+impl Header {
+    fn is_modern(&self) -> bool {
+        self.class != 0x6514
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.encrypted || self.class == 0x6414
+    }
+
+    fn to_meta(&self) -> BcMeta {
+        BcMeta {
+            msg_id: self.msg_id,
+            channel_id: self.channel_id,
+            stream_type: self.stream_type,
+            msg_num: self.msg_num,
+            class: self.class,
+            encrypted: self.encrypted,
+        }
+    }
+}
+
+/// The XML of a modern message is either an Extension or a body
+// This file is a frozen copy of the old decoder, so lints are left as they were back then
+#[allow(clippy::large_enum_variant)]
+enum TopXml {
+    BcXml(BcXml),
+    Extension(Extension),
+}
+
+impl TopXml {
+    fn try_parse(xml: &[u8]) -> Result<Self, String> {
+        let text = String::from_utf8_lossy(xml);
+        if text.contains("<Extension") {
+            yaserde::de::from_reader(xml).map(TopXml::Extension)
+        } else {
+            BcXml::try_parse(xml).map(TopXml::BcXml)
+        }
+    }
+}
+
+fn has_bin_offset(class: u16) -> bool {
+    class == 0x6414 || class == 0x0000
+}
+
+const XML_KEY: [u8; 8] = [0x1F, 0x2D, 0x3C, 0x4B, 0x5A, 0x69, 0x78, 0xFF];
+
+fn crypt(offset: u32, buf: &[u8]) -> Vec<u8> {
+    let key_iter = XML_KEY.iter().cycle().skip(offset as usize % 8);
+    key_iter
+        .zip(buf)
+        .map(|(key, i)| *i ^ key ^ (offset as u8))
+        .collect()
+}
+
+pub fn deserialize<R: Read>(context: &mut Context, r: R) -> Result<Bc, Error> {
+    // Throw away the nom-specific return types
+    read_from_reader(|reader| bc_msg(context, reader), r)
+}
+
+fn read_from_reader<P, O, E, R>(mut parser: P, mut rdr: R) -> Result<O, E>
+where
+    R: Read,
+    E: for<'a> From<nom::Err<NomErrorTuple<'a>>> + From<std::io::Error>,
+    P: FnMut(&[u8]) -> nom::IResult<&[u8], O>,
+{
+    let mut input: Vec<u8> = Vec::new();
+    loop {
+        let to_read = match parser(&input) {
+            Ok((_, parsed)) => return Ok(parsed),
+            Err(nom::Err::Incomplete(needed)) => {
+                match needed {
+                    nom::Needed::Unknown => 1, // read one byte
+                    nom::Needed::Size(len) => len,
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if 0 == (&mut rdr).take(to_read as u64).read_to_end(&mut input)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Read returned 0 bytes",
+            )
+            .into());
+        }
+    }
+}
+
+#[allow(clippy::needless_lifetimes)] // Frozen copy, as above
+fn bc_msg<'a, 'b>(context: &'a mut Context, buf: &'b [u8]) -> IResult<&'b [u8], Bc> {
+    let (buf, header) = bc_header(buf)?;
+    let (buf, body) = bc_body(context, &header, buf)?;
+
+    let bc = Bc {
+        meta: header.to_meta(),
+        body,
+    };
+
+    Ok((buf, bc))
+}
+
+#[allow(clippy::needless_lifetimes)] // Frozen copy, as above
+fn bc_body<'a, 'b, 'c>(
+    context: &'c mut Context,
+    header: &'a Header,
+    buf: &'b [u8],
+) -> IResult<&'b [u8], BcBody> {
+    if header.is_modern() {
+        let (buf, body) = bc_modern_msg(context, header, buf)?;
+        Ok((buf, BcBody::ModernMsg(body)))
+    } else {
+        let (buf, body_buf) = take(header.body_len)(buf)?;
+        let body = match header.msg_id {
+            MSG_ID_LOGIN => bc_legacy_login_msg(body_buf)?.1,
+            _ => LegacyMsg::UnknownMsg,
+        };
+        Ok((buf, BcBody::LegacyMsg(body)))
+    }
+}
+
+fn hex32<'a>() -> impl Fn(&'a [u8]) -> IResult<&'a [u8], String> {
+    map_res(take(32usize), |slice: &'a [u8]| {
+        String::from_utf8(slice.to_vec())
+    })
+}
+
+fn bc_legacy_login_msg(buf: &[u8]) -> IResult<&[u8], LegacyMsg> {
+    let (buf, username) = hex32()(buf)?;
+    let (buf, password) = hex32()(buf)?;
+
+    Ok((buf, LegacyMsg::LoginMsg { username, password }))
+}
+
+#[allow(clippy::needless_lifetimes)] // Frozen copy, as above
+fn bc_modern_msg<'a, 'b>(
+    context: &mut Context,
+    header: &'a Header,
+    buf: &'b [u8],
+) -> IResult<&'b [u8], ModernMsg> {
+    use nom::{
+        error::{make_error, ErrorKind},
+        Err,
+    };
+
+    let mut in_bin_mode = context
+        .in_bin_mode
+        .contains(&(header.msg_id, header.msg_num));
+
+    let end_of_xml = if in_bin_mode {
+        0
+    } else {
+        match header.bin_offset {
+            Some(off) if off > 0 => off,
+            _ => header.body_len,
+        }
+    };
+
+    let (mut buf, body_buf) = take(end_of_xml)(buf)?;
+
+    let decrypted;
+    let processed_body_buf = if !header.is_encrypted() {
+        buf
+    } else {
+        decrypted = crypt(header.channel_id as u32, body_buf);
+        &decrypted
+    };
+
+    let mut extension = None;
+    let mut xml = None;
+    let mut binary = None;
+    if end_of_xml > 0 {
+        let parsed = TopXml::try_parse(processed_body_buf)
+            .map_err(|_| Err::Error(make_error(buf, ErrorKind::MapRes)))?;
+
+        match parsed {
+            TopXml::BcXml(x) => {
+                xml = Some(x);
+            }
+            TopXml::Extension(ext) => {
+                if ext.binary_data == Some(1) {
+                    in_bin_mode = true;
+                }
+                extension = Some(ext);
+            }
+        }
+    }
+
+    if in_bin_mode {
+        if let Some(bin_offset) = header.bin_offset {
+            let (buf_after, payload) =
+                map(take(header.body_len - bin_offset), |x: &[u8]| x.to_vec())(buf)?;
+
+            context.in_bin_mode.insert((header.msg_id, header.msg_num));
+
+            // The model holds Bytes now; taking over the Vec doesn't copy it again
+            binary = Some(Bytes::from(payload));
+            buf = buf_after;
+        } else {
+            return Err(Err::Error(make_error(buf, ErrorKind::Verify)));
+        }
+    } else if extension.is_some() && end_of_xml < header.body_len {
+        let (buf_after, payload_buf) = take(header.body_len - end_of_xml)(buf)?;
+
+        let decrypted;
+        let processed_payload_buf = if !header.is_encrypted() {
+            payload_buf
+        } else {
+            decrypted = crypt(header.channel_id as u32, payload_buf);
+            &decrypted
+        };
+        let parsed = BcXml::try_parse(processed_payload_buf)
+            .map_err(|_| Err::Error(make_error(buf, ErrorKind::MapRes)))?;
+
+        xml = Some(parsed);
+        buf = buf_after;
+    }
+
+    Ok((
+        buf,
+        ModernMsg {
+            extension,
+            xml,
+            binary,
+        },
+    ))
+}
+
+fn bc_header(buf: &[u8]) -> IResult<&[u8], Header> {
+    let (buf, _magic) = verify(le_u32, |x| *x == MAGIC_HEADER)(buf)?;
+    let (buf, msg_id) = le_u32(buf)?;
+    let (buf, body_len) = le_u32(buf)?;
+    let (buf, channel_id) = le_u8(buf)?;
+    let (buf, stream_type) = le_u8(buf)?;
+    let (buf, msg_num) = le_u16(buf)?;
+    let (buf, (response_code, _ignored, class)) = tuple((le_u8, le_u8, le_u16))(buf)?;
+
+    let encrypted = response_code != 0;
+
+    let (buf, bin_offset) = cond(has_bin_offset(class), le_u32)(buf)?;
+
+    Ok((
+        buf,
+        Header {
+            msg_id,
+            body_len,
+            channel_id,
+            stream_type,
+            msg_num,
+            encrypted,
+            class,
+            bin_offset,
+        },
+    ))
+}
@@ -0,0 +1,113 @@
+//! Measures the CPU time it takes to decode a recorded video stream as it arrives over a loopback
+//! TCP connection, per megabit of stream.  Only the decoding thread's time is counted, not that of
+//! the thread sending the stream.  Run with `cargo bench --bench decode`.
+use cpu_time::ThreadTime;
+use neolink::bc::de::BcDecoder;
+use neolink::bc::model::*;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod baseline;
+
+/// How many times the recorded video message is repeated to make up the stream
+const MESSAGES: usize = 2000;
+/// Each way of decoding is timed this many times, and the fastest time kept
+const ROUNDS: usize = 10;
+
+fn main() {
+    let video_start = include_bytes!("../../src/bc/samples/modern_video_start1.bin");
+    let video_data = include_bytes!("../../src/bc/samples/modern_video_start2.bin");
+    let mut stream = video_start.to_vec();
+    for _ in 0..MESSAGES {
+        stream.extend_from_slice(video_data);
+    }
+    let stream = Arc::new(stream);
+    let mbit = stream.len() as f64 * 8.0 / 1_000_000.0;
+    println!("Decoding {:.0} Mbit of video", mbit);
+
+    report(
+        "Re-parsing reader from before BcDecoder",
+        mbit,
+        best_of(&stream, |mut conn| {
+            let mut context = baseline::Context::default();
+            let mut binary_len = 0;
+            while let Ok(bc) = baseline::deserialize(&mut context, &mut conn) {
+                binary_len += binary_len_of(bc);
+            }
+            binary_len
+        }),
+    );
+
+    report(
+        "Bc::deserialize, a message at a time",
+        mbit,
+        best_of(&stream, |mut conn| {
+            let mut context = BcContext::new();
+            let mut binary_len = 0;
+            while let Ok(bc) = Bc::deserialize(&mut context, &mut conn) {
+                binary_len += binary_len_of(bc);
+            }
+            binary_len
+        }),
+    );
+
+    report(
+        "BcDecoder, a buffer at a time",
+        mbit,
+        best_of(&stream, |mut conn| {
+            let mut decoder = BcDecoder::new();
+            let mut binary_len = 0;
+            while let Ok(bc) = decoder.read_from(&mut conn) {
+                binary_len += binary_len_of(bc);
+            }
+            binary_len
+        }),
+    );
+}
+
+fn binary_len_of(bc: Bc) -> usize {
+    match bc.body {
+        BcBody::ModernMsg(ModernMsg {
+            binary: Some(binary),
+            ..
+        }) => binary.len(),
+        _ => 0,
+    }
+}
+
+/// Times `decode` reading `stream` from a connection until it hangs up.  It returns how much
+/// binary it decoded, so that none of the work can be optimized away.
+fn best_of(stream: &Arc<Vec<u8>>, mut decode: impl FnMut(TcpStream) -> usize) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0..ROUNDS {
+        let to_send = stream.clone();
+        let sender = std::thread::spawn(move || {
+            TcpStream::connect(address)
+                .unwrap()
+                .write_all(&to_send)
+                .unwrap();
+        });
+        let (conn, _) = listener.accept().unwrap();
+
+        let start = ThreadTime::now();
+        let decoded = decode(conn);
+        best = best.min(start.elapsed());
+
+        sender.join().unwrap();
+        assert!(decoded > 0);
+    }
+    best
+}
+
+fn report(name: &str, mbit: f64, time: Duration) {
+    println!(
+        "{}: {:.1} us per Mbit ({:.0} Mbit/s)",
+        name,
+        time.as_secs_f64() * 1_000_000.0 / mbit,
+        mbit / time.as_secs_f64()
+    );
+}
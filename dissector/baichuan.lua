@@ -45,6 +45,7 @@ message_types = {
   [7]="<FileInfoList> (stop)",
   [8]="<FileInfoList> (DL Video)",
   [10]="<TalkAbility>",
+  [11]="Talk reset",
   [13]="<FileInfoList> (download)",
   [14]="<FileInfoList>",
   [15]="<FileInfoList>",
@@ -123,6 +124,8 @@ message_types = {
   [190]="PTZ Preset",
   [194]="<Ftp> (test)",
   [199]="<Support>",
+  [201]="<TalkConfig>",
+  [202]="Talk data",
   [208]="<LedState>",
   [209]="<LedState> (write)",
   [210]="<PTOP>",
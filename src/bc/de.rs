@@ -1,6 +1,7 @@
 use super::model::*;
-use super::xml::{AllTopXmls, Extension};
+use super::xml::{AllTopXmls, BcXml};
 use super::xml_crypto;
+use bytes::{Bytes, BytesMut};
 use err_derive::Error;
 use log::*;
 use nom::IResult;
@@ -28,66 +29,137 @@ impl<'a> From<nom::Err<NomErrorTuple<'a>>> for Error {
     }
 }
 
+/// How much more to ask the reader for at a time, when a message hasn't all arrived
+const READ_SIZE: usize = 64 * 1024;
+/// The longest a header can be: 20 bytes, and in most messages a 4 byte binary offset
+const MAX_HEADER_LEN: usize = 24;
+
 impl Bc {
-    pub fn deserialize<R: Read>(context: &mut BcContext, r: R) -> Result<Bc, Error> {
-        // Throw away the nom-specific return types
-        read_from_reader(|reader| bc_msg(context, reader), r)
+    /// Reads one message from `r`, reading no further than its end.  For reading message after
+    /// message from a connection, a `BcDecoder` reads in larger pieces.
+    pub fn deserialize<R: Read>(context: &mut BcContext, mut r: R) -> Result<Bc, Error> {
+        let mut buf = BytesMut::with_capacity(MAX_HEADER_LEN);
+        let len = loop {
+            if let Some(len) = message_len(&buf)? {
+                break len;
+            }
+            // The header is 20 bytes, then 4 more if it has a binary offset
+            let to_read = if buf.len() < 20 { 20 - buf.len() } else { 4 };
+            read_exact_into(&mut r, &mut buf, to_read)?;
+        };
+        let to_read = len - buf.len();
+        read_exact_into(&mut r, &mut buf, to_read)?;
+        bc_msg(context, &buf.freeze())
     }
 }
 
-fn read_from_reader<P, O, E, R>(mut parser: P, mut rdr: R) -> Result<O, E>
-where
-    R: Read,
-    E: for<'a> From<nom::Err<NomErrorTuple<'a>>> + From<std::io::Error>,
-    P: FnMut(&[u8]) -> nom::IResult<&[u8], O>,
-{
-    let mut input: Vec<u8> = Vec::new();
-    loop {
-        let to_read = match parser(&input) {
-            Ok((_, parsed)) => return Ok(parsed),
-            Err(nom::Err::Incomplete(needed)) => {
-                match needed {
-                    nom::Needed::Unknown => 1, // read one byte
-                    nom::Needed::Size(len) => len,
-                }
-            }
-            Err(e) => return Err(e.into()),
+/// Cuts messages out of a byte stream as it arrives.  The stream is read in large pieces into a
+/// buffer that is reused once the messages cut from it are dropped, and binary payloads are
+/// slices of that buffer rather than copies.
+#[derive(Default)]
+pub struct BcDecoder {
+    context: BcContext,
+    buf: BytesMut,
+}
+
+impl BcDecoder {
+    pub fn new() -> BcDecoder {
+        Default::default()
+    }
+
+    /// Where bytes go when the caller does its own reading, before calling `decode`
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Whether there are bytes left over that aren't yet a whole message
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Cuts the first message out of the buffer, or returns None if it hasn't all arrived yet
+    pub fn decode(&mut self) -> Result<Option<Bc>, Error> {
+        let len = match message_len(&self.buf)? {
+            Some(len) if len <= self.buf.len() => len,
+            _ => return Ok(None),
         };
+        let frame = self.buf.split_to(len).freeze();
+        bc_msg(&mut self.context, &frame).map(Some)
+    }
 
-        if 0 == (&mut rdr).take(to_read as u64).read_to_end(&mut input)? {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "Read returned 0 bytes",
-            )
-            .into());
+    /// Reads from `r` until a whole message has arrived, then returns it
+    pub fn read_from<R: Read>(&mut self, mut r: R) -> Result<Bc, Error> {
+        loop {
+            if let Some(bc) = self.decode()? {
+                return Ok(bc);
+            }
+            let start = self.buf.len();
+            self.buf.resize(start + READ_SIZE, 0);
+            let read = match r.read(&mut self.buf[start..]) {
+                Ok(read) => read,
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e.into());
+                }
+            };
+            self.buf.truncate(start + read);
+            if read == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Read returned 0 bytes",
+                )
+                .into());
+            }
         }
     }
 }
 
-fn bc_msg<'a, 'b>(context: &'a mut BcContext, buf: &'b [u8]) -> IResult<&'b [u8], Bc> {
-    let (buf, header) = bc_header(buf)?;
-    let (buf, body) = bc_body(context, &header, buf)?;
+/// The length of the message starting at `buf`, header and all, once its header has arrived
+fn message_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    match bc_header(buf) {
+        Ok((rest, header)) => Ok(Some(buf.len() - rest.len() + header.body_len as usize)),
+        Err(nom::Err::Incomplete(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let bc = Bc {
+fn read_exact_into<R: Read>(r: &mut R, buf: &mut BytesMut, len: usize) -> std::io::Result<()> {
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    let read = r.read_exact(&mut buf[start..]);
+    if read.is_err() {
+        buf.truncate(start);
+    }
+    read
+}
+
+/// Parses a whole message.  Its binary payload, if any, is a slice of `frame`.
+fn bc_msg(context: &mut BcContext, frame: &Bytes) -> Result<Bc, Error> {
+    let (buf, header) = bc_header(frame)?;
+    let (_, body) = bc_body(context, &header, frame, buf)?;
+
+    Ok(Bc {
         meta: header.to_meta(),
         body,
-    };
-
-    Ok((buf, bc))
+    })
 }
 
 fn bc_body<'a, 'b, 'c>(
     context: &'c mut BcContext,
     header: &'a BcHeader,
+    frame: &Bytes,
     buf: &'b [u8],
 ) -> IResult<&'b [u8], BcBody> {
     if header.is_modern() {
-        let (buf, body) = bc_modern_msg(context, header, buf)?;
+        let (buf, body) = bc_modern_msg(context, header, frame, buf)?;
         Ok((buf, BcBody::ModernMsg(body)))
     } else {
-        let (buf, body) = match header.msg_id {
-            MSG_ID_LOGIN => bc_legacy_login_msg(buf)?,
-            _ => (buf, LegacyMsg::UnknownMsg),
+        // Legacy bodies are padded out past what we read of them, so take the whole body first to
+        // leave the next message at the start of the buffer
+        let (buf, body_buf) = take(header.body_len)(buf)?;
+        let body = match header.msg_id {
+            MSG_ID_LOGIN => bc_legacy_login_msg(body_buf)?.1,
+            _ => LegacyMsg::UnknownMsg,
         };
         Ok((buf, BcBody::LegacyMsg(body)))
     }
@@ -109,6 +181,7 @@ fn bc_legacy_login_msg<'a>(buf: &'a [u8]) -> IResult<&'a [u8], LegacyMsg> {
 fn bc_modern_msg<'a, 'b>(
     context: &mut BcContext,
     header: &'a BcHeader,
+    frame: &Bytes,
     buf: &'b [u8],
 ) -> IResult<&'b [u8], ModernMsg> {
     use nom::{
@@ -116,7 +189,9 @@ fn bc_modern_msg<'a, 'b>(
         Err,
     };
 
-    let mut in_bin_mode = context.in_bin_mode.contains(&header.msg_id);
+    let mut in_bin_mode = context
+        .in_bin_mode
+        .contains(&(header.msg_id, header.msg_num));
 
     // We'd like to know where the XML stops, but we haven't parsed the XML yet to see if the
     // binaryData offset in the header is valid
@@ -135,11 +210,12 @@ fn bc_modern_msg<'a, 'b>(
     let processed_body_buf = if !header.is_encrypted() {
         buf
     } else {
-        decrypted = xml_crypto::crypt(header.enc_offset, body_buf);
+        decrypted = xml_crypto::crypt(header.channel_id as u32, body_buf);
         &decrypted
     };
 
     // Now we'll take the buffer that Nom gave a ref to and parse it.
+    let mut extension = None;
     let mut xml = None;
     let mut binary = None;
     if end_of_xml > 0 {
@@ -150,13 +226,17 @@ fn bc_modern_msg<'a, 'b>(
 
         // If this is the first message containing binary, the Extension message puts the message
         // ID into binary mode, then the first binary is sent after the XML.  All remaining
-        // messages for that ID are pure binary.
+        // messages for that ID are pure binary.  Other Extensions (such as one only naming a
+        // channel) are simply followed by an ordinary XML payload.
         match parsed {
             AllTopXmls::BcXml(x) => {
                 xml = Some(x);
             }
-            AllTopXmls::Extension(Extension { binary_data: _ }) => {
-                in_bin_mode = true;
+            AllTopXmls::Extension(ext) => {
+                if ext.binary_data == Some(1) {
+                    in_bin_mode = true;
+                }
+                extension = Some(ext);
             }
         }
     }
@@ -165,14 +245,15 @@ fn bc_modern_msg<'a, 'b>(
     if in_bin_mode {
         if let Some(bin_offset) = header.bin_offset {
             // Extract remainder of message as binary, if it exists
-            let (buf_after, payload) =
-                map(take(header.body_len - bin_offset), |x: &[u8]| x.to_vec())(buf)?;
+            let (buf_after, payload) = map(take(header.body_len - bin_offset), |x: &[u8]| {
+                frame.slice_ref(x)
+            })(buf)?;
 
             // Since the parser operates in streaming mode, must wait until after we successfully
             // receive enough bytes before modifying the context (otherwise we'll alter the
             // behavior of future passes of this function even if we didn't yet consume the
             // message).
-            context.in_bin_mode.insert(header.msg_id);
+            context.in_bin_mode.insert((header.msg_id, header.msg_num));
 
             binary = Some(payload);
             buf = buf_after;
@@ -181,16 +262,40 @@ fn bc_modern_msg<'a, 'b>(
             error!("Expected header to contain a binary offset, but there was none");
             return Err(Err::Error(make_error(buf, ErrorKind::Verify)));
         }
+    } else if extension.is_some() && end_of_xml < header.body_len {
+        let (buf_after, payload_buf) = take(header.body_len - end_of_xml)(buf)?;
+
+        let decrypted;
+        let processed_payload_buf = if !header.is_encrypted() {
+            payload_buf
+        } else {
+            decrypted = xml_crypto::crypt(header.channel_id as u32, payload_buf);
+            &decrypted
+        };
+        let parsed = BcXml::try_parse(processed_payload_buf)
+            .map_err(|_| Err::Error(make_error(buf, ErrorKind::MapRes)))?;
+
+        xml = Some(parsed);
+        buf = buf_after;
     }
 
-    Ok((buf, ModernMsg { xml, binary }))
+    Ok((
+        buf,
+        ModernMsg {
+            extension,
+            xml,
+            binary,
+        },
+    ))
 }
 
 fn bc_header(buf: &[u8]) -> IResult<&[u8], BcHeader> {
     let (buf, _magic) = verify(le_u32, |x| *x == MAGIC_HEADER)(buf)?;
     let (buf, msg_id) = le_u32(buf)?;
     let (buf, body_len) = le_u32(buf)?;
-    let (buf, enc_offset) = le_u32(buf)?;
+    let (buf, channel_id) = le_u8(buf)?;
+    let (buf, stream_type) = le_u8(buf)?;
+    let (buf, msg_num) = le_u16(buf)?;
     let (buf, (response_code, _ignored, class)) = tuple((le_u8, le_u8, le_u16))(buf)?;
 
     // All modern messages are encrypted.  In addition, it seems that the camera firmware checks
@@ -205,7 +310,9 @@ fn bc_header(buf: &[u8]) -> IResult<&[u8], BcHeader> {
         BcHeader {
             msg_id,
             body_len,
-            enc_offset,
+            channel_id,
+            stream_type,
+            msg_num,
             encrypted,
             class,
             bin_offset,
@@ -219,15 +326,19 @@ fn test_bc_modern_login() {
 
     let mut context = BcContext::new();
 
+    let sample = Bytes::from_static(sample);
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let (_, body) = bc_body(&mut context, &header, &sample, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 145);
-    assert_eq!(header.enc_offset, 0x1000000);
+    assert_eq!(header.channel_id, 0);
+    assert_eq!(header.stream_type, 0);
+    assert_eq!(header.msg_num, 0x100);
     assert_eq!(header.encrypted, true);
     assert_eq!(header.class, 0x6614);
     match body {
         BcBody::ModernMsg(ModernMsg {
+            extension: None,
             xml: Some(ref xml),
             binary: None,
         }) => assert_eq!(xml.encryption.as_ref().unwrap().nonce, "9E6D1FCB9E69846D"),
@@ -241,11 +352,14 @@ fn test_bc_legacy_login() {
 
     let mut context = BcContext::new();
 
+    let sample = Bytes::from_static(sample);
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let (_, body) = bc_body(&mut context, &header, &sample, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 1836);
-    assert_eq!(header.enc_offset, 0x1000000);
+    assert_eq!(header.channel_id, 0);
+    assert_eq!(header.stream_type, 0);
+    assert_eq!(header.msg_num, 0x100);
     assert_eq!(header.encrypted, true);
     assert_eq!(header.class, 0x6514);
     match body {
@@ -263,15 +377,19 @@ fn test_bc_modern_login_failed() {
 
     let mut context = BcContext::new();
 
+    let sample = Bytes::from_static(sample);
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let (_, body) = bc_body(&mut context, &header, &sample, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 0);
-    assert_eq!(header.enc_offset, 0x0);
+    assert_eq!(header.channel_id, 0);
+    assert_eq!(header.stream_type, 0);
+    assert_eq!(header.msg_num, 0);
     assert_eq!(header.encrypted, true);
     assert_eq!(header.class, 0x0000);
     match body {
         BcBody::ModernMsg(ModernMsg {
+            extension: None,
             xml: None,
             binary: None,
         }) => {
@@ -287,11 +405,14 @@ fn test_bc_modern_login_success() {
 
     let mut context = BcContext::new();
 
+    let sample = Bytes::from_static(sample);
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let (_, body) = bc_body(&mut context, &header, &sample, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 2949);
-    assert_eq!(header.enc_offset, 0x0);
+    assert_eq!(header.channel_id, 0);
+    assert_eq!(header.stream_type, 0);
+    assert_eq!(header.msg_num, 0);
     assert_eq!(header.encrypted, true);
     assert_eq!(header.class, 0x0000);
 
@@ -299,6 +420,7 @@ fn test_bc_modern_login_success() {
     // Test that we decoded XML and no binary.
     match body {
         BcBody::ModernMsg(ModernMsg {
+            extension: None,
             xml: Some(_),
             binary: None,
         }) => assert!(true),
@@ -317,6 +439,7 @@ fn test_bc_binary_mode() {
     let msg2 = Bc::deserialize(&mut context, &sample2[..]).unwrap();
     match msg1.body {
         BcBody::ModernMsg(ModernMsg {
+            extension: Some(_),
             xml: None,
             binary: Some(bin),
         }) => {
@@ -326,6 +449,7 @@ fn test_bc_binary_mode() {
     }
     match msg2.body {
         BcBody::ModernMsg(ModernMsg {
+            extension: None,
             xml: None,
             binary: Some(bin),
         }) => {
@@ -334,3 +458,82 @@ fn test_bc_binary_mode() {
         _ => assert!(false),
     }
 }
+
+#[test]
+fn test_bc_modern_logout() {
+    let sample = include_bytes!("samples/modern_logout.bin");
+
+    let mut context = BcContext::new();
+
+    let sample = Bytes::from_static(sample);
+    let (buf, header) = bc_header(&sample[..]).unwrap();
+    let (_, body) = bc_body(&mut context, &header, &sample, buf).unwrap();
+    assert_eq!(header.msg_id, 2);
+    assert_eq!(header.body_len, 193);
+    assert_eq!(header.class, 0x6414);
+    match body {
+        BcBody::ModernMsg(ModernMsg {
+            extension: None,
+            xml: Some(ref xml),
+            binary: None,
+        }) => {
+            // The logout message sends the credentials in plain text
+            let login_user = xml.login_user.as_ref().unwrap();
+            assert_eq!(login_user.user_name, "PlainTextUsername");
+            assert_eq!(login_user.password, "PlainTextPASSWORD");
+        }
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn test_bc_modern_logout_reply() {
+    let sample = include_bytes!("samples/modern_logout_reply.bin");
+
+    let mut context = BcContext::new();
+
+    let sample = Bytes::from_static(sample);
+    let (buf, header) = bc_header(&sample[..]).unwrap();
+    let (_, body) = bc_body(&mut context, &header, &sample, buf).unwrap();
+    assert_eq!(header.msg_id, 2);
+    assert_eq!(header.body_len, 0);
+    assert_eq!(header.class, 0x0000);
+    match body {
+        BcBody::ModernMsg(ModernMsg {
+            extension: None,
+            xml: None,
+            binary: None,
+        }) => assert!(true),
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn test_decoder() {
+    let login = include_bytes!("samples/model_sample_legacy_login.bin");
+    let sample1 = include_bytes!("samples/modern_video_start1.bin");
+    let sample2 = include_bytes!("samples/modern_video_start2.bin");
+    let stream = [&login[..], &sample1[..], &sample2[..]].concat();
+
+    // The bytes arrive in pieces that don't line up with the messages
+    let mut decoder = BcDecoder::new();
+    let mut decoded = vec![];
+    for piece in stream.chunks(1000) {
+        decoder.buffer_mut().extend_from_slice(piece);
+        while let Some(bc) = decoder.decode().unwrap() {
+            decoded.push(bc);
+        }
+    }
+    assert!(decoder.is_empty());
+    assert_eq!(decoded.len(), 3);
+    assert!(matches!(
+        decoded[0].body,
+        BcBody::LegacyMsg(LegacyMsg::LoginMsg { .. })
+    ));
+    match &decoded[2].body {
+        BcBody::ModernMsg(ModernMsg {
+            binary: Some(bin), ..
+        }) => assert_eq!(bin[..], sample2[sample2.len() - 30344..]),
+        _ => assert!(false),
+    }
+}
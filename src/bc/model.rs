@@ -1,13 +1,26 @@
-use super::xml::BcXml;
+use super::xml::{BcXml, Extension};
+use bytes::Bytes;
 use std::collections::HashSet;
 
 pub(super) const MAGIC_HEADER: u32 = 0xabcdef0;
 
 pub const MSG_ID_LOGIN: u32 = 1;
+pub const MSG_ID_LOGOUT: u32 = 2;
 pub const MSG_ID_VIDEO: u32 = 3;
+pub const MSG_ID_VIDEO_STOP: u32 = 4;
+pub const MSG_ID_TALK_ABILITY: u32 = 10;
+pub const MSG_ID_TALK_RESET: u32 = 11;
+pub const MSG_ID_PTZ_CONTROL: u32 = 18;
+pub const MSG_ID_PTZ_CONTROL_PRESET: u32 = 19;
+pub const MSG_ID_MOTION_REQUEST: u32 = 31;
+pub const MSG_ID_MOTION: u32 = 33;
+pub const MSG_ID_VERSION: u32 = 80;
 pub const MSG_ID_PING: u32 = 93;
 pub const MSG_ID_GET_GENERAL: u32 = 104;
 pub const MSG_ID_SET_GENERAL: u32 = 105;
+pub const MSG_ID_GET_PTZ_PRESET: u32 = 190;
+pub const MSG_ID_TALK_CONFIG: u32 = 201;
+pub const MSG_ID_TALK: u32 = 202;
 
 pub const EMPTY_LEGACY_PASSWORD: &str =
     "\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
@@ -18,16 +31,22 @@ pub struct Bc {
     pub body: BcBody,
 }
 
+// Nearly every message is modern, so boxing it to shrink the rare legacy one isn't worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq, Eq)]
 pub enum BcBody {
     LegacyMsg(LegacyMsg),
     ModernMsg(ModernMsg),
 }
 
+/// A modern message body.  An optional Extension XML comes first, and the header's binary offset
+/// points just past it; then follows either the XML body or, once the Extension has switched the
+/// message ID into binary mode, raw binary data.
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct ModernMsg {
+    pub extension: Option<Extension>,
     pub xml: Option<BcXml>,
-    pub binary: Option<Vec<u8>>,
+    pub binary: Option<Bytes>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -40,7 +59,9 @@ pub enum LegacyMsg {
 pub(super) struct BcHeader {
     pub body_len: u32,
     pub msg_id: u32,
-    pub enc_offset: u32,
+    pub channel_id: u8,
+    pub stream_type: u8,
+    pub msg_num: u16,
     pub encrypted: bool,
     pub class: u16,
     pub bin_offset: Option<u32>,
@@ -51,7 +72,13 @@ pub(super) struct BcHeader {
 #[derive(Debug, PartialEq, Eq)]
 pub struct BcMeta {
     pub msg_id: u32,
-    pub client_idx: u32,
+    /// Also the offset of the XML encryption key
+    pub channel_id: u8,
+    pub stream_type: u8,
+    /// Tells apart exchanges that share a message ID.  The camera answers a request with the
+    /// number it was sent with, and keeps using it for the stream of replies to a request for
+    /// video.
+    pub msg_num: u16,
     pub class: u16,
     pub encrypted: bool,
 }
@@ -66,7 +93,8 @@ pub(super) struct BcSendInfo {
 
 #[derive(Debug)]
 pub struct BcContext {
-    pub(super) in_bin_mode: HashSet<u32>,
+    /// The exchanges, by message ID and number, that have switched to sending binary
+    pub(super) in_bin_mode: HashSet<(u32, u16)>,
 }
 
 impl Bc {
@@ -76,11 +104,25 @@ impl Bc {
         Bc {
             meta,
             body: BcBody::ModernMsg(ModernMsg {
+                extension: None,
                 xml: Some(xml),
                 binary: None,
             }),
         }
     }
+
+    /// Convenience function that constructs a modern Bc message carrying only an Extension, as
+    /// used by requests that just need to name a channel.
+    pub fn new_from_ext(meta: BcMeta, ext: Extension) -> Bc {
+        Bc {
+            meta,
+            body: BcBody::ModernMsg(ModernMsg {
+                extension: Some(ext),
+                xml: None,
+                binary: None,
+            }),
+        }
+    }
 }
 
 impl Default for BcContext {
@@ -119,7 +161,9 @@ impl BcHeader {
     pub fn to_meta(&self) -> BcMeta {
         BcMeta {
             msg_id: self.msg_id,
-            client_idx: self.enc_offset,
+            channel_id: self.channel_id,
+            stream_type: self.stream_type,
+            msg_num: self.msg_num,
             class: self.class,
             encrypted: self.encrypted,
         }
@@ -130,7 +174,9 @@ impl BcHeader {
             bin_offset,
             body_len,
             msg_id: meta.msg_id,
-            enc_offset: meta.client_idx,
+            channel_id: meta.channel_id,
+            stream_type: meta.stream_type,
+            msg_num: meta.msg_num,
             class: meta.class,
             encrypted: meta.encrypted,
         }
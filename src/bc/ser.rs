@@ -1,5 +1,5 @@
 use super::model::*;
-use super::xml::BcXml;
+use super::xml::{BcXml, Extension};
 use super::xml_crypto;
 use cookie_factory::bytes::*;
 use cookie_factory::sequence::tuple;
@@ -17,14 +17,27 @@ impl Bc {
         let bin_offset;
         match &self.body {
             BcBody::ModernMsg(ref modern) => {
-                let (buf, xml_len) = gen(
-                    opt_ref(&modern.xml, |xml| bc_xml(self.meta.client_idx, xml)),
+                let (buf, ext_len) = gen(
+                    opt_ref(&modern.extension, |ext| {
+                        bc_ext(self.meta.channel_id as u32, ext)
+                    }),
                     vec![],
                 )?;
+                let (mut buf, xml_len) = gen(
+                    opt_ref(&modern.xml, |xml| bc_xml(self.meta.channel_id as u32, xml)),
+                    buf,
+                )?;
+                // The binary part is not encrypted, but it still counts towards the body length
+                if let Some(binary) = &modern.binary {
+                    buf.extend_from_slice(binary);
+                }
                 body_buf = buf;
                 bin_offset = if has_bin_offset(self.meta.class) {
-                    // If we're required to put binary length, put 0 if we have no binary
-                    Some(if modern.binary.is_some() {
+                    // If we're required to put binary length, put 0 if we have no binary.  An
+                    // Extension always ends at the binary offset.
+                    Some(if modern.extension.is_some() {
+                        ext_len as u32
+                    } else if modern.binary.is_some() {
                         xml_len as u32
                     } else {
                         0
@@ -43,17 +56,7 @@ impl Bc {
         // Now have enough info to create the header
         let header = BcHeader::from_meta(&self.meta, body_buf.len() as u32, bin_offset);
 
-        let (mut buf, _n) = gen(tuple((bc_header(&header), slice(body_buf))), buf)?;
-
-        // Put the binary part of the body, TODO this is poorly written
-        if let BcBody::ModernMsg(ModernMsg {
-            binary: Some(ref binary),
-            ..
-        }) = self.body
-        {
-            let (buf2, _) = gen(slice(binary), buf)?;
-            buf = buf2
-        }
+        let (buf, _n) = gen(tuple((bc_header(&header), slice(body_buf))), buf)?;
 
         Ok(buf)
     }
@@ -65,6 +68,12 @@ fn bc_xml<W: Write>(enc_offset: u32, xml: &BcXml) -> impl SerializeFn<W> {
     slice(enc_bytes)
 }
 
+fn bc_ext<W: Write>(enc_offset: u32, ext: &Extension) -> impl SerializeFn<W> {
+    let ext_bytes = ext.serialize(vec![]).unwrap();
+    let enc_bytes = xml_crypto::crypt(enc_offset, &ext_bytes);
+    slice(enc_bytes)
+}
+
 fn bc_header<W: Write>(header: &BcHeader) -> impl SerializeFn<W> {
     // TODO this is actually a u16 "response code" in modern messages
     let (signaled_encryption, spare) = if header.class == 0x0000 {
@@ -76,7 +85,9 @@ fn bc_header<W: Write>(header: &BcHeader) -> impl SerializeFn<W> {
         le_u32(MAGIC_HEADER),
         le_u32(header.msg_id),
         le_u32(header.body_len),
-        le_u32(header.enc_offset),
+        le_u8(header.channel_id),
+        le_u8(header.stream_type),
+        le_u16(header.msg_num),
         le_u8(signaled_encryption),
         le_u8(spare), // skipped byte
         le_u16(header.class),
@@ -171,3 +182,98 @@ fn test_modern_login_roundtrip() {
     let msg2 = Bc::deserialize::<&[u8]>(&mut context, ser_buf.as_ref()).unwrap();
     assert_eq!(msg, msg2);
 }
+
+#[test]
+fn test_logout_ser() {
+    use super::xml::*;
+
+    // The logout from the protocol notes, which is laid out differently to what we write
+    let sample = include_bytes!("samples/modern_logout.bin");
+    let mut context = BcContext::new();
+
+    let msg = Bc::new_from_xml(
+        BcMeta {
+            msg_id: MSG_ID_LOGOUT,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        BcXml {
+            login_user: Some(LoginUser {
+                version: xml_ver(),
+                user_name: "PlainTextUsername".to_string(),
+                password: "PlainTextPASSWORD".to_string(),
+                user_ver: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    );
+
+    let ser_buf = msg.serialize(vec![]).unwrap();
+    let sent = Bc::deserialize::<&[u8]>(&mut context, ser_buf.as_ref()).unwrap();
+    let expected = Bc::deserialize::<&[u8]>(&mut context, &sample[..]).unwrap();
+    assert_eq!(sent, expected);
+}
+
+#[test]
+fn test_extension_roundtrip() {
+    use super::xml::*;
+
+    let mut context = BcContext::new();
+
+    let msg = Bc::new_from_ext(
+        BcMeta {
+            msg_id: MSG_ID_GET_PTZ_PRESET,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        Extension {
+            version: xml_ver(),
+            channel_id: Some(0),
+            ..Default::default()
+        },
+    );
+
+    let ser_buf = msg.serialize(vec![]).unwrap();
+    let msg2 = Bc::deserialize::<&[u8]>(&mut context, ser_buf.as_ref()).unwrap();
+    assert_eq!(msg, msg2);
+    // An Extension naming a channel must not switch the message ID into binary mode
+    assert!(!context.in_bin_mode.contains(&(MSG_ID_GET_PTZ_PRESET, 0)));
+}
+
+#[test]
+fn test_binary_roundtrip() {
+    use super::xml::*;
+
+    let mut context = BcContext::new();
+
+    let msg = Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_TALK,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        body: BcBody::ModernMsg(ModernMsg {
+            extension: Some(Extension {
+                version: xml_ver(),
+                binary_data: Some(1),
+                channel_id: Some(0),
+            }),
+            xml: None,
+            binary: Some(vec![0x30, 0x31, 0x77, 0x62, 0x00, 0x00, 0x00, 0x00].into()),
+        }),
+    };
+
+    let ser_buf = msg.serialize(vec![]).unwrap();
+    let msg2 = Bc::deserialize::<&[u8]>(&mut context, ser_buf.as_ref()).unwrap();
+    assert_eq!(msg, msg2);
+}
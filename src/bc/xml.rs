@@ -6,9 +6,16 @@ use std::io::{Read, Write};
 use yaserde::{ser::Config, YaDeserialize, YaSerialize};
 use yaserde_derive::{YaDeserialize, YaSerialize};
 
+mod unknown;
+
+use unknown::{keep_unknown_in, keep_unknown_in_each, serialize_in_place, KeepUnknown};
+pub use unknown::{UnknownElements, XmlNode};
+
 #[cfg(test)]
 use indoc::indoc;
 
+// Only lives long enough to be unpacked, so the size of the BcXml variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(PartialEq, Eq, Debug, YaDeserialize)]
 #[yaserde(flatten)]
 pub(super) enum AllTopXmls {
@@ -41,20 +48,44 @@ pub struct BcXml {
     pub system_general: Option<SystemGeneral>,
     #[yaserde(rename = "Norm")]
     pub norm: Option<Norm>,
+    #[yaserde(rename = "PtzControl")]
+    pub ptz_control: Option<PtzControl>,
+    #[yaserde(rename = "PtzPreset")]
+    pub ptz_preset: Option<PtzPreset>,
+    #[yaserde(rename = "AlarmEventList")]
+    pub alarm_event_list: Option<AlarmEventList>,
+    #[yaserde(rename = "TalkAbility")]
+    pub talk_ability: Option<TalkAbility>,
+    #[yaserde(rename = "TalkConfig")]
+    pub talk_config: Option<TalkConfig>,
+    #[yaserde(rename = "StreamInfoList")]
+    pub stream_info_list: Option<StreamInfoList>,
+    #[yaserde(rename = "VersionInfo")]
+    pub version_info: Option<VersionInfo>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 impl AllTopXmls {
     pub fn try_parse(s: impl Read) -> Result<Self, String> {
-        yaserde::de::from_reader(s)
+        let xml = read_xml(s)?;
+        let mut top: AllTopXmls = yaserde::de::from_reader(&xml[..])?;
+        if let AllTopXmls::BcXml(ref mut bc_xml) = top {
+            bc_xml.keep_unknown(&XmlNode::parse(&xml[..])?)?;
+        }
+        Ok(top)
     }
 }
 
 impl BcXml {
     pub fn try_parse(s: impl Read) -> Result<Self, String> {
-        yaserde::de::from_reader(s)
+        let xml = read_xml(s)?;
+        let mut bc_xml: BcXml = yaserde::de::from_reader(&xml[..])?;
+        bc_xml.keep_unknown(&XmlNode::parse(&xml[..])?)?;
+        Ok(bc_xml)
     }
     pub fn serialize<W: Write>(&self, w: W) -> Result<W, String> {
-        yaserde::ser::serialize_with_writer(self, w, &Config::default())
+        serialize_in_place(self, w)
     }
 }
 
@@ -65,6 +96,8 @@ pub struct Encryption {
     #[yaserde(rename = "type")]
     pub type_: String,
     pub nonce: String,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
@@ -76,6 +109,8 @@ pub struct LoginUser {
     pub password: String,
     #[yaserde(rename = "userVer")]
     pub user_ver: u32,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 #[derive(PartialEq, Eq, Debug, YaDeserialize, YaSerialize)]
@@ -86,6 +121,8 @@ pub struct LoginNet {
     pub type_: String,
     #[yaserde(rename = "udpPort")]
     pub udp_port: u16,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 impl Default for LoginNet {
@@ -94,13 +131,84 @@ impl Default for LoginNet {
             version: xml_ver(),
             type_: "LAN".to_string(),
             udp_port: 0,
+            unknown: Default::default(),
+        }
+    }
+}
+
+impl LoginNet {
+    /// How a client logging in over UDP from `udp_port` describes its connection
+    pub fn over_udp(udp_port: u16) -> Self {
+        LoginNet {
+            type_: "P2P".to_string(),
+            udp_port,
+            ..Default::default()
         }
     }
 }
 
+/// Sent by the camera when we log in.  Which of these it sends depends on the model and firmware.
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
 pub struct DeviceInfo {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "firmVersion")]
+    pub firm_version: Option<String>,
+    #[yaserde(rename = "IOInputPortNum")]
+    pub io_input_port_num: Option<u32>,
+    #[yaserde(rename = "IOOutputPortNum")]
+    pub io_output_port_num: Option<u32>,
+    #[yaserde(rename = "diskNum")]
+    pub disk_num: Option<u32>,
+    /// Such as "wifi_solo_ipc"
+    #[yaserde(rename = "type")]
+    pub type_: Option<String>,
+    #[yaserde(rename = "channelNum")]
+    pub channel_num: Option<u32>,
+    #[yaserde(rename = "audioNum")]
+    pub audio_num: Option<u32>,
+    #[yaserde(rename = "ipChannel")]
+    pub ip_channel: Option<u32>,
+    #[yaserde(rename = "analogChnNum")]
+    pub analog_chn_num: Option<u32>,
     pub resolution: Resolution,
+    pub language: Option<String>,
+    /// 1 if an SD card is inserted
+    #[yaserde(rename = "sdCard")]
+    pub sd_card: Option<u32>,
+    /// "none", or the axes the camera can move in, such as "pt"
+    #[yaserde(rename = "ptzMode")]
+    pub ptz_mode: Option<String>,
+    /// Such as "IPC" or "NVR"
+    #[yaserde(rename = "typeInfo")]
+    pub type_info: Option<String>,
+    #[yaserde(rename = "softVer")]
+    pub soft_ver: Option<u32>,
+    #[yaserde(rename = "hardVer")]
+    pub hard_ver: Option<u32>,
+    #[yaserde(rename = "panelVer")]
+    pub panel_ver: Option<u32>,
+    #[yaserde(rename = "hdChannel1")]
+    pub hd_channel1: Option<u32>,
+    #[yaserde(rename = "hdChannel2")]
+    pub hd_channel2: Option<u32>,
+    #[yaserde(rename = "hdChannel3")]
+    pub hd_channel3: Option<u32>,
+    #[yaserde(rename = "hdChannel4")]
+    pub hd_channel4: Option<u32>,
+    /// "NTSC" or "PAL"
+    pub norm: Option<String>,
+    #[yaserde(rename = "osdFormat")]
+    pub osd_format: Option<String>,
+    #[yaserde(rename = "B485")]
+    pub b485: Option<u32>,
+    #[yaserde(rename = "supportAutoUpdate")]
+    pub support_auto_update: Option<u32>,
+    #[yaserde(rename = "userVer")]
+    pub user_ver: Option<u32>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
@@ -109,6 +217,89 @@ pub struct Resolution {
     pub name: String,
     pub width: u32,
     pub height: u32,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+/// Sent by the camera alongside its DeviceInfo when we log in
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct StreamInfoList {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "StreamInfo")]
+    pub stream_infos: Vec<ChannelStreamInfo>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+/// A `<StreamInfo>`: the streams offered on some of the camera's channels
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "StreamInfo")]
+pub struct ChannelStreamInfo {
+    /// A bit for each channel these streams are on
+    #[yaserde(rename = "channelBits")]
+    pub channel_bits: u32,
+    #[yaserde(rename = "encodeTable")]
+    pub encode_tables: Vec<EncodeTable>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+/// What one stream offers
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "encodeTable")]
+pub struct EncodeTable {
+    /// The stream's name, such as "mainStream" or "subStream"
+    #[yaserde(rename = "type")]
+    pub type_: String,
+    pub resolution: StreamResolution,
+    #[yaserde(rename = "defaultFramerate")]
+    pub default_framerate: u32,
+    /// In kbps
+    #[yaserde(rename = "defaultBitrate")]
+    pub default_bitrate: u32,
+    /// The frame rates the stream can be set to, as a comma separated list
+    #[yaserde(rename = "framerateTable")]
+    pub framerate_table: String,
+    /// The bit rates the stream can be set to, in kbps, as a comma separated list
+    #[yaserde(rename = "bitrateTable")]
+    pub bitrate_table: String,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct StreamResolution {
+    pub width: u32,
+    pub height: u32,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+/// The camera's answer to message 80: its model and the versions of what it runs
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct VersionInfo {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    pub name: String,
+    /// The model, such as "E1"
+    #[yaserde(rename = "type")]
+    pub type_: String,
+    #[yaserde(rename = "serialNumber")]
+    pub serial_number: String,
+    /// Such as "build 19110800"
+    #[yaserde(rename = "buildDay")]
+    pub build_day: String,
+    #[yaserde(rename = "hardwareVersion")]
+    pub hardware_version: String,
+    #[yaserde(rename = "cfgVersion")]
+    pub cfg_version: String,
+    #[yaserde(rename = "firmwareVersion")]
+    pub firmware_version: String,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
@@ -119,14 +310,28 @@ pub struct Preview {
     #[yaserde(rename = "channelId")]
     pub channel_id: u32,
     pub handle: u32,
+    /// Only present when starting a stream; the stop message (ID 4) omits it
     #[yaserde(rename = "streamType")]
-    pub stream_type: String,
+    pub stream_type: Option<String>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
 pub struct Extension {
+    #[yaserde(attribute)]
+    pub version: String,
+    /// Set to 1 by the camera when the rest of this message ID is binary data
     #[yaserde(rename = "binaryData")]
-    pub binary_data: u32,
+    pub binary_data: Option<u32>,
+    #[yaserde(rename = "channelId")]
+    pub channel_id: Option<u32>,
+}
+
+impl Extension {
+    pub fn serialize<W: Write>(&self, w: W) -> Result<W, String> {
+        yaserde::ser::serialize_with_writer(self, w, &Config::default())
+    }
 }
 
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
@@ -151,6 +356,8 @@ pub struct SystemGeneral {
     pub language: Option<String>,
     #[yaserde(rename = "deviceName")]
     pub device_name: Option<String>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
 }
 
 #[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
@@ -158,6 +365,321 @@ pub struct Norm {
     #[yaserde(attribute)]
     pub version: String,
     norm: String,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct PtzControl {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "channelId")]
+    pub channel_id: u32,
+    pub speed: u32,
+    pub command: String,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct PtzPreset {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "channelId")]
+    pub channel_id: u32,
+    #[yaserde(rename = "presetList")]
+    pub preset_list: PresetList,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct PresetList {
+    pub preset: Vec<Preset>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "preset")]
+pub struct Preset {
+    pub id: u32,
+    pub name: Option<String>,
+    /// Only sent by the client: "setPos" to save the current position, "toPos" to recall it
+    pub command: Option<String>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct AlarmEventList {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "AlarmEvent")]
+    pub alarm_events: Vec<AlarmEvent>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct AlarmEvent {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "channelId")]
+    pub channel_id: u32,
+    /// "MD" while motion is detected, "none" once it stops
+    pub status: String,
+    pub recording: i32,
+    #[yaserde(rename = "timeStamp")]
+    pub timestamp: i32,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct TalkAbility {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "duplexList")]
+    pub duplex_list: DuplexList,
+    #[yaserde(rename = "audioStreamModeList")]
+    pub audio_stream_mode_list: AudioStreamModeList,
+    #[yaserde(rename = "audioConfigList")]
+    pub audio_config_list: AudioConfigList,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct DuplexList {
+    /// "FDX" (full duplex) or "HDX" (half duplex)
+    pub duplex: Vec<String>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct AudioStreamModeList {
+    #[yaserde(rename = "audioStreamMode")]
+    pub audio_stream_mode: Vec<String>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct AudioConfigList {
+    #[yaserde(rename = "audioConfig")]
+    pub audio_config: Vec<AudioConfig>,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, Clone, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "audioConfig")]
+pub struct AudioConfig {
+    /// Only sent by the camera, to rank the configs it offers
+    pub priority: Option<u32>,
+    #[yaserde(rename = "audioType")]
+    pub audio_type: String,
+    #[yaserde(rename = "sampleRate")]
+    pub sample_rate: u32,
+    #[yaserde(rename = "samplePrecision")]
+    pub sample_precision: u32,
+    /// The number of samples in each ADPCM block
+    #[yaserde(rename = "lengthPerEncoder")]
+    pub length_per_encoder: u32,
+    #[yaserde(rename = "soundTrack")]
+    pub sound_track: String,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+/// Sent by the client to open the camera's speaker, choosing from what TalkAbility offered
+#[derive(PartialEq, Eq, Default, Debug, Clone, YaDeserialize, YaSerialize)]
+pub struct TalkConfig {
+    #[yaserde(attribute)]
+    pub version: String,
+
+    #[yaserde(rename = "channelId")]
+    pub channel_id: u32,
+    pub duplex: String,
+    #[yaserde(rename = "audioStreamMode")]
+    pub audio_stream_mode: String,
+    #[yaserde(rename = "audioConfig")]
+    pub audio_config: AudioConfig,
+    #[yaserde(flatten)]
+    pub unknown: UnknownElements,
+}
+
+impl KeepUnknown for BcXml {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in(self.encryption.as_mut(), children, "Encryption")?;
+        keep_unknown_in(self.login_user.as_mut(), children, "LoginUser")?;
+        keep_unknown_in(self.login_net.as_mut(), children, "LoginNet")?;
+        keep_unknown_in(self.device_info.as_mut(), children, "DeviceInfo")?;
+        keep_unknown_in(self.preview.as_mut(), children, "Preview")?;
+        keep_unknown_in(self.system_general.as_mut(), children, "SystemGeneral")?;
+        keep_unknown_in(self.norm.as_mut(), children, "Norm")?;
+        keep_unknown_in(self.ptz_control.as_mut(), children, "PtzControl")?;
+        keep_unknown_in(self.ptz_preset.as_mut(), children, "PtzPreset")?;
+        keep_unknown_in(self.alarm_event_list.as_mut(), children, "AlarmEventList")?;
+        keep_unknown_in(self.talk_ability.as_mut(), children, "TalkAbility")?;
+        keep_unknown_in(self.talk_config.as_mut(), children, "TalkConfig")?;
+        keep_unknown_in(self.stream_info_list.as_mut(), children, "StreamInfoList")?;
+        keep_unknown_in(self.version_info.as_mut(), children, "VersionInfo")
+    }
+}
+
+impl KeepUnknown for DeviceInfo {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in(Some(&mut self.resolution), children, "resolution")
+    }
+}
+
+impl KeepUnknown for StreamInfoList {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in_each(&mut self.stream_infos, children, "StreamInfo")
+    }
+}
+
+impl KeepUnknown for ChannelStreamInfo {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in_each(&mut self.encode_tables, children, "encodeTable")
+    }
+}
+
+impl KeepUnknown for EncodeTable {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in(Some(&mut self.resolution), children, "resolution")
+    }
+}
+
+impl KeepUnknown for PtzPreset {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in(Some(&mut self.preset_list), children, "presetList")
+    }
+}
+
+impl KeepUnknown for PresetList {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in_each(&mut self.preset, children, "preset")
+    }
+}
+
+impl KeepUnknown for AlarmEventList {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in_each(&mut self.alarm_events, children, "AlarmEvent")
+    }
+}
+
+impl KeepUnknown for TalkAbility {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in(Some(&mut self.duplex_list), children, "duplexList")?;
+        let modes = Some(&mut self.audio_stream_mode_list);
+        keep_unknown_in(modes, children, "audioStreamModeList")?;
+        keep_unknown_in(
+            Some(&mut self.audio_config_list),
+            children,
+            "audioConfigList",
+        )
+    }
+}
+
+impl KeepUnknown for AudioConfigList {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in_each(&mut self.audio_config, children, "audioConfig")
+    }
+}
+
+impl KeepUnknown for TalkConfig {
+    fn unknown_mut(&mut self) -> &mut UnknownElements {
+        &mut self.unknown
+    }
+
+    fn keep_unknown_in_fields(&mut self, children: &[XmlNode]) -> Result<(), String> {
+        keep_unknown_in(Some(&mut self.audio_config), children, "audioConfig")
+    }
+}
+
+macro_rules! keep_unknown {
+    ($($name:ident),*) => {
+        $(
+            impl KeepUnknown for $name {
+                fn unknown_mut(&mut self) -> &mut UnknownElements {
+                    &mut self.unknown
+                }
+            }
+        )*
+    };
+}
+
+keep_unknown!(
+    Encryption,
+    LoginUser,
+    LoginNet,
+    Resolution,
+    Preview,
+    SystemGeneral,
+    Norm,
+    PtzControl,
+    Preset,
+    AlarmEvent,
+    DuplexList,
+    AudioStreamModeList,
+    AudioConfig,
+    StreamResolution,
+    VersionInfo
+);
+
+fn read_xml(mut s: impl Read) -> Result<Vec<u8>, String> {
+    let mut xml = vec![];
+    s.read_to_end(&mut xml).map_err(|e| e.to_string())?;
+    Ok(xml)
 }
 
 pub fn xml_ver() -> String {
@@ -245,11 +767,13 @@ fn test_login_ser() {
             user_name: "9F07915E819A076E2E14169830769D6".to_string(),
             password: "8EFECD610524A98390F118D2789BE3B".to_string(),
             user_ver: 1,
+            ..Default::default()
         }),
         login_net: Some(LoginNet {
             version: "1.1".to_string(),
             type_: "LAN".to_string(),
             udp_port: 0,
+            ..Default::default()
         }),
         ..BcXml::default()
     };
@@ -322,7 +846,487 @@ fn test_binary_deser() {
     );
     let b = AllTopXmls::try_parse(sample.as_bytes()).unwrap();
     match b {
-        AllTopXmls::Extension(Extension { binary_data: 1 }) => assert!(true),
+        AllTopXmls::Extension(Extension {
+            binary_data: Some(1),
+            ..
+        }) => assert!(true),
         _ => assert!(false),
     }
 }
+
+#[test]
+fn test_preview_stop_ser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <Preview version="1.1">
+        <channelId>0</channelId>
+        <handle>0</handle>
+        </Preview>
+        </body>"#
+    );
+
+    let b = BcXml {
+        preview: Some(Preview {
+            version: "1.1".to_string(),
+            channel_id: 0,
+            handle: 0,
+            stream_type: None,
+            ..Default::default()
+        }),
+        ..BcXml::default()
+    };
+
+    let b2 = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let b3 = BcXml::try_parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+
+    assert_eq!(b, b2);
+    assert_eq!(b, b3);
+}
+
+#[test]
+fn test_ptz_control_ser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <PtzControl version="1.1">
+        <channelId>0</channelId>
+        <speed>32</speed>
+        <command>right</command>
+        </PtzControl>
+        </body>"#
+    );
+
+    let b = BcXml {
+        ptz_control: Some(PtzControl {
+            version: "1.1".to_string(),
+            channel_id: 0,
+            speed: 32,
+            command: "right".to_string(),
+            ..Default::default()
+        }),
+        ..BcXml::default()
+    };
+
+    let b2 = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let b3 = BcXml::try_parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+
+    assert_eq!(b, b2);
+    assert_eq!(b, b3);
+}
+
+#[test]
+fn test_ptz_preset_deser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <PtzPreset version="1.1">
+        <channelId>0</channelId>
+        <presetList>
+        <preset>
+        <id>1</id>
+        <name>gate</name>
+        </preset>
+        <preset>
+        <id>2</id>
+        <name>porch</name>
+        </preset>
+        </presetList>
+        </PtzPreset>
+        </body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let presets = b.ptz_preset.unwrap().preset_list.preset;
+    assert_eq!(presets.len(), 2);
+    assert_eq!(presets[0].id, 1);
+    assert_eq!(presets[0].name.as_deref(), Some("gate"));
+    assert_eq!(presets[1].id, 2);
+    assert_eq!(presets[1].command, None);
+
+    // A camera with no presets sends an empty list
+    let empty = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <PtzPreset version="1.1">
+        <channelId>0</channelId>
+        <presetList />
+        </PtzPreset>
+        </body>"#
+    );
+    let b = BcXml::try_parse(empty.as_bytes()).unwrap();
+    assert!(b.ptz_preset.unwrap().preset_list.preset.is_empty());
+}
+
+#[test]
+fn test_alarm_event_list_deser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <AlarmEventList version="1.1">
+        <AlarmEvent version="1.1">
+        <channelId>0</channelId>
+        <status>MD</status>
+        <recording>0</recording>
+        <timeStamp>0</timeStamp>
+        </AlarmEvent>
+        </AlarmEventList>
+        </body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let events = b.alarm_event_list.unwrap().alarm_events;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].channel_id, 0);
+    assert_eq!(events[0].status, "MD");
+    assert_eq!(events[0].recording, 0);
+}
+
+#[test]
+fn test_talk_ability_deser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <TalkAbility version="1.1">
+        <duplexList>
+        <duplex>FDX</duplex>
+        </duplexList>
+        <audioStreamModeList>
+        <audioStreamMode>followVideoStream</audioStreamMode>
+        </audioStreamModeList>
+        <audioConfigList>
+        <audioConfig>
+        <priority>0</priority>
+        <audioType>adpcm</audioType>
+        <sampleRate>16000</sampleRate>
+        <samplePrecision>16</samplePrecision>
+        <lengthPerEncoder>1024</lengthPerEncoder>
+        <soundTrack>mono</soundTrack>
+        </audioConfig>
+        </audioConfigList>
+        </TalkAbility>
+        </body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let talk_ability = b.talk_ability.unwrap();
+    assert_eq!(talk_ability.duplex_list.duplex, vec!["FDX".to_string()]);
+    assert_eq!(
+        talk_ability.audio_stream_mode_list.audio_stream_mode,
+        vec!["followVideoStream".to_string()]
+    );
+    assert_eq!(
+        talk_ability.audio_config_list.audio_config,
+        vec![AudioConfig {
+            priority: Some(0),
+            audio_type: "adpcm".to_string(),
+            sample_rate: 16000,
+            sample_precision: 16,
+            length_per_encoder: 1024,
+            sound_track: "mono".to_string(),
+            ..Default::default()
+        }]
+    );
+}
+
+#[test]
+fn test_talk_config_ser() {
+    let b = BcXml {
+        talk_config: Some(TalkConfig {
+            version: xml_ver(),
+            channel_id: 0,
+            duplex: "FDX".to_string(),
+            audio_stream_mode: "followVideoStream".to_string(),
+            audio_config: AudioConfig {
+                priority: None,
+                audio_type: "adpcm".to_string(),
+                sample_rate: 16000,
+                sample_precision: 16,
+                length_per_encoder: 1024,
+                sound_track: "mono".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        ..BcXml::default()
+    };
+
+    let b2 = BcXml::try_parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+    assert_eq!(b, b2);
+}
+
+#[test]
+fn test_login_reply_round_trip() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <DeviceInfo version="1.1">
+        <firmVersion>00000000000000</firmVersion>
+        <IOInputPortNum>0</IOInputPortNum>
+        <IOOutputPortNum>0</IOOutputPortNum>
+        <diskNum>0</diskNum>
+        <type>wifi_solo_ipc</type>
+        <channelNum>1</channelNum>
+        <audioNum>1</audioNum>
+        <ipChannel>0</ipChannel>
+        <analogChnNum>1</analogChnNum>
+        <resolution>
+        <resolutionName>2304*1296</resolutionName>
+        <width>2304</width>
+        <height>1296</height>
+        </resolution>
+        <language>English</language>
+        <sdCard>1</sdCard>
+        <ptzMode>pt</ptzMode>
+        <typeInfo>IPC</typeInfo>
+        <softVer>33555019</softVer>
+        <hardVer>0</hardVer>
+        <panelVer>0</panelVer>
+        <hdChannel1>0</hdChannel1>
+        <hdChannel2>0</hdChannel2>
+        <hdChannel3>0</hdChannel3>
+        <hdChannel4>0</hdChannel4>
+        <norm>NTSC</norm>
+        <osdFormat>DMY</osdFormat>
+        <B485>0</B485>
+        <supportAutoUpdate>0</supportAutoUpdate>
+        <userVer>1</userVer>
+        </DeviceInfo>
+        <StreamInfoList version="1.1">
+        <StreamInfo>
+        <channelBits>1</channelBits>
+        <encodeTable>
+        <type>mainStream</type>
+        <resolution>
+        <width>2304</width>
+        <height>1296</height>
+        </resolution>
+        <defaultFramerate>15</defaultFramerate>
+        <defaultBitrate>2560</defaultBitrate>
+        <framerateTable>15,12,10,8,6,4,2</framerateTable>
+        <bitrateTable>1024,1536,2048,2560,3072</bitrateTable>
+        </encodeTable>
+        <encodeTable>
+        <type>subStream</type>
+        <resolution>
+        <width>896</width>
+        <height>512</height>
+        </resolution>
+        <defaultFramerate>15</defaultFramerate>
+        <defaultBitrate>512</defaultBitrate>
+        <framerateTable>15,12,10,8,6,4,2</framerateTable>
+        <bitrateTable>128,256,384,512,768,1024</bitrateTable>
+        </encodeTable>
+        </StreamInfo>
+        </StreamInfoList>
+        </body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let device_info = b.device_info.as_ref().unwrap();
+    assert_eq!(device_info.firm_version.as_deref(), Some("00000000000000"));
+    assert_eq!(device_info.type_.as_deref(), Some("wifi_solo_ipc"));
+    assert_eq!(device_info.channel_num, Some(1));
+    assert_eq!(device_info.audio_num, Some(1));
+    assert_eq!(device_info.resolution.width, 2304);
+    assert_eq!(device_info.sd_card, Some(1));
+    assert_eq!(device_info.ptz_mode.as_deref(), Some("pt"));
+    assert_eq!(device_info.type_info.as_deref(), Some("IPC"));
+    assert_eq!(device_info.norm.as_deref(), Some("NTSC"));
+    assert_eq!(device_info.unknown, UnknownElements::default());
+
+    let stream_infos = &b.stream_info_list.as_ref().unwrap().stream_infos;
+    assert_eq!(stream_infos.len(), 1);
+    assert_eq!(stream_infos[0].channel_bits, 1);
+    let sub_stream = &stream_infos[0].encode_tables[1];
+    assert_eq!(sub_stream.type_, "subStream");
+    assert_eq!(sub_stream.resolution.width, 896);
+    assert_eq!(sub_stream.resolution.height, 512);
+    assert_eq!(sub_stream.default_framerate, 15);
+    assert_eq!(sub_stream.default_bitrate, 512);
+    assert_eq!(sub_stream.bitrate_table, "128,256,384,512,768,1024");
+
+    let original = XmlNode::parse(sample.as_bytes()).unwrap();
+    let reserialized = XmlNode::parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+    assert_eq!(reserialized, original);
+}
+
+#[test]
+fn test_deviceinfo_round_trip() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <DeviceInfo version="1.1">
+        <type>wifi_solo_ipc</type>
+        <wifiMode>sta</wifiMode>
+        <channelNum>1</channelNum>
+        <resolution>
+        <resolutionName>3840*2160</resolutionName>
+        <width>3840</width>
+        <height>2160</height>
+        </resolution>
+        <sdCard>0</sdCard>
+        <batteryNum>1</batteryNum>
+        <userVer>1</userVer>
+        </DeviceInfo>
+        <StreamInfoList version="1.1">
+        <StreamInfo>
+        <channelBits>1</channelBits>
+        <encodeTable>
+        <type>mainStream</type>
+        <resolution>
+        <width>3840</width>
+        <aspect>16:9</aspect>
+        <height>2160</height>
+        </resolution>
+        <codec>h265</codec>
+        <defaultFramerate>15</defaultFramerate>
+        <defaultBitrate>6144</defaultBitrate>
+        <framerateTable>15,12,10</framerateTable>
+        <bitrateTable>4096,6144,8192</bitrateTable>
+        </encodeTable>
+        <encodeTable>
+        <type>subStream</type>
+        <resolution>
+        <width>640</width>
+        <height>360</height>
+        </resolution>
+        <defaultFramerate>15</defaultFramerate>
+        <defaultBitrate>256</defaultBitrate>
+        <framerateTable>15,10</framerateTable>
+        <bitrateTable>128,256</bitrateTable>
+        <gop>30</gop>
+        </encodeTable>
+        <wideAngle>0</wideAngle>
+        </StreamInfo>
+        </StreamInfoList>
+        </body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let device_info = b.device_info.as_ref().unwrap();
+    assert_eq!(device_info.resolution.width, 3840);
+    let unknown_names: Vec<_> = device_info
+        .unknown
+        .0
+        .iter()
+        .map(|(_, u)| u.name())
+        .collect();
+    assert_eq!(unknown_names, [Some("wifiMode"), Some("batteryNum")]);
+
+    // Each stream keeps its own unknown elements, down to its resolution
+    let stream_info = &b.stream_info_list.as_ref().unwrap().stream_infos[0];
+    assert_eq!(stream_info.unknown.0.len(), 1);
+    let (main_stream, sub_stream) = (&stream_info.encode_tables[0], &stream_info.encode_tables[1]);
+    assert_eq!(main_stream.unknown.0[0].1.name(), Some("codec"));
+    assert_eq!(main_stream.resolution.unknown.0[0].1.name(), Some("aspect"));
+    assert_eq!(sub_stream.unknown.0[0].1.name(), Some("gop"));
+    assert_eq!(sub_stream.resolution.unknown, UnknownElements::default());
+
+    let original = XmlNode::parse(sample.as_bytes()).unwrap();
+    let reserialized = XmlNode::parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+    assert_eq!(reserialized, original);
+}
+
+#[test]
+fn test_version_info_deser() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <VersionInfo version="1.1">
+        <name>Cammy02</name>
+        <type>E1</type>
+        <serialNumber>00000000000000</serialNumber>
+        <buildDay>build 19110800</buildDay>
+        <hardwareVersion>IPC_517SD5</hardwareVersion>
+        <cfgVersion>v2.0.0.0</cfgVersion>
+        <firmwareVersion>v2.0.0.587_19110800</firmwareVersion>
+        <detail>IPC_51716M110000000100000</detail>
+        <IEClient>IEClient</IEClient>
+        <pakSuffix>pak</pakSuffix>
+        <helpVersion>blackPointsLevel=0</helpVersion>
+        </VersionInfo>
+        </body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let version_info = b.version_info.unwrap();
+    assert_eq!(version_info.name, "Cammy02");
+    assert_eq!(version_info.type_, "E1");
+    assert_eq!(version_info.serial_number, "00000000000000");
+    assert_eq!(version_info.build_day, "build 19110800");
+    assert_eq!(version_info.hardware_version, "IPC_517SD5");
+    assert_eq!(version_info.cfg_version, "v2.0.0.0");
+    assert_eq!(version_info.firmware_version, "v2.0.0.587_19110800");
+    assert_eq!(version_info.unknown.0.len(), 4);
+}
+
+#[test]
+fn test_system_general_round_trip() {
+    let sample = indoc!(
+        r#"
+        <?xml version="1.0" encoding="UTF-8" ?>
+        <body>
+        <SystemGeneral version="1.1">
+        <timeZone>-3600</timeZone>
+        <year>2020</year>
+        <month>10</month>
+        <day>17</day>
+        <hour>12</hour>
+        <minute>30</minute>
+        <second>5</second>
+        <osdFormat>DMY</osdFormat>
+        <timeFormat>0</timeFormat>
+        <language>English</language>
+        <deviceName>Porch</deviceName>
+        <dstInfo enable="1">
+        <startMonth>3</startMonth>
+        <endMonth>10</endMonth>
+        </dstInfo>
+        <autoUpdate>0</autoUpdate>
+        </SystemGeneral>
+        </body>"#
+    );
+
+    let mut b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let original = XmlNode::parse(sample.as_bytes()).unwrap();
+    let reserialized = XmlNode::parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+    assert_eq!(reserialized, original);
+
+    // Setting the time leaves the rest of the settings alone
+    b.system_general.as_mut().unwrap().hour = Some(13);
+    let changed = BcXml::try_parse(b.serialize(vec![]).unwrap().as_slice()).unwrap();
+    let system_general = changed.system_general.unwrap();
+    assert_eq!(system_general.hour, Some(13));
+    assert_eq!(system_general.device_name.as_deref(), Some("Porch"));
+    assert_eq!(system_general.unknown, b.system_general.unwrap().unknown);
+}
+
+#[test]
+fn test_unknown_in_place_round_trip() {
+    // Written the way we write XML, so that it must come back byte for byte
+    let sample = concat!(
+        r#"<?xml version="1.0" encoding="utf-8"?>"#,
+        r#"<body><SystemGeneral version="1.1"><timeZone>0</timeZone><year>2020</year>"#,
+        r#"<dstInfo enable="1"><startMonth>3</startMonth></dstInfo><month>10</month>"#,
+        r#"</SystemGeneral><mystery>1</mystery><PtzPreset version="1.1"><channelId>0</channelId>"#,
+        r#"<presetList><preset><id>1</id><icon>star</icon><name>Gate</name></preset>"#,
+        r#"<preset><id>2</id><name>Porch</name></preset></presetList></PtzPreset></body>"#
+    );
+
+    let b = BcXml::try_parse(sample.as_bytes()).unwrap();
+    let presets = &b.ptz_preset.as_ref().unwrap().preset_list.preset;
+    assert_eq!(presets[0].unknown.0.len(), 1);
+    assert_eq!(presets[1].unknown, UnknownElements::default());
+    let reserialized = String::from_utf8(b.serialize(vec![]).unwrap()).unwrap();
+    assert_eq!(reserialized, sample);
+}
@@ -0,0 +1,254 @@
+//! Keeps the XML we don't model, so that a message we receive, change and send back to the camera
+//! doesn't lose the settings we never parsed.
+//!
+//! Each struct in `BcXml` has an `UnknownElements` field, flattened so that yaserde writes its
+//! elements after the struct's own fields.  yaserde can hand a flattened field the elements it
+//! skipped, but loses track of them as soon as the struct has a struct-typed field, so instead
+//! `BcXml::try_parse` fills them in afterwards from the document itself, noting where among its
+//! siblings each one was.  `serialize_in_place` then moves them back there.
+use std::io::{Read, Write};
+use xml::attribute::OwnedAttribute;
+use xml::name::OwnedName;
+use xml::reader::{EventReader, XmlEvent};
+use xml::writer::XmlEvent as WriterEvent;
+use yaserde::de::Deserializer;
+use yaserde::ser::Serializer;
+use yaserde::{YaDeserialize, YaSerialize};
+
+/// The elements of a struct that it has no field for, in the order they arrived, each with its
+/// position among the children of the struct's element
+#[derive(PartialEq, Eq, Default, Debug, Clone)]
+pub struct UnknownElements(pub Vec<(usize, XmlNode)>);
+
+/// Written just before each unknown element, with its position, for `serialize_in_place` to find
+const POSITION_MARK: &str = "neolink-position";
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum XmlNode {
+    Element {
+        name: OwnedName,
+        attributes: Vec<OwnedAttribute>,
+        children: Vec<XmlNode>,
+    },
+    Text(String),
+}
+
+/// A struct that keeps the elements it has no field for
+pub(super) trait KeepUnknown: YaSerialize {
+    fn unknown_mut(&mut self) -> &mut UnknownElements;
+
+    /// Passes the children of this struct's element on to its fields that keep their own
+    fn keep_unknown_in_fields(&mut self, _children: &[XmlNode]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Keeps the children of `element`, the element this struct was parsed from, that are left
+    /// out when the struct is serialized
+    fn keep_unknown(&mut self, element: &XmlNode) -> Result<(), String> {
+        self.unknown_mut().0.clear();
+        let known = XmlNode::parse(yaserde::ser::to_string(self)?.as_bytes())?;
+        let mut known_names: Vec<&str> =
+            known.children().iter().filter_map(XmlNode::name).collect();
+
+        let children = element.children();
+        let unknown = children
+            .iter()
+            .enumerate()
+            .filter(|(_, child)| {
+                // Each known element accounts for one child of the same name
+                let known_at = child
+                    .name()
+                    .and_then(|name| known_names.iter().position(|&known| known == name));
+                match known_at {
+                    Some(i) => {
+                        known_names.remove(i);
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .map(|(position, child)| (position, child.clone()))
+            .collect();
+        self.unknown_mut().0 = unknown;
+
+        self.keep_unknown_in_fields(children)
+    }
+}
+
+/// Keeps the unknown elements of `field`, if the struct has it, from the first of `children`
+/// named `name`
+pub(super) fn keep_unknown_in<T: KeepUnknown>(
+    field: Option<&mut T>,
+    children: &[XmlNode],
+    name: &str,
+) -> Result<(), String> {
+    match (field, children.iter().find(|c| c.name() == Some(name))) {
+        (Some(field), Some(element)) => field.keep_unknown(element),
+        _ => Ok(()),
+    }
+}
+
+/// Keeps the unknown elements of each of `fields` from the children named `name`, in order
+pub(super) fn keep_unknown_in_each<T: KeepUnknown>(
+    fields: &mut [T],
+    children: &[XmlNode],
+    name: &str,
+) -> Result<(), String> {
+    let elements = children.iter().filter(|c| c.name() == Some(name));
+    for (field, element) in fields.iter_mut().zip(elements) {
+        field.keep_unknown(element)?;
+    }
+    Ok(())
+}
+
+/// Serializes `value` as a document, with each unknown element back in its place among the known
+/// ones rather than after them
+pub(super) fn serialize_in_place<T: YaSerialize, W: Write>(value: &T, w: W) -> Result<W, String> {
+    let marked = yaserde::ser::to_string(value)?;
+    let mut reader = EventReader::new(marked.as_bytes());
+    let mut writer = Serializer::new_from_writer(w);
+    for node in XmlNode::read_placed(&mut reader)? {
+        node.write(&mut writer)?;
+    }
+    Ok(writer.into_inner())
+}
+
+impl XmlNode {
+    /// Parses a whole document, trimmed of whitespace as yaserde does, into its root element
+    pub fn parse(r: impl Read) -> Result<XmlNode, String> {
+        let mut reader = Deserializer::new_from_reader(r);
+        match reader.next_event()? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => Ok(XmlNode::Element {
+                name,
+                attributes,
+                children: XmlNode::read_children(&mut reader)?,
+            }),
+            event => Err(format!("Expected an element, got {:?}", event)),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            XmlNode::Element { name, .. } => Some(&name.local_name),
+            XmlNode::Text(_) => None,
+        }
+    }
+
+    pub fn children(&self) -> &[XmlNode] {
+        match self {
+            XmlNode::Element { children, .. } => children,
+            XmlNode::Text(_) => &[],
+        }
+    }
+
+    /// Reads nodes up to and including the end of the element they are in
+    fn read_children<R: Read>(reader: &mut Deserializer<R>) -> Result<Vec<XmlNode>, String> {
+        let mut children = vec![];
+        loop {
+            match reader.next_event()? {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => children.push(XmlNode::Element {
+                    name,
+                    attributes,
+                    children: XmlNode::read_children(reader)?,
+                }),
+                XmlEvent::Characters(text) => children.push(XmlNode::Text(text)),
+                XmlEvent::EndElement { .. } => return Ok(children),
+                event => return Err(format!("Unexpected {:?} in an element", event)),
+            }
+        }
+    }
+
+    /// Reads nodes up to and including the end of the element or document they are in, moving
+    /// those marked as unknown to where they were found
+    fn read_placed<R: Read>(reader: &mut EventReader<R>) -> Result<Vec<XmlNode>, String> {
+        let mut known = vec![];
+        let mut unknown = vec![];
+        let mut position: Option<usize> = None;
+        loop {
+            let node = match reader.next().map_err(|e| e.to_string())? {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => XmlNode::Element {
+                    name,
+                    attributes,
+                    children: XmlNode::read_placed(reader)?,
+                },
+                XmlEvent::Characters(text) => XmlNode::Text(text),
+                XmlEvent::ProcessingInstruction { name, data } if name == POSITION_MARK => {
+                    position = data.and_then(|data| data.parse().ok());
+                    continue;
+                }
+                XmlEvent::EndElement { .. } | XmlEvent::EndDocument => break,
+                _ => continue,
+            };
+            match position.take() {
+                Some(position) => unknown.push((position, node)),
+                None => known.push(node),
+            }
+        }
+        // They are in the order they were found, so each one's place is already filled up to it
+        for (position, node) in unknown {
+            known.insert(position.min(known.len()), node);
+        }
+        Ok(known)
+    }
+
+    fn write<W: Write>(&self, writer: &mut Serializer<W>) -> Result<(), String> {
+        match self {
+            XmlNode::Element {
+                name,
+                attributes,
+                children,
+            } => {
+                let mut start = WriterEvent::start_element(name.borrow());
+                for attr in attributes {
+                    start = start.attr(attr.name.borrow(), &attr.value);
+                }
+                writer.write(start).map_err(|e| e.to_string())?;
+                for child in children {
+                    child.write(writer)?;
+                }
+                writer
+                    .write(WriterEvent::end_element())
+                    .map_err(|e| e.to_string())
+            }
+            XmlNode::Text(text) => writer
+                .write(WriterEvent::characters(text))
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl YaDeserialize for UnknownElements {
+    fn deserialize<R: Read>(reader: &mut Deserializer<R>) -> Result<Self, String> {
+        // Handed the struct's element, with whatever yaserde managed to keep of what it skipped
+        match reader.next_event()? {
+            XmlEvent::StartElement { .. } => {
+                let children = XmlNode::read_children(reader)?;
+                Ok(UnknownElements(children.into_iter().enumerate().collect()))
+            }
+            event => Err(format!("Expected an element, got {:?}", event)),
+        }
+    }
+}
+
+impl YaSerialize for UnknownElements {
+    fn serialize<W: Write>(&self, writer: &mut Serializer<W>) -> Result<(), String> {
+        // Flattening leaves the struct's own tags to the struct
+        for (position, node) in &self.0 {
+            let position = position.to_string();
+            writer
+                .write(WriterEvent::processing_instruction(
+                    POSITION_MARK,
+                    Some(&position),
+                ))
+                .map_err(|e| e.to_string())?;
+            node.write(writer)?;
+        }
+        Ok(())
+    }
+}
@@ -1,33 +1,116 @@
-use self::connection::BcConnection;
-use self::media_packet::{MediaDataKind, MediaDataSubscriber};
+use self::connection::{BcConnection, BcSubscription};
+use self::media_packet::{
+    aac_duration, MediaData, MediaDataKind, MediaDataSubscriber, StreamClock,
+};
 use crate::bc;
 use crate::bc::{model::*, xml::*};
 use crate::gst::GstOutputs;
 use adpcm::adpcm_to_pcm;
 use err_derive::Error;
 use log::*;
-use std::io::Write;
+use std::collections::HashMap;
+use std::fmt;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
 use std::time::Duration;
 
 use Md5Trunc::*;
 
 mod adpcm;
+mod async_camera;
 mod connection;
+mod discovery;
 mod media_packet;
+mod motion;
+mod ptz;
+mod talk;
 mod time;
+mod version;
 
+pub use async_camera::AsyncBcCamera;
+pub use connection::BcCodec;
+pub use discovery::{discover, find_camera_by_uid, DiscoveredCamera};
+pub use media_packet::StreamInfo;
+pub use motion::{MotionDataSubscriber, MotionEvent, MotionKind};
+pub use ptz::PtzCommand;
+
+/// A camera, whose methods block until it answers.  Each connection has a thread to receive on;
+/// `AsyncBcCamera` does without one on a tokio runtime.
 pub struct BcCamera {
     address: SocketAddr,
     connection: Option<BcConnection>,
     logged_in: bool,
+    credentials: Option<Credentials>,
+    /// The video streams running over the connection, by channel and handle
+    video_streams: Mutex<HashMap<(u32, u32), VideoStream>>,
+}
+
+struct VideoStream {
+    /// The message number the camera is sending the stream with
+    msg_num: u16,
+    /// Set once `stop_video` has been asked to stop it
+    stopped: bool,
+}
+
+/// What the camera tells us about itself when we log in
+#[derive(Debug)]
+pub struct LoginInfo {
+    pub device_info: DeviceInfo,
+    /// Not sent by every camera
+    pub stream_info_list: Option<StreamInfoList>,
 }
 
-use crate::Never;
+/// The plaintext credentials used to log in; the camera expects them again when logging out.
+struct Credentials {
+    username: String,
+    password: Option<String>,
+}
 
 type Result<T> = std::result::Result<T, Error>;
 
 const RX_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to search the LAN for a camera given by UID
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The camera's ADPCM audio is mono at 8 kHz
+const ADPCM_SAMPLE_RATE: u64 = 8000;
+
+/// Where to find a camera: a host and port, or the UID printed on it, which is looked up on the
+/// LAN each time the camera is connected to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CameraAddress {
+    Host(String),
+    Uid(String),
+}
+
+/// How Baichuan messages travel to the camera.  Most cameras take TCP, but battery and some Wi-Fi
+/// cameras only speak UDP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl From<&str> for CameraAddress {
+    fn from(host: &str) -> CameraAddress {
+        CameraAddress::Host(host.to_string())
+    }
+}
+
+impl From<&String> for CameraAddress {
+    fn from(host: &String) -> CameraAddress {
+        CameraAddress::Host(host.clone())
+    }
+}
+
+impl fmt::Display for CameraAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CameraAddress::Host(host) => f.write_str(host),
+            CameraAddress::Uid(uid) => write!(f, "UID {}", uid),
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -44,7 +127,7 @@ pub enum Error {
     ConnectionError(#[error(source)] self::connection::Error),
 
     #[error(display = "Communication error")]
-    UnintelligibleReply { reply: Bc, why: &'static str },
+    UnintelligibleReply { reply: Box<Bc>, why: &'static str },
 
     #[error(display = "Dropped connection")]
     DroppedConnection(#[error(source)] std::sync::mpsc::RecvError),
@@ -84,15 +167,32 @@ impl Drop for BcCamera {
 }
 
 impl BcCamera {
-    pub fn connect<T: ToSocketAddrs>(host: T) -> Result<BcCamera> {
-        let addr_iter = match host.to_socket_addrs() {
-            Ok(iter) => iter,
-            Err(_) => return Err(Error::AddrResolutionError),
-        };
+    pub fn connect(address: impl Into<CameraAddress>) -> Result<BcCamera> {
+        BcCamera::connect_with_transport(address, Transport::Tcp)
+    }
 
-        for addr in addr_iter {
+    /// Connects over UDP or TCP.  A camera can only be reached over UDP by its UID.
+    pub fn connect_with_transport(
+        address: impl Into<CameraAddress>,
+        transport: Transport,
+    ) -> Result<BcCamera> {
+        let address = address.into();
+        let uid = match (&address, transport) {
+            (CameraAddress::Uid(uid), Transport::Udp) => Some(uid.clone()),
+            (CameraAddress::Host(_), Transport::Udp) => {
+                return Err(Error::Other(
+                    "A camera can only be reached over UDP by its UID",
+                ))
+            }
+            (_, Transport::Tcp) => None,
+        };
+        for addr in resolve(address)? {
             debug!("Trying {}", addr);
-            let conn = match BcConnection::new(addr, RX_TIMEOUT) {
+            let conn = match &uid {
+                Some(uid) => BcConnection::new_udp(addr.ip(), uid, RX_TIMEOUT),
+                None => BcConnection::new_tcp(addr, RX_TIMEOUT),
+            };
+            let conn = match conn {
                 Ok(conn) => conn,
                 Err(err) => match err {
                     connection::Error::CommunicationError(ref err) => {
@@ -108,6 +208,8 @@ impl BcCamera {
                 address: addr,
                 connection: Some(conn),
                 logged_in: false,
+                credentials: None,
+                video_streams: Mutex::new(HashMap::new()),
             });
         }
 
@@ -121,7 +223,7 @@ impl BcCamera {
         self.connection = None;
     }
 
-    pub fn login(&mut self, username: &str, password: Option<&str>) -> Result<DeviceInfo> {
+    pub fn login(&mut self, username: &str, password: Option<&str>) -> Result<LoginInfo> {
         let connection = self
             .connection
             .as_ref()
@@ -130,209 +232,427 @@ impl BcCamera {
 
         // Login flow is: Send legacy login message, expect back a modern message with Encryption
         // details.  Then, re-send the login as a modern login message.  Expect back a device info
-        // congratulating us on logging in.
-
-        // In the legacy scheme, username/password are MD5'd if they are encrypted (which they need
-        // to be to "upgrade" to the modern login flow), then the hex of the MD5 is sent.
-        // Note: I suspect there may be a buffer overflow opportunity in the firmware since in the
-        // Baichuan library, these strings are capped at 32 bytes with a null terminator.  This
-        // could also be a mistake in the library, the effect being it only compares 31 chars, not 32.
-        let md5_username = md5_string(username, ZeroLast);
-        let md5_password = password
-            .map(|p| md5_string(p, ZeroLast))
-            .unwrap_or_else(|| EMPTY_LEGACY_PASSWORD.to_owned());
-
-        let legacy_login = Bc {
-            meta: BcMeta {
-                msg_id: MSG_ID_LOGIN,
-                client_idx: 0,
-                encrypted: true,
-                class: 0x6514,
-            },
-            body: BcBody::LegacyMsg(LegacyMsg::LoginMsg {
-                username: md5_username,
-                password: md5_password,
-            }),
-        };
-
-        sub_login.send(legacy_login)?;
-
-        let legacy_reply = sub_login.rx.recv_timeout(RX_TIMEOUT)?;
-        let nonce;
-        match legacy_reply.body {
-            BcBody::ModernMsg(ModernMsg {
-                xml:
-                    Some(BcXml {
-                        encryption: Some(encryption),
-                        ..
-                    }),
-                ..
-            }) => {
-                nonce = encryption.nonce;
-            }
-            _ => {
-                return Err(Error::UnintelligibleReply {
-                    reply: legacy_reply,
-                    why: "Expected an Encryption message back",
-                })
-            }
-        }
-
-        // In the modern login flow, the username/password are concat'd with the server's nonce
-        // string, then MD5'd, then the hex of this MD5 is sent as the password.  This nonce
-        // prevents replay attacks if the server were to require modern flow, but not rainbow table
-        // attacks (since the plain user/password MD5s have already been sent).  The upshot is that
-        // you should use a very strong random password that is not found in a rainbow table and
-        // not feasibly crackable with John the Ripper.
-
-        let modern_password = password.unwrap_or("");
-        let concat_username = format!("{}{}", username, nonce);
-        let concat_password = format!("{}{}", modern_password, nonce);
-        let md5_username = md5_string(&concat_username, Truncate);
-        let md5_password = md5_string(&concat_password, Truncate);
-
-        let modern_login = Bc::new_from_xml(
-            BcMeta {
-                msg_id: MSG_ID_LOGIN,
-                client_idx: 0, // TODO
-                encrypted: true,
-                class: 0x6414,
-            },
-            BcXml {
-                login_user: Some(LoginUser {
-                    version: xml_ver(),
-                    user_name: md5_username,
-                    password: md5_password,
-                    user_ver: 1,
-                }),
-                login_net: Some(LoginNet::default()),
-                ..Default::default()
-            },
-        );
-
-        sub_login.send(modern_login)?;
-        let modern_reply = sub_login.rx.recv_timeout(RX_TIMEOUT)?;
-
-        let device_info;
-        match modern_reply.body {
-            BcBody::ModernMsg(ModernMsg {
-                xml:
-                    Some(BcXml {
-                        device_info: Some(info),
-                        ..
-                    }),
-                ..
-            }) => {
-                // Login succeeded!
-                self.logged_in = true;
-                device_info = info;
-            }
-            BcBody::ModernMsg(ModernMsg {
-                xml: None,
-                binary: None,
-            }) => return Err(Error::AuthFailed),
-            _ => {
-                return Err(Error::UnintelligibleReply {
-                    reply: modern_reply,
-                    why: "Expected a DeviceInfo message back from login",
-                })
-            }
-        }
-
-        Ok(device_info)
+        // and stream info list congratulating us on logging in.
+        sub_login.send(legacy_login_msg(username, password))?;
+        let nonce = login_nonce(sub_login.rx.recv_timeout(RX_TIMEOUT)?)?;
+
+        let udp_port = connection.udp_port();
+        sub_login.send(modern_login_msg(username, password, &nonce, udp_port))?;
+        let login_info = login_info(sub_login.rx.recv_timeout(RX_TIMEOUT)?)?;
+
+        // Login succeeded!
+        self.logged_in = true;
+        self.credentials = Some(Credentials {
+            username: username.to_string(),
+            password: password.map(str::to_string),
+        });
+        Ok(login_info)
     }
 
     pub fn logout(&mut self) -> Result<()> {
-        if self.logged_in {
-            // TODO
+        if !self.logged_in {
+            return Ok(());
         }
+        // Whether or not the camera acknowledges us, the session is over from our point of view
         self.logged_in = false;
-        Ok(())
+
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to log out");
+        let credentials = self
+            .credentials
+            .take()
+            .expect("Credentials are always stored on login");
+        let sub_logout = connection.subscribe(MSG_ID_LOGOUT)?;
+
+        sub_logout.send(logout_msg(credentials))?;
+        logout_reply(sub_logout.rx.recv_timeout(RX_TIMEOUT)?)
     }
 
     pub fn ping(&self) -> Result<()> {
         let connection = self.connection.as_ref().expect("Must be connected to ping");
         let sub_ping = connection.subscribe(MSG_ID_PING)?;
 
-        let ping = Bc {
-            meta: BcMeta {
-                msg_id: MSG_ID_PING,
-                client_idx: 0,
-                encrypted: true,
-                class: 0x6414,
-            },
-            body: BcBody::ModernMsg(ModernMsg {
-                ..Default::default()
-            }),
-        };
-
-        sub_ping.send(ping)?;
+        sub_ping.send(ping_msg())?;
 
         sub_ping.rx.recv_timeout(RX_TIMEOUT)?;
 
         Ok(())
     }
 
+    /// Streams video and audio from the camera into `data_outs`.  This only returns successfully
+    /// once another thread has called `stop_video`.  The main and sub streams can run at the same
+    /// time, from different threads.
     pub fn start_video(
         &self,
         data_outs: &mut GstOutputs,
         stream_name: &str,
         channel_id: u32,
-    ) -> Result<Never> {
+    ) -> Result<()> {
         let connection = self
             .connection
             .as_ref()
             .expect("Must be connected to start video");
         let sub_video = connection.subscribe(MSG_ID_VIDEO)?;
-
-        let start_video = Bc::new_from_xml(
-            BcMeta {
-                msg_id: MSG_ID_VIDEO,
-                client_idx: 0, // TODO
-                encrypted: true,
-                class: 0x6414, // IDK why
-            },
-            BcXml {
-                preview: Some(Preview {
-                    version: xml_ver(),
-                    channel_id,
-                    handle: 0,
-                    stream_type: stream_name.to_string(),
-                }),
-                ..Default::default()
+        let key = (channel_id, stream_handle(stream_name));
+        let msg_num = sub_video
+            .msg_num()
+            .expect("Video subscriptions are numbered");
+        self.video_streams.lock().unwrap().insert(
+            key,
+            VideoStream {
+                msg_num,
+                stopped: false,
             },
         );
 
-        sub_video.send(start_video)?;
+        let result = self.receive_video(&sub_video, data_outs, stream_name, channel_id);
+        self.video_streams.lock().unwrap().remove(&key);
+        result
+    }
+
+    fn receive_video(
+        &self,
+        sub_video: &BcSubscription,
+        data_outs: &mut GstOutputs,
+        stream_name: &str,
+        channel_id: u32,
+    ) -> Result<()> {
+        let key = (channel_id, stream_handle(stream_name));
+        sub_video.send(start_video_msg(stream_name, channel_id))?;
 
-        let mut media_sub = MediaDataSubscriber::from_bc_sub(&sub_video);
+        let mut media_sub = MediaDataSubscriber::from_bc_sub(sub_video);
+        // This is a new stream, so its times start again from zero
+        let mut clock = StreamClock::new();
+        data_outs.reset_clock();
 
         loop {
-            let binary_data = media_sub.next_media_packet()?;
-            // We now have a complete interesting packet. Send it to gst.
-            // Process the packet
-            match binary_data.kind() {
-                MediaDataKind::VideoDataIframe | MediaDataKind::VideoDataPframe => {
-                    let media_format = binary_data.media_format();
-                    data_outs.set_format(media_format);
-                    data_outs.vidsrc.write_all(binary_data.body())?;
-                }
-                MediaDataKind::AudioDataAac => {
-                    let media_format = binary_data.media_format();
-                    data_outs.set_format(media_format);
-                    data_outs.audsrc.write_all(binary_data.body())?;
-                }
-                MediaDataKind::AudioDataAdpcm => {
-                    let media_format = binary_data.media_format();
-                    data_outs.set_format(media_format);
-                    let adpcm = binary_data.body();
-                    let pcm = adpcm_to_pcm(adpcm)?;
-                    data_outs.audsrc.write_all(&pcm)?;
-                }
-                _ => {}
+            let binary_data = match media_sub.next_media_packet() {
+                Ok(binary_data) => binary_data,
+                // stop_video hangs up on our subscription once the camera has stopped the stream
+                Err(Error::TimeoutDisconnected) if self.video_stopped(key) => return Ok(()),
+                Err(e) => return Err(e),
             };
+            write_media_packet(data_outs, &mut clock, binary_data)?;
         }
     }
+
+    fn video_stopped(&self, key: (u32, u32)) -> bool {
+        match self.video_streams.lock().unwrap().get(&key) {
+            Some(stream) => stream.stopped,
+            None => false,
+        }
+    }
+
+    /// Asks the camera to stop sending the stream `stream_name` on `channel_id`, then makes the
+    /// matching `start_video` call return.  The session stays logged in, so another stream can be
+    /// started on it straight away, and any other stream carries on.
+    pub fn stop_video(&self, stream_name: &str, channel_id: u32) -> Result<()> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to stop video");
+        let sub_stop_video = connection.subscribe(MSG_ID_VIDEO_STOP)?;
+        let key = (channel_id, stream_handle(stream_name));
+
+        sub_stop_video.send(stop_video_msg(stream_name, channel_id))?;
+        sub_stop_video.rx.recv_timeout(RX_TIMEOUT)?;
+
+        // The camera won't send any more of this stream, so wake up its start_video rather than
+        // letting it time out
+        if let Some(stream) = self.video_streams.lock().unwrap().get_mut(&key) {
+            stream.stopped = true;
+            connection.hang_up_on(MSG_ID_VIDEO, stream.msg_num);
+        }
+
+        Ok(())
+    }
+}
+
+/// Where a camera can be reached: the addresses its host name resolves to, or where it answered a
+/// search for its UID.  This blocks while it looks them up.
+fn resolve(address: CameraAddress) -> Result<Vec<SocketAddr>> {
+    match address {
+        CameraAddress::Host(host) => match host.to_socket_addrs() {
+            Ok(iter) => Ok(iter.collect()),
+            Err(_) => Err(Error::AddrResolutionError),
+        },
+        CameraAddress::Uid(uid) => {
+            let camera = find_camera_by_uid(&uid, DISCOVERY_TIMEOUT)?;
+            debug!("Found {} at {}", uid, camera.address);
+            Ok(vec![camera.address])
+        }
+    }
+}
+
+/// The first message of the login, in the legacy format every camera understands
+fn legacy_login_msg(username: &str, password: Option<&str>) -> Bc {
+    // In the legacy scheme, username/password are MD5'd if they are encrypted (which they need
+    // to be to "upgrade" to the modern login flow), then the hex of the MD5 is sent.
+    // Note: I suspect there may be a buffer overflow opportunity in the firmware since in the
+    // Baichuan library, these strings are capped at 32 bytes with a null terminator.  This
+    // could also be a mistake in the library, the effect being it only compares 31 chars, not 32.
+    let md5_username = md5_string(username, ZeroLast);
+    let md5_password = password
+        .map(|p| md5_string(p, ZeroLast))
+        .unwrap_or_else(|| EMPTY_LEGACY_PASSWORD.to_owned());
+
+    Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_LOGIN,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6514,
+        },
+        body: BcBody::LegacyMsg(LegacyMsg::LoginMsg {
+            username: md5_username,
+            password: md5_password,
+        }),
+    }
+}
+
+/// The nonce the camera answers the legacy login with, for the modern login to use
+fn login_nonce(legacy_reply: Bc) -> Result<String> {
+    match legacy_reply.body {
+        BcBody::ModernMsg(ModernMsg {
+            xml:
+                Some(BcXml {
+                    encryption: Some(encryption),
+                    ..
+                }),
+            ..
+        }) => Ok(encryption.nonce),
+        _ => Err(Error::UnintelligibleReply {
+            reply: Box::new(legacy_reply),
+            why: "Expected an Encryption message back",
+        }),
+    }
+}
+
+/// The second message of the login, which proves we know the password without sending it.  It
+/// also tells the camera how we are connected: over TCP as a LAN client, or over UDP from
+/// `udp_port`.
+fn modern_login_msg(
+    username: &str,
+    password: Option<&str>,
+    nonce: &str,
+    udp_port: Option<u16>,
+) -> Bc {
+    // In the modern login flow, the username/password are concat'd with the server's nonce
+    // string, then MD5'd, then the hex of this MD5 is sent as the password.  This nonce
+    // prevents replay attacks if the server were to require modern flow, but not rainbow table
+    // attacks (since the plain user/password MD5s have already been sent).  The upshot is that
+    // you should use a very strong random password that is not found in a rainbow table and
+    // not feasibly crackable with John the Ripper.
+
+    let modern_password = password.unwrap_or("");
+    let concat_username = format!("{}{}", username, nonce);
+    let concat_password = format!("{}{}", modern_password, nonce);
+    let md5_username = md5_string(&concat_username, Truncate);
+    let md5_password = md5_string(&concat_password, Truncate);
+
+    Bc::new_from_xml(
+        BcMeta {
+            msg_id: MSG_ID_LOGIN,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        BcXml {
+            login_user: Some(LoginUser {
+                version: xml_ver(),
+                user_name: md5_username,
+                password: md5_password,
+                user_ver: 1,
+                ..Default::default()
+            }),
+            login_net: Some(match udp_port {
+                Some(port) => LoginNet::over_udp(port),
+                None => LoginNet::default(),
+            }),
+            ..Default::default()
+        },
+    )
+}
+
+/// What the camera answers a successful modern login with.  An empty reply means the credentials
+/// were wrong.
+fn login_info(modern_reply: Bc) -> Result<LoginInfo> {
+    match modern_reply.body {
+        BcBody::ModernMsg(ModernMsg {
+            xml:
+                Some(BcXml {
+                    device_info: Some(device_info),
+                    stream_info_list,
+                    ..
+                }),
+            ..
+        }) => Ok(LoginInfo {
+            device_info,
+            stream_info_list,
+        }),
+        BcBody::ModernMsg(ModernMsg {
+            extension: None,
+            xml: None,
+            binary: None,
+        }) => Err(Error::AuthFailed),
+        _ => Err(Error::UnintelligibleReply {
+            reply: Box::new(modern_reply),
+            why: "Expected a DeviceInfo message back from login",
+        }),
+    }
+}
+
+fn logout_msg(credentials: Credentials) -> Bc {
+    // Unlike login, the logout message carries the username and password in plain text
+    Bc::new_from_xml(
+        BcMeta {
+            msg_id: MSG_ID_LOGOUT,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        BcXml {
+            login_user: Some(LoginUser {
+                version: xml_ver(),
+                user_name: credentials.username,
+                password: credentials.password.unwrap_or_default(),
+                user_ver: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+}
+
+fn logout_reply(reply: Bc) -> Result<()> {
+    match reply.body {
+        BcBody::ModernMsg(_) => Ok(()),
+        _ => Err(Error::UnintelligibleReply {
+            reply: Box::new(reply),
+            why: "Expected a modern reply to logout",
+        }),
+    }
+}
+
+fn ping_msg() -> Bc {
+    Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_PING,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        body: BcBody::ModernMsg(ModernMsg {
+            ..Default::default()
+        }),
+    }
+}
+
+fn start_video_msg(stream_name: &str, channel_id: u32) -> Bc {
+    Bc::new_from_xml(
+        BcMeta {
+            msg_id: MSG_ID_VIDEO,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414, // IDK why
+        },
+        BcXml {
+            preview: Some(Preview {
+                version: xml_ver(),
+                channel_id,
+                handle: stream_handle(stream_name),
+                stream_type: Some(stream_name.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+}
+
+fn stop_video_msg(stream_name: &str, channel_id: u32) -> Bc {
+    Bc::new_from_xml(
+        BcMeta {
+            msg_id: MSG_ID_VIDEO_STOP,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        BcXml {
+            preview: Some(Preview {
+                version: xml_ver(),
+                channel_id,
+                handle: stream_handle(stream_name),
+                stream_type: None,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+}
+
+/// Hands a complete media packet to GStreamer, timestamped by `clock`
+fn write_media_packet(
+    data_outs: &mut GstOutputs,
+    clock: &mut StreamClock,
+    binary_data: MediaData,
+) -> Result<()> {
+    match binary_data.kind() {
+        MediaDataKind::InfoData => {
+            if let Some(stream_info) = binary_data.stream_info() {
+                data_outs.set_stream_info(stream_info);
+            }
+        }
+        MediaDataKind::VideoDataIframe | MediaDataKind::VideoDataPframe => {
+            let media_format = binary_data.media_format();
+            data_outs.set_format(media_format);
+            let camera_time = binary_data
+                .microseconds()
+                .expect("Video frames always have a time");
+            let keyframe = binary_data.kind() == MediaDataKind::VideoDataIframe;
+            let video_time = clock.video_time(camera_time);
+            data_outs.write_video(binary_data.body_bytes(), video_time, keyframe)?;
+        }
+        MediaDataKind::AudioDataAac => {
+            let media_format = binary_data.media_format();
+            data_outs.set_format(media_format);
+            let duration = aac_duration(binary_data.body());
+            data_outs.write_audio(binary_data.body_bytes(), clock.audio_time(duration))?;
+        }
+        MediaDataKind::AudioDataAdpcm => {
+            let media_format = binary_data.media_format();
+            data_outs.set_format(media_format);
+            let adpcm = binary_data.body();
+            let pcm = adpcm_to_pcm(adpcm)?;
+            // Two bytes for each decoded sample
+            let duration = (pcm.len() / 2) as u64 * 1_000_000 / ADPCM_SAMPLE_RATE;
+            data_outs.write_audio(pcm.into(), clock.audio_time(Some(duration)))?;
+        }
+        _ => {}
+    };
+    Ok(())
+}
+
+/// The camera tells apart the streams of a channel by the handle they are started with
+fn stream_handle(stream_name: &str) -> u32 {
+    match stream_name {
+        "subStream" => 256,
+        "externStream" => 1024,
+        _ => 0,
+    }
 }
 
 /// The Baichuan library has a very peculiar behavior where it always zeros the last byte.  I
@@ -367,3 +687,26 @@ fn test_md5_string() {
         "21232F297A57A5A743894A0E4A801FC\0"
     );
 }
+
+#[test]
+fn test_modern_login_net() {
+    let login_net = |msg: Bc| match msg.body {
+        BcBody::ModernMsg(ModernMsg {
+            xml:
+                Some(BcXml {
+                    login_net: Some(login_net),
+                    ..
+                }),
+            ..
+        }) => login_net,
+        body => panic!("Unexpected body {:?}", body),
+    };
+
+    let tcp = login_net(modern_login_msg("admin", None, "nonce", None));
+    assert_eq!(tcp.type_, "LAN");
+    assert_eq!(tcp.udp_port, 0);
+
+    let udp = login_net(modern_login_msg("admin", None, "nonce", Some(4567)));
+    assert_eq!(udp.type_, "P2P");
+    assert_eq!(udp.udp_port, 4567);
+}
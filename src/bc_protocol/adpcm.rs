@@ -4,6 +4,7 @@
 use crate::Error;
 use log::error;
 use std::convert::TryInto;
+use std::iter::repeat;
 
 struct AdpcmSetup {
     max_step_index: u32,
@@ -242,3 +243,118 @@ pub fn adpcm_to_pcm(bytes: &[u8]) -> Result<Vec<u8>, Error> {
     }
     Ok(result)
 }
+
+/// Encodes 16 bit mono PCM samples into DVI4 blocks of `block_samples` samples each, in the same
+/// form that `adpcm_to_pcm` decodes (and that the camera sends and expects). The last block is
+/// padded with silence.
+pub fn pcm_to_adpcm(samples: &[i16], block_samples: usize) -> Vec<u8> {
+    let context = AdpcmSetup::new_ima();
+
+    // Two samples per byte, so blocks must hold an even number of them
+    let block_samples = std::cmp::max(2, block_samples & !1);
+    let data_size = block_samples / 2;
+    // The block size in the header counts the predictor state as well as the data, and is
+    // stored halved like the decoder expects
+    let half_block_size = ((data_size + 4) / 2) as u16;
+
+    let mut result: Vec<u8> = vec![];
+
+    // The encoder state carries over between blocks, and each block header records where it
+    // was at the start of that block
+    let mut last_output: i32 = 0;
+    let mut step_index: i32 = 0;
+
+    for block in samples.chunks(block_samples) {
+        result.extend(&[0x00, 0x01]); // HISilicon frame type
+        result.extend(&half_block_size.to_le_bytes());
+        result.extend(&(last_output as i16).to_le_bytes());
+        result.extend(&(step_index as u16).to_le_bytes());
+
+        let mut high_nibble: Option<u8> = None;
+        for &sample in block.iter().chain(repeat(&0)).take(block_samples) {
+            let step = context.steps[step_index as usize] as i32;
+
+            let mut delta = sample as i32 - last_output;
+            let mut unibble: u8 = 0;
+            if delta < 0 {
+                unibble |= 0b1000;
+                delta = -delta;
+            }
+
+            // Choose each magnitude bit so that the decoder's bit shift approximation (see
+            // adpcm_to_pcm) reconstructs a sample as close as possible to this one
+            let mut diff = step >> 3;
+            if delta >= step {
+                unibble |= 0b0100;
+                delta -= step;
+                diff += step;
+            }
+            if delta >= step >> 1 {
+                unibble |= 0b0010;
+                delta -= step >> 1;
+                diff += step >> 1;
+            }
+            if delta >= step >> 2 {
+                unibble |= 0b0001;
+                diff += step >> 2;
+            }
+
+            // Track the decoder's output rather than the input, so errors don't accumulate
+            let raw_sample = if (unibble & 0b1000) == 0b1000 {
+                last_output - diff
+            } else {
+                last_output + diff
+            };
+            last_output = match raw_sample {
+                value if value > context.max_sample_size - 1 => context.max_sample_size - 1,
+                value if value < -context.max_sample_size => -context.max_sample_size,
+                value => value,
+            };
+
+            step_index = match step_index + context.changes[unibble as usize] {
+                n if n < 0 => 0,
+                n if n > context.max_step_index as i32 => context.max_step_index as i32,
+                n => n,
+            };
+
+            // The first sample of each pair goes in the high nibble
+            match high_nibble.take() {
+                None => high_nibble = Some(unibble),
+                Some(high) => result.push((high << 4) | unibble),
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn test_adpcm_roundtrip() {
+    // A loud 500Hz tone at 16kHz, spanning a few blocks with a partial one at the end
+    let samples: Vec<i16> = (0..3000)
+        .map(|i| ((i as f32 * 2.0 * std::f32::consts::PI * 500.0 / 16000.0).sin() * 20000.0) as i16)
+        .collect();
+
+    let adpcm = pcm_to_adpcm(&samples, 1024);
+    // 3 blocks, each of 4 byte frame header + 4 byte predictor state + 512 bytes of data
+    assert_eq!(adpcm.len(), 3 * (8 + 512));
+    assert_eq!(&adpcm[0..4], &[0x00, 0x01, 0x02, 0x01]);
+
+    let pcm = adpcm_to_pcm(&adpcm).unwrap();
+    assert_eq!(pcm.len(), 3 * 1024 * 2);
+    let decoded: Vec<i16> = pcm
+        .chunks(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+
+    // Skip the first few samples while the step size adapts to the signal
+    for (original, decoded) in samples.iter().zip(decoded.iter()).skip(32) {
+        assert!(
+            (*original as i32 - *decoded as i32).abs() < 2000,
+            "{} decoded as {}",
+            original,
+            decoded
+        );
+    }
+    // The padding is silence
+    assert!(decoded[3000 + 32..].iter().all(|s| s.abs() < 2000));
+}
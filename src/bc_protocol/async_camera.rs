@@ -0,0 +1,296 @@
+use super::connection::{self, AsyncBcConnection};
+use super::media_packet::{MediaDataBuffer, StreamClock};
+use super::time::{get_general_msg, time_from_reply};
+use super::*;
+use ::time::OffsetDateTime;
+use std::sync::{mpsc, Arc};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::oneshot;
+
+/// A camera, reached over TCP, whose methods are futures.  It needs no thread of its own, so a
+/// tokio runtime with a handful of threads can serve any number of them; the connection is
+/// received on by a task, which must be spawned within the runtime.
+pub struct AsyncBcCamera {
+    address: SocketAddr,
+    /// Shared with the task that logs out once the camera is dropped
+    connection: Arc<AsyncBcConnection>,
+    /// Set while logged in
+    credentials: Mutex<Option<Credentials>>,
+}
+
+impl Drop for AsyncBcCamera {
+    fn drop(&mut self) {
+        // We can't wait for the camera here, so log out in the background if there is a runtime
+        // to do it on
+        let credentials = self.credentials.get_mut().unwrap().take();
+        if let (Some(credentials), Ok(runtime)) =
+            (credentials, tokio::runtime::Handle::try_current())
+        {
+            let connection = self.connection.clone();
+            runtime.spawn(async move {
+                if let Err(err) = logout(&connection, credentials).await {
+                    warn!("Could not log out, ignoring: {}", err);
+                }
+            });
+        }
+    }
+}
+
+impl AsyncBcCamera {
+    pub async fn connect(address: impl Into<CameraAddress>) -> Result<AsyncBcCamera> {
+        let address = address.into();
+        // Looking up a host name or searching for a UID blocks, so do it off the runtime
+        let addrs = tokio::task::spawn_blocking(move || resolve(address))
+            .await
+            .expect("Address resolution panicked")?;
+
+        for addr in addrs {
+            debug!("Trying {}", addr);
+            let connection = match AsyncBcConnection::new_tcp(addr, RX_TIMEOUT).await {
+                Ok(connection) => connection,
+                Err(connection::Error::CommunicationError(ref err)) => {
+                    debug!("Assuming timeout from {}", err);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            debug!("Success: {}", addr);
+            return Ok(AsyncBcCamera {
+                address: addr,
+                connection: Arc::new(connection),
+                credentials: Mutex::new(None),
+            });
+        }
+
+        Err(Error::Timeout)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub async fn login(&self, username: &str, password: Option<&str>) -> Result<LoginInfo> {
+        let mut sub_login = self.connection.subscribe(MSG_ID_LOGIN)?;
+
+        sub_login.send(legacy_login_msg(username, password)).await?;
+        let nonce = login_nonce(recv(&mut sub_login.rx).await?)?;
+
+        sub_login
+            .send(modern_login_msg(username, password, &nonce, None))
+            .await?;
+        let login_info = login_info(recv(&mut sub_login.rx).await?)?;
+
+        *self.credentials.lock().unwrap() = Some(Credentials {
+            username: username.to_string(),
+            password: password.map(str::to_string),
+        });
+        Ok(login_info)
+    }
+
+    /// Ends the session the camera keeps for our login.  Dropping the camera does this too, but
+    /// without waiting for the camera's answer.
+    pub async fn logout(&self) -> Result<()> {
+        // Whether or not the camera acknowledges us, the session is over from our point of view
+        let credentials = self.credentials.lock().unwrap().take();
+        match credentials {
+            Some(credentials) => logout(&self.connection, credentials).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let mut sub_ping = self.connection.subscribe(MSG_ID_PING)?;
+
+        sub_ping.send(ping_msg()).await?;
+        recv(&mut sub_ping.rx).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_time(&self) -> Result<Option<OffsetDateTime>> {
+        let mut sub_get_general = self.connection.subscribe(MSG_ID_GET_GENERAL)?;
+
+        sub_get_general.send(get_general_msg()).await?;
+        time_from_reply(recv(&mut sub_get_general.rx).await?)
+    }
+
+    /// Streams video and audio from the camera into `data_outs` until the connection drops.  To
+    /// stop sooner, drop the future and call `stop_video`.  Any number of streams can run at once.
+    ///
+    /// Writing to the outputs can block, for instance while a recording is finished, so it is done
+    /// on a thread of its own rather than on the runtime.
+    pub async fn start_video(
+        &self,
+        data_outs: Arc<Mutex<GstOutputs>>,
+        stream_name: &str,
+        channel_id: u32,
+    ) -> Result<()> {
+        let mut sub_video = self.connection.subscribe(MSG_ID_VIDEO)?;
+        sub_video
+            .send(start_video_msg(stream_name, channel_id))
+            .await?;
+
+        let mut media = MediaDataBuffer::new();
+        let (packet_tx, packet_rx) = mpsc::channel();
+        let (error_tx, error_rx) = oneshot::channel();
+        // The thread finishes once the stream does, and we drop the sender
+        std::thread::spawn(move || {
+            if let Err(err) = write_media_packets(&data_outs, packet_rx) {
+                let _ = error_tx.send(err);
+            }
+        });
+
+        loop {
+            while let Some(binary_data) = media.next_media_packet() {
+                if packet_tx.send(binary_data).is_err() {
+                    // The thread only stops early when writing fails
+                    return Err(error_rx
+                        .await
+                        .unwrap_or(Error::Other("Media writer stopped")));
+                }
+            }
+            if let BcBody::ModernMsg(ModernMsg {
+                binary: Some(binary),
+                ..
+            }) = recv(&mut sub_video.rx).await?.body
+            {
+                media.extend(binary);
+            }
+        }
+    }
+
+    /// Asks the camera to stop sending the stream `stream_name` on `channel_id`
+    pub async fn stop_video(&self, stream_name: &str, channel_id: u32) -> Result<()> {
+        let mut sub_stop_video = self.connection.subscribe(MSG_ID_VIDEO_STOP)?;
+
+        sub_stop_video
+            .send(stop_video_msg(stream_name, channel_id))
+            .await?;
+        recv(&mut sub_stop_video.rx).await?;
+
+        Ok(())
+    }
+}
+
+/// Writes the packets of one stream to `data_outs` as they arrive
+fn write_media_packets(
+    data_outs: &Mutex<GstOutputs>,
+    packets: mpsc::Receiver<MediaData>,
+) -> Result<()> {
+    // This is a new stream, so its times start again from zero
+    let mut clock = StreamClock::new();
+    data_outs.lock().unwrap().reset_clock();
+    for binary_data in packets {
+        write_media_packet(&mut data_outs.lock().unwrap(), &mut clock, binary_data)?;
+    }
+    Ok(())
+}
+
+async fn logout(connection: &AsyncBcConnection, credentials: Credentials) -> Result<()> {
+    let mut sub_logout = connection.subscribe(MSG_ID_LOGOUT)?;
+
+    sub_logout.send(logout_msg(credentials)).await?;
+    logout_reply(recv(&mut sub_logout.rx).await?)
+}
+
+/// Waits for the next message for a subscription, as long as a blocking receive would
+async fn recv(rx: &mut UnboundedReceiver<Bc>) -> Result<Bc> {
+    match tokio::time::timeout(RX_TIMEOUT, rx.recv()).await {
+        Ok(Some(msg)) => Ok(msg),
+        Ok(None) => Err(Error::TimeoutDisconnected),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+#[tokio::test]
+async fn test_async_camera() {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    let nonce_reply = &include_bytes!("../bc/samples/model_sample_modern_login.bin")[..];
+    let login_reply = &include_bytes!("../bc/samples/modern_login_success.bin")[..];
+
+    // Stands in for a camera, answering each request the way one does
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let camera = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut camera = BcCodec::new(socket);
+        for reply in &[nonce_reply, login_reply] {
+            let login = camera.next().await.unwrap().unwrap();
+            assert_eq!(login.meta.msg_id, MSG_ID_LOGIN);
+            // The recorded replies are numbered as the camera numbers its reply to this request
+            let mut reply = reply.to_vec();
+            reply[14..16].copy_from_slice(&login.meta.msg_num.to_le_bytes());
+            camera.get_mut().write_all(&reply).await.unwrap();
+        }
+
+        let ping = camera.next().await.unwrap().unwrap();
+        assert_eq!(ping.meta.msg_id, MSG_ID_PING);
+        camera.send(ping).await.unwrap();
+
+        let get = camera.next().await.unwrap().unwrap();
+        assert_eq!(get.meta.msg_id, MSG_ID_GET_GENERAL);
+        let reply = Bc::new_from_xml(
+            get.meta,
+            BcXml {
+                system_general: Some(SystemGeneral {
+                    version: xml_ver(),
+                    time_zone: Some(0),
+                    year: Some(2020),
+                    month: Some(10),
+                    day: Some(17),
+                    hour: Some(12),
+                    minute: Some(30),
+                    second: Some(5),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        camera.send(reply).await.unwrap();
+
+        // Logging out sends the plain credentials, and only once
+        let logout = camera.next().await.unwrap().unwrap();
+        assert_eq!(logout.meta.msg_id, MSG_ID_LOGOUT);
+        match &logout.body {
+            BcBody::ModernMsg(ModernMsg {
+                xml:
+                    Some(BcXml {
+                        login_user: Some(user),
+                        ..
+                    }),
+                ..
+            }) => assert_eq!((&*user.user_name, &*user.password), ("admin", "12345678")),
+            body => panic!("Unexpected logout {:?}", body),
+        }
+        camera
+            .send(Bc {
+                meta: logout.meta,
+                body: BcBody::ModernMsg(Default::default()),
+            })
+            .await
+            .unwrap();
+        assert!(camera.next().await.is_none());
+    });
+
+    let cam = AsyncBcCamera::connect(&address).await.unwrap();
+    let login_info = cam.login("admin", Some("12345678")).await.unwrap();
+    assert_eq!(login_info.device_info.resolution.width, 3840);
+    assert!(login_info.stream_info_list.is_some());
+    cam.ping().await.unwrap();
+    assert_eq!(
+        cam.get_time().await.unwrap(),
+        Some(
+            ::time::date!(2020 - 10 - 17)
+                .with_time(::time::time!(12:30:05))
+                .assume_utc()
+        )
+    );
+    cam.logout().await.unwrap();
+    cam.logout().await.unwrap();
+    drop(cam);
+    camera.await.unwrap();
+}
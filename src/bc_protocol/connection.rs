@@ -1,30 +1,68 @@
 use crate::bc;
+use crate::bc::de::BcDecoder;
 use crate::bc::model::*;
 use err_derive::Error;
 use log::*;
 use socket2::{Domain, Socket, Type};
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
-use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
+mod async_connection;
+mod codec;
+mod udp;
+
+pub use self::async_connection::AsyncBcConnection;
+pub use self::codec::BcCodec;
+pub use self::udp::UdpTransport;
+
+/// Carries Baichuan messages to and from a camera.  Either way, they travel as a stream of bytes
+/// that the transport keeps in order.
+pub trait BcTransport: Send + Sync {
+    /// Sends a whole serialized message, without interleaving it with any other
+    fn send(&self, buf: &[u8]) -> std::io::Result<()>;
+
+    /// The incoming stream, read by the receiving thread.  Only called once.
+    fn reader(&self) -> std::io::Result<Box<dyn Read + Send>>;
+
+    /// Ends the connection, making the reader return EOF or an error
+    fn shutdown(&self);
+
+    /// The port to tell the camera about at login, if the transport uses UDP
+    fn udp_port(&self) -> Option<u16> {
+        None
+    }
+}
+
 /// A shareable connection to a camera.  Handles serialization of messages.  To send/receive, call
 /// .subscribe() with a message ID.  You can use the BcSubscription to send or receive only
 /// messages with that ID; each incoming message is routed to its appropriate subscriber.
 ///
-/// There can be only one subscriber per kind of message at a time.
+/// Each subscription sends with its own message number, which the camera echoes in its replies,
+/// so any number of them can share a message ID: two video streams, say, or two queries at once.
+/// Messages the camera sends of its own accord, like motion alarms, go to the one subscriber
+/// from .subscribe_all() instead.
 pub struct BcConnection {
-    connection: Arc<Mutex<TcpStream>>,
-    subscribers: Arc<Mutex<BTreeMap<u32, Sender<Bc>>>>,
+    transport: Box<dyn BcTransport>,
+    subscribers: Arc<Mutex<Subscribers>>,
+    next_msg_num: AtomicU16,
     rx_thread: Option<JoinHandle<()>>,
 }
 
+/// Subscribers by message ID and number.  No number means all messages with the ID that are not
+/// for another subscriber.
+type Subscribers<S = Sender<Bc>> = BTreeMap<(u32, Option<u16>), S>;
+
 pub struct BcSubscription<'a> {
     pub rx: Receiver<Bc>,
     msg_id: u32,
+    msg_num: Option<u16>,
     conn: &'a BcConnection,
 }
 
@@ -46,33 +84,62 @@ pub enum Error {
 }
 
 impl BcConnection {
-    pub fn new(addr: SocketAddr, timeout: Duration) -> Result<BcConnection> {
+    /// Connects over TCP, which is how most cameras are reached
+    pub fn new_tcp(addr: SocketAddr, timeout: Duration) -> Result<BcConnection> {
         let tcp_conn = connect_to(addr, timeout)?;
-        let subscribers: Arc<Mutex<BTreeMap<u32, Sender<Bc>>>> = Default::default();
+        BcConnection::new(Box::new(TcpTransport {
+            stream: Mutex::new(tcp_conn),
+        }))
+    }
+
+    /// Connects over UDP to the camera with this UID at `ip`
+    pub fn new_udp(ip: IpAddr, uid: &str, timeout: Duration) -> Result<BcConnection> {
+        BcConnection::new(Box::new(UdpTransport::connect(ip, uid, timeout)?))
+    }
+
+    pub fn new(transport: Box<dyn BcTransport>) -> Result<BcConnection> {
+        let subscribers: Arc<Mutex<Subscribers>> = Default::default();
 
         let mut subs = subscribers.clone();
-        let conn = tcp_conn.try_clone()?;
+        let mut conn = transport.reader()?;
 
         let rx_thread = std::thread::spawn(move || {
-            let mut context = BcContext::new();
+            let mut decoder = BcDecoder::new();
             let mut result;
             while {
-                result = BcConnection::poll(&mut context, &conn, &mut subs);
+                result = BcConnection::poll(&mut decoder, &mut conn, &mut subs);
                 result.is_ok()
             } {}
             error!("Deserialization error: {:?}", result.unwrap_err());
         });
 
         Ok(BcConnection {
-            connection: Arc::new(Mutex::new(tcp_conn)),
+            transport,
             subscribers,
+            next_msg_num: AtomicU16::new(0),
             rx_thread: Some(rx_thread),
         })
     }
 
+    /// Subscribes to the replies to what is sent with the subscription, under a message number
+    /// no other subscription is using
     pub fn subscribe(&self, msg_id: u32) -> Result<BcSubscription> {
         let (tx, rx) = channel();
-        match self.subscribers.lock().unwrap().entry(msg_id) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let msg_num = insert_numbered(&mut subscribers, &self.next_msg_num, msg_id, tx);
+        Ok(BcSubscription {
+            rx,
+            conn: self,
+            msg_id,
+            msg_num: Some(msg_num),
+        })
+    }
+
+    /// Subscribes to all messages with this ID that aren't replies to another subscription.
+    /// There can be only one such subscriber per ID at a time.
+    pub fn subscribe_all(&self, msg_id: u32) -> Result<BcSubscription> {
+        let (tx, rx) = channel();
+        match self.subscribers.lock().unwrap().entry((msg_id, None)) {
             Entry::Vacant(vac_entry) => vac_entry.insert(tx),
             Entry::Occupied(_) => return Err(Error::SimultaneousSubscription { msg_id }),
         };
@@ -80,35 +147,59 @@ impl BcConnection {
             rx,
             conn: self,
             msg_id,
+            msg_num: None,
         })
     }
 
+    /// Hangs up on every subscriber to `msg_id`.  Their receivers see the channel disconnect,
+    /// which wakes them up if they are blocked waiting for a message.
+    pub fn hang_up(&self, msg_id: u32) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|&(id, _), _| id != msg_id);
+    }
+
+    /// Hangs up on the subscriber to `msg_id` with this message number, if there is one
+    pub fn hang_up_on(&self, msg_id: u32, msg_num: u16) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .remove(&(msg_id, Some(msg_num)));
+    }
+
+    /// The port the camera can send UDP to us on, if we are connected over UDP
+    pub fn udp_port(&self) -> Option<u16> {
+        self.transport.udp_port()
+    }
+
     fn poll(
-        context: &mut BcContext,
-        connection: &TcpStream,
-        subscribers: &mut Arc<Mutex<BTreeMap<u32, Sender<Bc>>>>,
+        decoder: &mut BcDecoder,
+        connection: &mut dyn Read,
+        subscribers: &mut Arc<Mutex<Subscribers>>,
     ) -> Result<()> {
         // Don't hold the lock during deserialization so we don't poison the subscribers mutex if
         // something goes wrong
 
-        let response = Bc::deserialize(context, connection).map_err(|err| {
+        let response = decoder.read_from(connection).map_err(|err| {
             // If the connection hangs up, hang up on all subscribers
             subscribers.lock().unwrap().clear();
             err
         })?;
         let msg_id = response.meta.msg_id;
+        let msg_num = response.meta.msg_num;
 
         let mut locked_subs = subscribers.lock().unwrap();
-        match locked_subs.entry(msg_id) {
-            Entry::Occupied(mut occ) => {
-                if occ.get_mut().send(response).is_err() {
+        match route(&locked_subs, msg_id, msg_num) {
+            Some(key) => {
+                if locked_subs[&key].send(response).is_err() {
                     // Exceedingly unlikely, unless you mishandle the subscription object
                     warn!("Subscriber to ID {} dropped their channel", msg_id);
-                    occ.remove();
+                    locked_subs.remove(&key);
                 }
             }
-            Entry::Vacant(_) => {
-                debug!("Ignoring uninteresting message ID {}", msg_id);
+            None => {
+                debug!("Ignoring uninteresting message ID {} ({})", msg_id, msg_num);
                 trace!("Contents: {:?}", response);
             }
         }
@@ -117,10 +208,42 @@ impl BcConnection {
     }
 }
 
+/// Adds a subscriber to `msg_id` under a message number no other subscriber is using, returning
+/// the number
+fn insert_numbered<S>(
+    subscribers: &mut Subscribers<S>,
+    next_msg_num: &AtomicU16,
+    msg_id: u32,
+    tx: S,
+) -> u16 {
+    loop {
+        let msg_num = next_msg_num.fetch_add(1, Ordering::Relaxed);
+        if let Entry::Vacant(vac_entry) = subscribers.entry((msg_id, Some(msg_num))) {
+            vac_entry.insert(tx);
+            return msg_num;
+        }
+    }
+}
+
+/// The subscriber a message is for: the one with its number, or failing that the one taking all
+/// messages with its ID.  A reply with a number nobody is waiting on is dropped, rather than
+/// guessing who it might be for.
+fn route<S>(subscribers: &Subscribers<S>, msg_id: u32, msg_num: u16) -> Option<(u32, Option<u16>)> {
+    let exact = (msg_id, Some(msg_num));
+    let all = (msg_id, None);
+    if subscribers.contains_key(&exact) {
+        Some(exact)
+    } else if subscribers.contains_key(&all) {
+        Some(all)
+    } else {
+        None
+    }
+}
+
 impl Drop for BcConnection {
     fn drop(&mut self) {
         debug!("Shutting down BcConnection...");
-        let _ = self.connection.lock().unwrap().shutdown(Shutdown::Both);
+        self.transport.shutdown();
         match self
             .rx_thread
             .take()
@@ -138,10 +261,21 @@ impl Drop for BcConnection {
 }
 
 impl<'a> BcSubscription<'a> {
-    pub fn send(&self, bc: Bc) -> Result<()> {
+    /// The number this subscription's messages are sent with, unless it takes all messages with
+    /// its ID
+    pub fn msg_num(&self) -> Option<u16> {
+        self.msg_num
+    }
+
+    /// Sends a message, numbered so that the camera's replies come back to this subscription
+    pub fn send(&self, mut bc: Bc) -> Result<()> {
         assert!(bc.meta.msg_id == self.msg_id);
+        if let Some(msg_num) = self.msg_num {
+            bc.meta.msg_num = msg_num;
+        }
 
-        bc.serialize(&*self.conn.connection.lock().unwrap())?;
+        let buf = bc.serialize(vec![])?;
+        self.conn.transport.send(&buf)?;
         Ok(())
     }
 }
@@ -149,7 +283,30 @@ impl<'a> BcSubscription<'a> {
 /// Makes it difficult to avoid unsubscribing when you're finished
 impl<'a> Drop for BcSubscription<'a> {
     fn drop(&mut self) {
-        self.conn.subscribers.lock().unwrap().remove(&self.msg_id);
+        self.conn
+            .subscribers
+            .lock()
+            .unwrap()
+            .remove(&(self.msg_id, self.msg_num));
+    }
+}
+
+/// Baichuan over a TCP connection, whose byte stream carries the messages as they are
+struct TcpTransport {
+    stream: Mutex<TcpStream>,
+}
+
+impl BcTransport for TcpTransport {
+    fn send(&self, buf: &[u8]) -> std::io::Result<()> {
+        self.stream.lock().unwrap().write_all(buf)
+    }
+
+    fn reader(&self) -> std::io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.stream.lock().unwrap().try_clone()?))
+    }
+
+    fn shutdown(&self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
     }
 }
 
@@ -171,3 +328,83 @@ fn connect_to(addr: SocketAddr, timeout: Duration) -> Result<TcpStream> {
 
     Ok(socket.into_tcp_stream())
 }
+
+#[test]
+fn test_interleaved_subscribers() {
+    use std::net::TcpListener;
+
+    /// A recorded message, as if it had been sent with this message number
+    fn renumbered(packet: &[u8], msg_num: u16) -> Vec<u8> {
+        let mut packet = packet.to_vec();
+        packet[14..16].copy_from_slice(&msg_num.to_le_bytes());
+        packet
+    }
+    let video_start = &include_bytes!("../bc/samples/modern_video_start1.bin")[..];
+    let video_data = &include_bytes!("../bc/samples/modern_video_start2.bin")[..];
+    let login_reply = &include_bytes!("../bc/samples/modern_login_success.bin")[..];
+    let timeout = Duration::from_secs(5);
+
+    // Stands in for a camera, which the test writes to directly
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let conn = BcConnection::new_tcp(listener.local_addr().unwrap(), timeout).unwrap();
+    let (mut camera, _) = listener.accept().unwrap();
+
+    let main_stream = conn.subscribe(MSG_ID_VIDEO).unwrap();
+    let sub_stream = conn.subscribe(MSG_ID_VIDEO).unwrap();
+    let login = conn.subscribe(MSG_ID_LOGIN).unwrap();
+    let main_num = main_stream.msg_num.unwrap();
+    let sub_num = sub_stream.msg_num.unwrap();
+    let login_num = login.msg_num.unwrap();
+    assert_ne!(main_num, sub_num);
+
+    for packet in &[
+        renumbered(video_start, main_num),
+        renumbered(video_start, sub_num),
+        renumbered(video_data, sub_num),
+        // Nobody asked for this one
+        renumbered(video_start, sub_num.wrapping_add(100)),
+        // Nor this one, even though there is only one subscriber to its ID
+        renumbered(login_reply, login_num.wrapping_add(1)),
+        renumbered(login_reply, login_num),
+        renumbered(video_data, main_num),
+    ] {
+        camera.write_all(packet).unwrap();
+    }
+
+    for stream in &[&main_stream, &sub_stream] {
+        // Each stream switches to binary on its own
+        let start = stream.rx.recv_timeout(timeout).unwrap();
+        assert!(matches!(
+            start.body,
+            BcBody::ModernMsg(ModernMsg {
+                extension: Some(_),
+                binary: Some(_),
+                ..
+            })
+        ));
+        let data = stream.rx.recv_timeout(timeout).unwrap();
+        assert!(matches!(
+            data.body,
+            BcBody::ModernMsg(ModernMsg {
+                extension: None,
+                binary: Some(_),
+                ..
+            })
+        ));
+    }
+    // Everything before the last packet has been routed by now
+    assert!(main_stream.rx.try_recv().is_err());
+    assert!(sub_stream.rx.try_recv().is_err());
+    assert_eq!(
+        login.rx.recv_timeout(timeout).unwrap().meta.msg_num,
+        login_num
+    );
+    assert!(login.rx.try_recv().is_err());
+
+    // A second catch-all for the same ID is refused, and hanging up disconnects everyone
+    let all_video = conn.subscribe_all(MSG_ID_VIDEO).unwrap();
+    assert!(conn.subscribe_all(MSG_ID_VIDEO).is_err());
+    conn.hang_up(MSG_ID_VIDEO);
+    assert!(main_stream.rx.recv().is_err());
+    assert!(all_video.rx.recv().is_err());
+}
@@ -0,0 +1,173 @@
+//! The async counterpart of `BcConnection`.  Rather than a thread of its own, each connection has a
+//! task on the caller's tokio runtime, which receives messages and routes them to subscribers.
+use super::codec::BcCodec;
+use super::{insert_numbered, route, Error, Result, Subscribers};
+use crate::bc::model::*;
+use futures::{SinkExt, StreamExt};
+use log::*;
+use std::collections::btree_map::Entry;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU16;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+type AsyncSubscribers = Subscribers<UnboundedSender<Bc>>;
+
+/// A shareable connection to a camera, subscribed to in the same way as a `BcConnection`.  It must
+/// be created within a tokio runtime, which it receives messages on until it is dropped.
+pub struct AsyncBcConnection {
+    writer: tokio::sync::Mutex<BcCodec<OwnedWriteHalf>>,
+    subscribers: Arc<Mutex<AsyncSubscribers>>,
+    next_msg_num: AtomicU16,
+    rx_task: JoinHandle<()>,
+}
+
+pub struct AsyncBcSubscription<'a> {
+    pub rx: UnboundedReceiver<Bc>,
+    msg_id: u32,
+    msg_num: Option<u16>,
+    conn: &'a AsyncBcConnection,
+}
+
+impl AsyncBcConnection {
+    pub async fn new_tcp(addr: SocketAddr, timeout: Duration) -> Result<AsyncBcConnection> {
+        let stream = match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+        };
+        Ok(AsyncBcConnection::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> AsyncBcConnection {
+        let (reader, writer) = stream.into_split();
+        let subscribers: Arc<Mutex<AsyncSubscribers>> = Default::default();
+        let rx_task = tokio::spawn(receive(BcCodec::new(reader), subscribers.clone()));
+
+        AsyncBcConnection {
+            writer: tokio::sync::Mutex::new(BcCodec::new(writer)),
+            subscribers,
+            next_msg_num: AtomicU16::new(0),
+            rx_task,
+        }
+    }
+
+    /// Subscribes to the replies to what is sent with the subscription, under a message number
+    /// no other subscription is using
+    pub fn subscribe(&self, msg_id: u32) -> Result<AsyncBcSubscription<'_>> {
+        let (tx, rx) = unbounded_channel();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let msg_num = insert_numbered(&mut subscribers, &self.next_msg_num, msg_id, tx);
+        Ok(AsyncBcSubscription {
+            rx,
+            conn: self,
+            msg_id,
+            msg_num: Some(msg_num),
+        })
+    }
+
+    /// Subscribes to all messages with this ID that aren't replies to another subscription.
+    /// There can be only one such subscriber per ID at a time.
+    pub fn subscribe_all(&self, msg_id: u32) -> Result<AsyncBcSubscription<'_>> {
+        let (tx, rx) = unbounded_channel();
+        match self.subscribers.lock().unwrap().entry((msg_id, None)) {
+            Entry::Vacant(vac_entry) => vac_entry.insert(tx),
+            Entry::Occupied(_) => return Err(Error::SimultaneousSubscription { msg_id }),
+        };
+        Ok(AsyncBcSubscription {
+            rx,
+            conn: self,
+            msg_id,
+            msg_num: None,
+        })
+    }
+
+    /// Hangs up on every subscriber to `msg_id`, ending their receivers
+    pub fn hang_up(&self, msg_id: u32) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|&(id, _), _| id != msg_id);
+    }
+
+    /// Hangs up on the subscriber to `msg_id` with this message number, if there is one
+    pub fn hang_up_on(&self, msg_id: u32, msg_num: u16) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .remove(&(msg_id, Some(msg_num)));
+    }
+}
+
+/// Routes each incoming message to its subscriber until the connection hangs up
+async fn receive(mut reader: BcCodec<OwnedReadHalf>, subscribers: Arc<Mutex<AsyncSubscribers>>) {
+    while let Some(received) = reader.next().await {
+        let response = match received {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Deserialization error: {:?}", e);
+                break;
+            }
+        };
+        let msg_id = response.meta.msg_id;
+        let msg_num = response.meta.msg_num;
+
+        let mut locked_subs = subscribers.lock().unwrap();
+        match route(&locked_subs, msg_id, msg_num) {
+            Some(key) => {
+                if locked_subs[&key].send(response).is_err() {
+                    warn!("Subscriber to ID {} dropped their channel", msg_id);
+                    locked_subs.remove(&key);
+                }
+            }
+            None => {
+                debug!("Ignoring uninteresting message ID {} ({})", msg_id, msg_num);
+                trace!("Contents: {:?}", response);
+            }
+        }
+    }
+
+    // If the connection hangs up, hang up on all subscribers
+    subscribers.lock().unwrap().clear();
+}
+
+impl Drop for AsyncBcConnection {
+    fn drop(&mut self) {
+        debug!("Shutting down AsyncBcConnection...");
+        // Dropping the read half along with the task, and the write half along with us, closes
+        // the socket
+        self.rx_task.abort();
+    }
+}
+
+impl<'a> AsyncBcSubscription<'a> {
+    /// The number this subscription's messages are sent with, unless it takes all messages with
+    /// its ID
+    pub fn msg_num(&self) -> Option<u16> {
+        self.msg_num
+    }
+
+    /// Sends a message, numbered so that the camera's replies come back to this subscription
+    pub async fn send(&self, mut bc: Bc) -> Result<()> {
+        assert!(bc.meta.msg_id == self.msg_id);
+        if let Some(msg_num) = self.msg_num {
+            bc.meta.msg_num = msg_num;
+        }
+
+        self.conn.writer.lock().await.send(bc).await
+    }
+}
+
+impl<'a> Drop for AsyncBcSubscription<'a> {
+    fn drop(&mut self) {
+        self.conn
+            .subscribers
+            .lock()
+            .unwrap()
+            .remove(&(self.msg_id, self.msg_num));
+    }
+}
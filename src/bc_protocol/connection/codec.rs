@@ -0,0 +1,170 @@
+//! Reads and writes Baichuan messages over an async byte stream, such as a tokio `TcpStream`
+use super::{Error, Result};
+use crate::bc::de::BcDecoder;
+use crate::bc::model::*;
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How much to ask the stream for at a time
+const READ_SIZE: usize = 64 * 1024;
+/// Outgoing messages are buffered until flushed, but once this much is waiting the sink flushes
+/// before taking any more
+const WRITE_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// A `Stream` of the messages arriving on `T` and a `Sink` for messages to send on it.  The
+/// `Stream` only needs `T` to be readable and the `Sink` only needs it to be writable, so each half
+/// of a split connection can have a codec of its own.
+pub struct BcCodec<T> {
+    io: T,
+    decoder: BcDecoder,
+    write_buf: Vec<u8>,
+}
+
+impl<T> BcCodec<T> {
+    pub fn new(io: T) -> BcCodec<T> {
+        BcCodec {
+            io,
+            decoder: BcDecoder::new(),
+            write_buf: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Writing directly to the stream skips any messages still waiting to be flushed
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Gives back the stream, dropping anything read but not yet parsed or written but not yet
+    /// flushed
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for BcCodec<T> {
+    type Item = Result<Bc>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bc>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(bc) = this.decoder.decode()? {
+                return Poll::Ready(Some(Ok(bc)));
+            }
+
+            let buf = this.decoder.buffer_mut();
+            let start = buf.len();
+            buf.resize(start + READ_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut buf[start..]);
+            let polled = Pin::new(&mut this.io).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            buf.truncate(start + read);
+
+            match polled {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                // A clean hang up comes between messages
+                Poll::Ready(Ok(())) if read == 0 && start == 0 => return Poll::Ready(None),
+                Poll::Ready(Ok(())) if read == 0 => {
+                    return Poll::Ready(Some(Err(
+                        std::io::Error::from(ErrorKind::UnexpectedEof).into()
+                    )))
+                }
+                Poll::Ready(Ok(())) => {}
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Sink<Bc> for BcCodec<T> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.write_buf.len() >= WRITE_BUFFER_LIMIT {
+            self.poll_flush(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bc) -> Result<()> {
+        item.serialize(&mut self.get_mut().write_buf)?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while !this.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut this.io).poll_write(cx, &this.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::Error::from(ErrorKind::WriteZero).into()));
+            }
+            this.write_buf.drain(..written);
+        }
+        Pin::new(&mut this.io).poll_flush(cx).map_err(Error::from)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().io)
+            .poll_shutdown(cx)
+            .map_err(Error::from)
+    }
+}
+
+#[tokio::test]
+async fn test_codec() {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+
+    let video_start = &include_bytes!("../../bc/samples/modern_video_start1.bin")[..];
+    let video_data = &include_bytes!("../../bc/samples/modern_video_start2.bin")[..];
+
+    // The small buffer makes the messages arrive a piece at a time
+    let (ours, mut theirs) = tokio::io::duplex(1024);
+    let camera = tokio::spawn(async move {
+        theirs.write_all(video_start).await.unwrap();
+        theirs.write_all(video_data).await.unwrap();
+        theirs
+    });
+
+    let mut codec = BcCodec::new(ours);
+    for expected_len in &[32, 30344] {
+        match codec.next().await.unwrap().unwrap().body {
+            BcBody::ModernMsg(ModernMsg {
+                binary: Some(binary),
+                ..
+            }) => assert_eq!(binary.len(), *expected_len),
+            body => panic!("Expected binary, got {:?}", body),
+        }
+    }
+
+    // What is sent comes out of the other end as it went in
+    let theirs = camera.await.unwrap();
+    let ping = || Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_PING,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 7,
+            encrypted: true,
+            class: 0x6414,
+        },
+        body: BcBody::ModernMsg(ModernMsg::default()),
+    };
+    codec.send(ping()).await.unwrap();
+    let mut camera = BcCodec::new(theirs);
+    assert_eq!(camera.next().await.unwrap().unwrap(), ping());
+
+    // Hanging up between messages ends the stream
+    drop(camera);
+    assert!(codec.next().await.is_none());
+}
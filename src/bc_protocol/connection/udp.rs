@@ -0,0 +1,533 @@
+//! Baichuan over UDP, for cameras that don't take TCP connections.  The byte stream of messages is
+//! cut into numbered data packets, which each end acknowledges, resending any the other end
+//! hasn't acknowledged in time and putting what arrives back in order.
+use super::BcTransport;
+use crate::bc_protocol::discovery::DISCOVERY_PORTS;
+use crate::bcudp::model::*;
+use crate::bcudp::xml::*;
+use log::*;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The largest datagram we send or ask to receive
+const MTU: u32 = 1350;
+/// Data packets not acknowledged after this long are sent again
+const RESEND_INTERVAL: Duration = Duration::from_millis(500);
+/// We acknowledge at least this often, even with nothing new, so the camera knows we're still here
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the socket waits for a packet before seeing to resends and acknowledgements
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Out of order packets are only held onto this far ahead of the next one we need
+const MAX_EARLY_PACKETS: u32 = 4096;
+
+pub struct UdpTransport {
+    shared: Arc<Shared>,
+    reader: Mutex<Option<Receiver<Vec<u8>>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// What the transport and its thread both use
+struct Shared {
+    socket: UdpSocket,
+    /// Our ID for the connection
+    cid: i32,
+    /// The camera's ID for the connection
+    did: i32,
+    outgoing: Mutex<Outgoing>,
+    stopped: AtomicBool,
+}
+
+impl UdpTransport {
+    /// Asks the camera at `ip` to take a connection, then starts the thread that receives data
+    /// packets, acknowledges them and resends ours
+    pub fn connect(ip: IpAddr, uid: &str, timeout: Duration) -> Result<UdpTransport> {
+        let targets: Vec<SocketAddr> = DISCOVERY_PORTS
+            .iter()
+            .map(|&port| SocketAddr::new(ip, port))
+            .collect();
+        UdpTransport::connect_to(&targets, uid, timeout)
+    }
+
+    fn connect_to(targets: &[SocketAddr], uid: &str, timeout: Duration) -> Result<UdpTransport> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let (cid, did) = handshake(&socket, targets, uid, timeout)?;
+        debug!("UDP connection to {} is {}/{}", uid, cid, did);
+
+        let shared = Arc::new(Shared {
+            socket,
+            cid,
+            did,
+            outgoing: Mutex::new(Outgoing::default()),
+            stopped: AtomicBool::new(false),
+        });
+        let (tx, rx) = channel();
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || thread_shared.run(tx, timeout));
+
+        Ok(UdpTransport {
+            shared,
+            reader: Mutex::new(Some(rx)),
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+}
+
+impl BcTransport for UdpTransport {
+    fn send(&self, buf: &[u8]) -> Result<()> {
+        let max_payload = MTU as usize - UDP_DATA_HEADER_LEN;
+        let mut outgoing = self.shared.outgoing.lock().unwrap();
+        for chunk in buf.chunks(max_payload) {
+            let packet = BcUdp::Data(UdpData {
+                connection_id: self.shared.did,
+                packet_id: outgoing.next_id,
+                payload: chunk.to_vec(),
+            })
+            .serialize(vec![])
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+            self.shared.socket.send(&packet)?;
+            outgoing.push(packet, Instant::now());
+        }
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<Box<dyn Read + Send>> {
+        let rx = self.reader.lock().unwrap().take().ok_or_else(|| {
+            Error::new(
+                ErrorKind::AlreadyExists,
+                "The reader has already been taken",
+            )
+        })?;
+        Ok(Box::new(UdpReader {
+            rx,
+            buf: vec![],
+            pos: 0,
+        }))
+    }
+
+    fn shutdown(&self) {
+        if self.shared.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let goodbye = BcUdp::Discovery(UdpDiscovery {
+            tid: new_id(),
+            payload: UdpXml {
+                c2d_disc: Some(C2dDisc {
+                    cid: self.shared.cid,
+                    did: self.shared.did,
+                }),
+                ..Default::default()
+            },
+        });
+        if let Ok(packet) = goodbye.serialize(vec![]) {
+            let _ = self.shared.socket.send(&packet);
+        }
+    }
+
+    fn udp_port(&self) -> Option<u16> {
+        self.shared.socket.local_addr().ok().map(|addr| addr.port())
+    }
+}
+
+impl Drop for UdpTransport {
+    fn drop(&mut self) {
+        self.shutdown();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Asks for a connection until the camera answers, returning our ID for it and the camera's.
+/// Afterwards the socket only talks to the address the answer came from.
+fn handshake(
+    socket: &UdpSocket,
+    targets: &[SocketAddr],
+    uid: &str,
+    timeout: Duration,
+) -> Result<(i32, i32)> {
+    let cid = new_id() as i32 & i32::MAX;
+    let request = BcUdp::Discovery(UdpDiscovery {
+        tid: new_id(),
+        payload: UdpXml {
+            c2d_c: Some(C2dC {
+                uid: uid.to_string(),
+                cli: PortList {
+                    port: socket.local_addr()?.port(),
+                },
+                cid,
+                mtu: MTU,
+                debug: false,
+                os: "MAC".to_string(),
+            }),
+            ..Default::default()
+        },
+    })
+    .serialize(vec![])
+    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut next_send = Instant::now();
+    let mut buf = vec![0; 65536];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} did not take a UDP connection", uid),
+            ));
+        }
+        if now >= next_send {
+            for target in targets {
+                if let Err(e) = socket.send_to(&request, target) {
+                    debug!("Could not ask {} for a connection: {}", target, e);
+                }
+            }
+            next_send = now + RESEND_INTERVAL;
+        }
+
+        socket.set_read_timeout(Some(next_send.min(deadline) - now))?;
+        let (len, sender) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        if let Ok(BcUdp::Discovery(UdpDiscovery {
+            payload: UdpXml {
+                d2c_c_r: Some(reply),
+                ..
+            },
+            ..
+        })) = BcUdp::deserialize(&buf[..len])
+        {
+            if reply.cid != cid {
+                continue;
+            }
+            if reply.rsp != 0 {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("{} refused a UDP connection ({})", uid, reply.rsp),
+                ));
+            }
+            socket.connect(sender)?;
+            return Ok((cid, reply.did));
+        }
+    }
+}
+
+impl Shared {
+    /// Receives packets until the transport is shut down or the camera goes quiet for `timeout`.
+    /// The stream is sent to the reader in order; dropping `tx` on the way out ends it.
+    fn run(&self, tx: Sender<Vec<u8>>, timeout: Duration) {
+        if let Err(e) = self.socket.set_read_timeout(Some(POLL_INTERVAL)) {
+            error!("Could not set up UDP connection: {}", e);
+            return;
+        }
+        let mut incoming = Incoming::default();
+        let mut buf = vec![0; 65536];
+        let mut last_heard = Instant::now();
+        let mut last_ack = Instant::now();
+        let mut ack_due = false;
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            match self.socket.recv(&mut buf) {
+                Ok(len) => match BcUdp::deserialize(&buf[..len]) {
+                    Ok(BcUdp::Data(data)) if data.connection_id == self.cid => {
+                        last_heard = Instant::now();
+                        ack_due = true;
+                        for payload in incoming.receive(data.packet_id, data.payload) {
+                            if tx.send(payload).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Ok(BcUdp::Ack(ack)) if ack.connection_id == self.cid => {
+                        last_heard = Instant::now();
+                        self.outgoing.lock().unwrap().acknowledge(&ack);
+                    }
+                    Ok(other) => trace!("Ignoring UDP message: {:?}", other),
+                    Err(e) => debug!("Ignoring damaged UDP message: {}", e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                // The camera wasn't listening for a moment, perhaps while rebooting; if it stays
+                // away, the connection times out
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    debug!("UDP packet refused: {}", e)
+                }
+                Err(e) => {
+                    error!("UDP connection failed: {}", e);
+                    return;
+                }
+            }
+
+            let now = Instant::now();
+            if now - last_heard > timeout {
+                warn!("UDP connection timed out");
+                return;
+            }
+            if ack_due || now - last_ack >= ACK_INTERVAL {
+                if let Ok(ack) = BcUdp::Ack(incoming.ack(self.did)).serialize(vec![]) {
+                    let _ = self.socket.send(&ack);
+                }
+                ack_due = false;
+                last_ack = now;
+            }
+            for packet in self.outgoing.lock().unwrap().due(now) {
+                let _ = self.socket.send(&packet);
+            }
+        }
+    }
+}
+
+/// The packets we've sent that haven't been acknowledged yet
+#[derive(Default)]
+struct Outgoing {
+    next_id: u32,
+    unacked: BTreeMap<u32, (Vec<u8>, Instant)>,
+}
+
+impl Outgoing {
+    fn push(&mut self, packet: Vec<u8>, sent: Instant) {
+        self.unacked.insert(self.next_id, (packet, sent));
+        self.next_id = self.next_id.wrapping_add(1);
+    }
+
+    fn acknowledge(&mut self, ack: &UdpAck) {
+        self.unacked
+            .retain(|&id, _| !seq_at_or_before(id, ack.packet_id));
+        for (i, &received) in ack.received.iter().enumerate() {
+            if received {
+                let id = ack.packet_id.wrapping_add(1).wrapping_add(i as u32);
+                self.unacked.remove(&id);
+            }
+        }
+    }
+
+    /// The packets to send again, which are then counted as sent at `now`
+    fn due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        self.unacked
+            .values_mut()
+            .filter(|(_, sent)| now - *sent >= RESEND_INTERVAL)
+            .map(|(packet, sent)| {
+                *sent = now;
+                packet.clone()
+            })
+            .collect()
+    }
+}
+
+/// Puts the packets we receive back in order
+#[derive(Default)]
+struct Incoming {
+    /// The first packet we haven't had yet
+    next_id: u32,
+    /// Packets that arrived before some of those ahead of them
+    early: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Incoming {
+    /// Takes in a packet, returning the payloads that are now next in the stream, in order
+    fn receive(&mut self, packet_id: u32, payload: Vec<u8>) -> Vec<Vec<u8>> {
+        let ahead = packet_id.wrapping_sub(self.next_id);
+        if ahead >= MAX_EARLY_PACKETS {
+            // Already had it, and our acknowledgement must have been lost; or absurdly far ahead
+            return vec![];
+        }
+        self.early.insert(packet_id, payload);
+
+        let mut ready = vec![];
+        while let Some(payload) = self.early.remove(&self.next_id) {
+            ready.push(payload);
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        ready
+    }
+
+    fn ack(&self, connection_id: i32) -> UdpAck {
+        let last = self
+            .early
+            .keys()
+            .map(|id| id.wrapping_sub(self.next_id))
+            .max();
+        UdpAck {
+            connection_id,
+            packet_id: self.next_id.wrapping_sub(1),
+            received: match last {
+                Some(last) => (0..=last)
+                    .map(|i| self.early.contains_key(&self.next_id.wrapping_add(i)))
+                    .collect(),
+                None => vec![],
+            },
+        }
+    }
+}
+
+/// Whether packet `a` is `b` or came before it, allowing for the IDs wrapping around
+fn seq_at_or_before(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) as i32 >= 0
+}
+
+/// Reads the stream the receiving thread puts back together
+struct UdpReader {
+    rx: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for UdpReader {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(buf) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                // The connection is over
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn new_id() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.subsec_nanos())
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_reassembly() {
+    let mut incoming = Incoming::default();
+    assert_eq!(incoming.receive(0, vec![0]), vec![vec![0]]);
+    // 1 and 3 are lost or late
+    assert!(incoming.receive(2, vec![2]).is_empty());
+    assert!(incoming.receive(4, vec![4]).is_empty());
+    let ack = incoming.ack(99);
+    assert_eq!(ack.connection_id, 99);
+    assert_eq!(ack.packet_id, 0);
+    assert_eq!(ack.received, vec![false, true, false, true]);
+
+    assert_eq!(incoming.receive(1, vec![1]), vec![vec![1], vec![2]]);
+    // A resend of one we already have
+    assert!(incoming.receive(2, vec![2]).is_empty());
+    assert_eq!(incoming.receive(3, vec![3]), vec![vec![3], vec![4]]);
+    let ack = incoming.ack(99);
+    assert_eq!(ack.packet_id, 4);
+    assert!(ack.received.is_empty());
+
+    // Nothing received yet is acknowledged as the packet before the first
+    assert_eq!(Incoming::default().ack(0).packet_id, u32::MAX);
+}
+
+#[test]
+fn test_resend() {
+    let start = Instant::now();
+    let mut outgoing = Outgoing::default();
+    for id in 0..4u8 {
+        outgoing.push(vec![id], start);
+    }
+    assert!(outgoing.due(start).is_empty());
+
+    // Nothing has arrived yet
+    outgoing.acknowledge(&UdpAck {
+        connection_id: 0,
+        packet_id: u32::MAX,
+        received: vec![],
+    });
+    assert_eq!(outgoing.unacked.len(), 4);
+
+    // 0, 1 and 3 arrived, so only 2 needs sending again
+    outgoing.acknowledge(&UdpAck {
+        connection_id: 0,
+        packet_id: 1,
+        received: vec![false, true],
+    });
+    let later = start + RESEND_INTERVAL;
+    assert_eq!(outgoing.due(later), vec![vec![2]]);
+    // Not again until another interval has passed
+    assert!(outgoing.due(later).is_empty());
+    assert_eq!(outgoing.due(later + RESEND_INTERVAL), vec![vec![2]]);
+}
+
+#[test]
+fn test_connection() {
+    // Stands in for a camera, taking a connection and echoing back what is sent over it
+    let camera = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = camera.local_addr().unwrap();
+    let echo = std::thread::spawn(move || {
+        let mut buf = vec![0; 65536];
+        let (len, client) = camera.recv_from(&mut buf).unwrap();
+        let (tid, request) = match BcUdp::deserialize(&buf[..len]).unwrap() {
+            BcUdp::Discovery(UdpDiscovery {
+                tid,
+                payload:
+                    UdpXml {
+                        c2d_c: Some(request),
+                        ..
+                    },
+            }) => (tid, request),
+            other => panic!("Expected a connection request, got {:?}", other),
+        };
+        assert_eq!(request.uid, "95270000ABCDEFGH");
+        let reply = BcUdp::Discovery(UdpDiscovery {
+            tid,
+            payload: UdpXml {
+                d2c_c_r: Some(D2cCR {
+                    rsp: 0,
+                    cid: request.cid,
+                    did: 77,
+                }),
+                ..Default::default()
+            },
+        });
+        camera
+            .send_to(&reply.serialize(vec![]).unwrap(), client)
+            .unwrap();
+
+        // Drop the first data packet, so it has to be resent
+        let mut dropped = false;
+        let mut echoed = 0;
+        while echoed < 2 {
+            let len = camera.recv(&mut buf).unwrap();
+            if let BcUdp::Data(data) = BcUdp::deserialize(&buf[..len]).unwrap() {
+                assert_eq!(data.connection_id, 77);
+                if !dropped {
+                    dropped = true;
+                    continue;
+                }
+                let echo = BcUdp::Data(UdpData {
+                    connection_id: request.cid,
+                    packet_id: data.packet_id,
+                    payload: data.payload,
+                });
+                camera
+                    .send_to(&echo.serialize(vec![]).unwrap(), client)
+                    .unwrap();
+                echoed += 1;
+            }
+        }
+    });
+
+    let transport =
+        UdpTransport::connect_to(&[target], "95270000ABCDEFGH", Duration::from_secs(5)).unwrap();
+    let mut reader = transport.reader().unwrap();
+    // Big enough to span two packets
+    let message: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+    transport.send(&message).unwrap();
+    let mut received = vec![0; message.len()];
+    reader.read_exact(&mut received).unwrap();
+    assert_eq!(received, message);
+    echo.join().unwrap();
+}
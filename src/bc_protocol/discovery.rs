@@ -0,0 +1,177 @@
+//! Finds cameras on the LAN by broadcasting a search, which each camera answers with its UID and
+//! the port it takes Baichuan connections on
+use super::{Error, Result};
+use crate::bcudp::model::*;
+use crate::bcudp::xml::*;
+use log::*;
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Cameras listen for searches on both of these ports
+pub(crate) const DISCOVERY_PORTS: [u16; 2] = [2015, 2018];
+/// Searches go out again this often, in case one was lost
+const RESEND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A camera that answered a search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredCamera {
+    /// Where to connect to the camera
+    pub address: SocketAddr,
+    pub uid: String,
+    pub model: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Searches the LAN for cameras, returning all those that answer within `timeout`
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredCamera>> {
+    search(&broadcast_targets(), timeout, |_| false)
+}
+
+/// Searches the LAN for the camera with this UID
+pub fn find_camera_by_uid(uid: &str, timeout: Duration) -> Result<DiscoveredCamera> {
+    let found = search(&broadcast_targets(), timeout, |camera| {
+        camera.uid.eq_ignore_ascii_case(uid)
+    })?;
+    found
+        .into_iter()
+        .find(|camera| camera.uid.eq_ignore_ascii_case(uid))
+        .ok_or(Error::AddrResolutionError)
+}
+
+fn broadcast_targets() -> Vec<SocketAddr> {
+    DISCOVERY_PORTS
+        .iter()
+        .map(|&port| SocketAddr::from((Ipv4Addr::BROADCAST, port)))
+        .collect()
+}
+
+/// Sends searches to `targets` until `timeout` has passed or `done` is true of a camera
+fn search(
+    targets: &[SocketAddr],
+    timeout: Duration,
+    mut done: impl FnMut(&DiscoveredCamera) -> bool,
+) -> Result<Vec<DiscoveredCamera>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let port = socket.local_addr()?.port();
+    let tid = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.subsec_nanos())
+        .unwrap_or(0);
+    let probe = BcUdp::Discovery(UdpDiscovery {
+        tid,
+        payload: UdpXml {
+            c2d_s: Some(C2dS {
+                to: PortList { port },
+            }),
+            ..Default::default()
+        },
+    })
+    .serialize(vec![])?;
+
+    let mut found = vec![];
+    let mut seen = HashSet::new();
+    let deadline = Instant::now() + timeout;
+    let mut next_send = Instant::now();
+    let mut buf = vec![0; 65536];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(found);
+        }
+        if now >= next_send {
+            for target in targets {
+                if let Err(e) = socket.send_to(&probe, target) {
+                    debug!("Could not send search to {}: {}", target, e);
+                }
+            }
+            next_send = now + RESEND_INTERVAL;
+        }
+
+        socket.set_read_timeout(Some(next_send.min(deadline) - now))?;
+        let (len, sender) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let camera = match BcUdp::deserialize(&buf[..len]) {
+            Ok(BcUdp::Discovery(UdpDiscovery {
+                payload: UdpXml {
+                    d2c_s: Some(reply), ..
+                },
+                ..
+            })) => DiscoveredCamera {
+                address: SocketAddr::new(sender.ip(), reply.port),
+                uid: reply.uid,
+                model: reply.model,
+                name: reply.name,
+            },
+            Ok(_) => continue,
+            Err(e) => {
+                debug!("Ignoring reply from {}: {}", sender, e);
+                continue;
+            }
+        };
+        if seen.insert(camera.uid.clone()) {
+            debug!("Found camera {} at {}", camera.uid, camera.address);
+            let finished = done(&camera);
+            found.push(camera);
+            if finished {
+                return Ok(found);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_search() {
+    // Stands in for a camera, answering searches the way one does
+    let camera = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = camera.local_addr().unwrap();
+    let answer = std::thread::spawn(move || {
+        let mut buf = vec![0; 65536];
+        // Answer twice, as if the search had been sent to both ports
+        for _ in 0..2 {
+            let (len, sender) = camera.recv_from(&mut buf).unwrap();
+            let probe = match BcUdp::deserialize(&buf[..len]).unwrap() {
+                BcUdp::Discovery(probe) => probe,
+                other => panic!("Expected a search, got {:?}", other),
+            };
+            let reply_to = SocketAddr::new(sender.ip(), probe.payload.c2d_s.unwrap().to.port);
+            let reply = BcUdp::Discovery(UdpDiscovery {
+                tid: probe.tid,
+                payload: UdpXml {
+                    d2c_s: Some(D2cS {
+                        uid: "95270000ABCDEFGH".to_string(),
+                        port: 9000,
+                        model: Some("E1 Zoom".to_string()),
+                        name: Some("Driveway".to_string()),
+                    }),
+                    ..Default::default()
+                },
+            });
+            camera
+                .send_to(&reply.serialize(vec![]).unwrap(), reply_to)
+                .unwrap();
+        }
+    });
+
+    // The same camera answering twice is only listed once
+    let found = search(&[target, target], Duration::from_millis(500), |_| false).unwrap();
+    answer.join().unwrap();
+    assert_eq!(
+        found,
+        vec![DiscoveredCamera {
+            address: "127.0.0.1:9000".parse().unwrap(),
+            uid: "95270000ABCDEFGH".to_string(),
+            model: Some("E1 Zoom".to_string()),
+            name: Some("Driveway".to_string()),
+        }]
+    );
+}
@@ -2,10 +2,13 @@ use super::{Error, Result, RX_TIMEOUT};
 use crate::bc::model::*;
 use crate::bc_protocol::connection::BcSubscription;
 use crate::gst::StreamFormat;
+use bytes::{Bytes, BytesMut};
 use log::trace;
 use log::*;
 use std::collections::VecDeque;
 use std::convert::TryInto;
+use std::time::Instant;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time};
 
 const INVALID_MEDIA_PACKETS: &[MediaDataKind] = &[MediaDataKind::Unknown];
 
@@ -27,7 +30,7 @@ pub enum MediaDataKind {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct MediaData {
-    data: Vec<u8>,
+    data: Bytes,
 }
 
 impl MediaData {
@@ -37,6 +40,13 @@ impl MediaData {
         &self.data[lower_limit..upper_limit]
     }
 
+    /// The body, sharing the packet's memory rather than copying it
+    pub fn body_bytes(&self) -> Bytes {
+        let lower_limit = self.header_size();
+        let upper_limit = self.data_size() + lower_limit;
+        self.data.slice(lower_limit..upper_limit)
+    }
+
     fn header_size_from_kind(kind: MediaDataKind) -> usize {
         match kind {
             MediaDataKind::VideoDataIframe => 32,
@@ -185,119 +195,478 @@ impl MediaData {
         }
     }
 
-    pub fn timestamp(&self) -> Option<u64> {
-        let kind = self.kind();
-        match kind {
-            MediaDataKind::VideoDataIframe | MediaDataKind::VideoDataPframe => Some(
-                Self::bytes_to_size(&self.data[16..20])
+    /// The camera's media clock in microseconds, which is carried by video frames only.  It wraps
+    /// around every 2^32 microseconds (about 71 minutes).
+    pub fn microseconds(&self) -> Option<u32> {
+        match self.kind() {
+            MediaDataKind::VideoDataIframe | MediaDataKind::VideoDataPframe => {
+                Some(u32::from_le_bytes(
+                    self.data[16..20]
+                        .try_into()
+                        .expect("slice with incorrect length"),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// The camera's wall clock as a POSIX time, which is carried by I frames only
+    pub fn posix_time(&self) -> Option<u32> {
+        match self.kind() {
+            MediaDataKind::VideoDataIframe => Some(u32::from_le_bytes(
+                self.data[24..28]
                     .try_into()
-                    .expect("usize wont fit into u64"),
-            ),
+                    .expect("slice with incorrect length"),
+            )),
             _ => None,
         }
     }
+
+    /// Decodes an Info V1/V2 packet
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        if self.kind() != MediaDataKind::InfoData {
+            return None;
+        }
+        let header = self.header();
+        let u32_at = |i: usize| {
+            u32::from_le_bytes(
+                header[i..i + 4]
+                    .try_into()
+                    .expect("slice with incorrect length"),
+            )
+        };
+        Some(StreamInfo {
+            width: u32_at(8),
+            height: u32_at(12),
+            fps: header[17],
+            start_time: utc_from_bytes(&header[18..24]),
+            end_time: utc_from_bytes(&header[24..30]),
+        })
+    }
+
+    /// Wraps one block of ADPCM audio (as made by `pcm_to_adpcm`) in a media packet, as the
+    /// camera expects to receive it for talk
+    pub fn from_adpcm_block(block: &[u8]) -> MediaData {
+        const MAGIC_ADPCM: &[u8] = &[0x30, 0x31, 0x77, 0x62];
+        let size = (block.len() as u16).to_le_bytes();
+
+        let mut data = Vec::with_capacity(block.len() + 16);
+        data.extend_from_slice(MAGIC_ADPCM);
+        data.extend_from_slice(&size);
+        data.extend_from_slice(&size);
+        data.extend_from_slice(block);
+        let pad_size = MediaData::pad_size_from_raw(&data);
+        data.resize(data.len() + pad_size, 0);
+        MediaData { data: data.into() }
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.data
+    }
 }
 
-pub struct MediaDataSubscriber<'a> {
-    binary_buffer: VecDeque<u8>,
-    bc_sub: &'a BcSubscription<'a>,
+/// Describes a stream, as sent by the camera in an Info V1/V2 packet ahead of the first frame
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StreamInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Reolink cameras send the frame rate, but some other brands send the index of their frame
+    /// rate setting instead
+    pub fps: u8,
+    pub start_time: Option<OffsetDateTime>,
+    pub end_time: Option<OffsetDateTime>,
 }
 
-impl<'a> MediaDataSubscriber<'a> {
-    pub fn from_bc_sub<'b>(bc_sub: &'b BcSubscription) -> MediaDataSubscriber<'b> {
-        MediaDataSubscriber {
-            binary_buffer: VecDeque::new(),
-            bc_sub,
+/// Reads a UTC time stored as years since 1900, month, day, hour, minute and second bytes.  Live
+/// streams leave these zeroed, which gives None.
+fn utc_from_bytes(bytes: &[u8]) -> Option<OffsetDateTime> {
+    let date = Date::try_from_ymd(1900 + bytes[0] as i32, bytes[1], bytes[2]).ok()?;
+    let time = Time::try_from_hms(bytes[3], bytes[4], bytes[5]).ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+/// Returns how long the ADTS frames of an AAC packet play for in microseconds, or None if the
+/// packet isn't made of whole ADTS frames
+pub fn aac_duration(mut adts: &[u8]) -> Option<u64> {
+    const SAMPLE_RATES: [u64; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+    let mut duration = 0;
+    while !adts.is_empty() {
+        if adts.len() < 7 || adts[0] != 0xFF || adts[1] & 0xF0 != 0xF0 {
+            return None;
+        }
+        let sample_rate = *SAMPLE_RATES.get((adts[2] as usize >> 2) & 0x0F)?;
+        let frame_len =
+            ((adts[3] as usize & 0x03) << 11) | ((adts[4] as usize) << 3) | (adts[5] as usize >> 5);
+        if frame_len < 7 || frame_len > adts.len() {
+            return None;
         }
+        // Each raw data block is 1024 samples
+        let blocks = (adts[6] & 0x03) as u64 + 1;
+        duration += blocks * 1024 * 1_000_000 / sample_rate;
+        adts = &adts[frame_len..];
     }
+    Some(duration)
+}
 
-    fn fill_binary_buffer(&mut self) -> Result<()> {
-        // Loop messages until we get binary add that data and return
-        loop {
-            let msg = self.bc_sub.rx.recv_timeout(RX_TIMEOUT)?;
-            if let BcBody::ModernMsg(ModernMsg {
-                binary: Some(binary),
-                ..
-            }) = msg.body
+/// Turns the camera's wrapping media clock into a continuous time in microseconds since the
+/// stream started, which is what timestamps the buffers we hand to GStreamer.
+pub struct StreamClock {
+    last_camera_time: Option<u32>,
+    last_arrival: Option<Instant>,
+    stream_time: u64,
+    /// Where the audio packet after the last one starts, if we know how long the last one was
+    next_audio_time: Option<u64>,
+}
+
+// Any jump bigger than this is not a real gap between frames, so the camera's clock must have been
+// reset
+const MAX_CLOCK_JUMP: u32 = 10_000_000;
+
+// Audio this far from when it arrived has lost packets or stalled, so it starts again from its
+// arrival
+const MAX_AUDIO_DRIFT: u64 = 1_000_000;
+
+impl StreamClock {
+    pub fn new() -> StreamClock {
+        StreamClock {
+            last_camera_time: None,
+            last_arrival: None,
+            stream_time: 0,
+            next_audio_time: None,
+        }
+    }
+
+    /// Returns the stream time of a video frame stamped with `camera_time` by the camera
+    pub fn video_time(&mut self, camera_time: u32) -> u64 {
+        self.video_time_at(camera_time, Instant::now())
+    }
+
+    fn video_time_at(&mut self, camera_time: u32, now: Instant) -> u64 {
+        if let (Some(last_camera_time), Some(last_arrival)) =
+            (self.last_camera_time, self.last_arrival)
+        {
+            // Wrapping subtraction takes care of the clock wrapping around
+            let forward = camera_time.wrapping_sub(last_camera_time);
+            let backward = last_camera_time.wrapping_sub(camera_time);
+            if forward <= MAX_CLOCK_JUMP {
+                self.stream_time += forward as u64;
+            } else if backward <= MAX_CLOCK_JUMP {
+                // Slightly out of order; place it in the past without winding the clock back
+                return self.stream_time.saturating_sub(backward as u64);
+            } else {
+                // We can't know how much time really passed, so go by when it arrived
+                warn!("Camera media clock jumped; carrying on from the previous frame");
+                self.stream_time += (now - last_arrival).as_micros() as u64;
+            }
+        }
+        self.last_camera_time = Some(camera_time);
+        self.last_arrival = Some(now);
+        self.stream_time
+    }
+
+    /// Returns the stream time of an audio packet that plays for `duration` microseconds.  Audio
+    /// packets are not stamped by the camera, but they arrive in bursts, so each one follows on
+    /// from the one before.  The first is placed after the latest video frame by how much later it
+    /// arrived, as is any packet whose length we don't know.
+    pub fn audio_time(&mut self, duration: Option<u64>) -> u64 {
+        self.audio_time_at(duration, Instant::now())
+    }
+
+    fn audio_time_at(&mut self, duration: Option<u64>, now: Instant) -> u64 {
+        let last_arrival = *self.last_arrival.get_or_insert(now);
+        let arrival_time = self.stream_time + (now - last_arrival).as_micros() as u64;
+        let audio_time = match (duration, self.next_audio_time) {
+            (Some(_), Some(next))
+                if next.max(arrival_time) - next.min(arrival_time) <= MAX_AUDIO_DRIFT =>
             {
-                // Add the new binary to the buffer and return
-                self.binary_buffer.extend(binary);
-                break;
+                next
             }
+            _ => arrival_time,
+        };
+        self.next_audio_time = duration.map(|duration| audio_time + duration);
+        audio_time
+    }
+}
+
+impl Default for StreamClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cuts the binary a stream arrives in, which need not line up with the packets, into media
+/// packets
+#[derive(Default)]
+pub struct MediaDataBuffer {
+    /// The binary not yet cut into packets, in the pieces it arrived in
+    chunks: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl MediaDataBuffer {
+    pub fn new() -> MediaDataBuffer {
+        Default::default()
+    }
+
+    /// Adds binary as it arrives from the camera
+    pub fn extend(&mut self, binary: Bytes) {
+        if !binary.is_empty() {
+            self.len += binary.len();
+            self.chunks.push_back(binary);
         }
-        Ok(())
     }
 
-    fn advance_to_media_packet(&mut self) -> Result<()> {
+    fn advance_to_media_packet(&mut self) -> bool {
         // In the event we get an unknown packet we advance by brute force
         // reading of bytes to the next valid magic
-        while self.binary_buffer.len() < MAGIC_SIZE {
-            self.fill_binary_buffer()?;
+        let mut skipping = false;
+        while self.len >= MAGIC_SIZE {
+            // Check the kind, if its invalid use pop a byte and try again
+            let magic = self.peek(MAGIC_SIZE);
+            if !INVALID_MEDIA_PACKETS.contains(&MediaData::kind_from_raw(&magic)) {
+                return true;
+            }
+            if !skipping {
+                warn!("Possibly truncated packet or unknown magic in stream");
+                trace!("Unknown magic was: {:x?}", &magic);
+                skipping = true;
+            }
+            self.take(1);
         }
+        false
+    }
 
-        // Check the kind, if its invalid use pop a byte and try again
-        let mut magic = MediaDataSubscriber::get_first_n_deque(&self.binary_buffer, MAGIC_SIZE);
-        if INVALID_MEDIA_PACKETS.contains(&MediaData::kind_from_raw(&magic)) {
-            warn!("Possibly truncated packet or unknown magic in stream");
-            trace!("Unknown magic was: {:x?}", &magic);
-        }
-        while INVALID_MEDIA_PACKETS.contains(&MediaData::kind_from_raw(&magic)) {
-            self.binary_buffer.pop_front();
-            while self.binary_buffer.len() < MAGIC_SIZE {
-                self.fill_binary_buffer()?;
+    /// Copies out the first `n` bytes, which may be spread over several pieces
+    fn peek(&self, n: usize) -> Vec<u8> {
+        let mut peeked = Vec::with_capacity(n);
+        for chunk in &self.chunks {
+            let wanted = (n - peeked.len()).min(chunk.len());
+            peeked.extend_from_slice(&chunk[..wanted]);
+            if peeked.len() == n {
+                break;
             }
-            magic = MediaDataSubscriber::get_first_n_deque(&self.binary_buffer, MAGIC_SIZE);
         }
-
-        Ok(())
+        peeked
     }
 
-    fn get_first_n_deque<T: std::clone::Clone>(deque: &VecDeque<T>, n: usize) -> Vec<T> {
-        // NOTE: I want to use make_contiguous
-        // This will make this func unneeded as we can use
-        // make_contiguous then as_slices.0
-        // We won't need the clone in this case either.
-        // This is an experimental feature.
-        // It is about to be moved to stable though
-        // As can be seen from this PR
-        // https://github.com/rust-lang/rust/pull/74559
-        let slice0 = deque.as_slices().0;
-        let slice1 = deque.as_slices().1;
-        if slice0.len() >= n {
-            slice0[0..n].to_vec()
-        } else {
-            let remain = n - slice0.len();
-            slice0.iter().chain(&slice1[0..remain]).cloned().collect()
+    /// Takes the first `n` bytes.  They are only copied if they are spread over several pieces;
+    /// otherwise they are a slice of the one piece.
+    fn take(&mut self, n: usize) -> Bytes {
+        self.len -= n;
+        let first = self
+            .chunks
+            .front_mut()
+            .expect("Taking more than was buffered");
+        if first.len() > n {
+            return first.split_to(n);
+        }
+        if first.len() == n {
+            return self.chunks.pop_front().unwrap();
         }
+
+        let mut taken = BytesMut::with_capacity(n);
+        while taken.len() < n {
+            let chunk = self
+                .chunks
+                .front_mut()
+                .expect("Taking more than was buffered");
+            let part = chunk.split_to((n - taken.len()).min(chunk.len()));
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+            taken.extend_from_slice(&part);
+        }
+        taken.freeze()
     }
 
-    pub fn next_media_packet(&mut self) -> std::result::Result<MediaData, Error> {
+    /// The next media packet, or None until all of it has arrived
+    pub fn next_media_packet(&mut self) -> Option<MediaData> {
         // Find the first packet (does nothing if already at one)
-        self.advance_to_media_packet()?;
+        if !self.advance_to_media_packet() {
+            return None;
+        }
 
         // Get the magic bytes (guaranteed by advance_to_media_packet)
-        let magic = MediaDataSubscriber::get_first_n_deque(&self.binary_buffer, MAGIC_SIZE);
+        let magic = self.peek(MAGIC_SIZE);
 
         // Get enough for the full header
         let header_size = MediaData::header_size_from_raw(&magic);
-        while self.binary_buffer.len() < header_size {
-            self.fill_binary_buffer()?;
+        if self.len < header_size {
+            return None;
         }
 
         // Get enough for the full data + 8 byte buffer
-        let header = MediaDataSubscriber::get_first_n_deque(&self.binary_buffer, header_size);
+        let header = self.peek(header_size);
         let data_size = MediaData::data_size_from_raw(&header);
         let pad_size = MediaData::pad_size_from_raw(&header);
         let full_size = header_size + data_size + pad_size;
-        while self.binary_buffer.len() < full_size {
-            self.fill_binary_buffer()?;
+        if self.len < full_size {
+            return None;
         }
 
-        // Pop the full binary buffer
-        let binary = self.binary_buffer.drain(..full_size);
-
-        Ok(MediaData {
-            data: binary.collect(),
+        Some(MediaData {
+            data: self.take(full_size),
         })
     }
 }
+
+pub struct MediaDataSubscriber<'a> {
+    buffer: MediaDataBuffer,
+    bc_sub: &'a BcSubscription<'a>,
+}
+
+impl<'a> MediaDataSubscriber<'a> {
+    pub fn from_bc_sub<'b>(bc_sub: &'b BcSubscription) -> MediaDataSubscriber<'b> {
+        MediaDataSubscriber {
+            buffer: MediaDataBuffer::new(),
+            bc_sub,
+        }
+    }
+
+    fn fill_binary_buffer(&mut self) -> Result<()> {
+        // Loop messages until we get binary add that data and return
+        loop {
+            let msg = self.bc_sub.rx.recv_timeout(RX_TIMEOUT)?;
+            if let BcBody::ModernMsg(ModernMsg {
+                binary: Some(binary),
+                ..
+            }) = msg.body
+            {
+                // Add the new binary to the buffer and return
+                self.buffer.extend(binary);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_media_packet(&mut self) -> std::result::Result<MediaData, Error> {
+        loop {
+            if let Some(packet) = self.buffer.next_media_packet() {
+                return Ok(packet);
+            }
+            self.fill_binary_buffer()?;
+        }
+    }
+}
+
+#[test]
+fn test_adpcm_media_packet() {
+    let block = [0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34];
+    let packet = MediaData::from_adpcm_block(&block);
+    assert_eq!(packet.kind(), MediaDataKind::AudioDataAdpcm);
+    assert_eq!(packet.body(), &block[..]);
+    // 8 byte header, the block, then padding up to a multiple of 8
+    assert_eq!(packet.into_bytes().len(), 8 + 16);
+}
+
+#[test]
+fn test_stream_clock() {
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let mut clock = StreamClock::new();
+
+    // The first frame starts the stream, whatever the camera's clock says
+    assert_eq!(clock.video_time_at(5_000_000, ms(0)), 0);
+    assert_eq!(clock.video_time_at(5_040_000, ms(40)), 40_000);
+    // Audio goes by its arrival after the latest frame
+    assert_eq!(clock.audio_time_at(None, ms(50)), 50_000);
+    // A frame slightly out of order doesn't wind the clock back
+    assert_eq!(clock.video_time_at(5_020_000, ms(60)), 20_000);
+    assert_eq!(clock.video_time_at(5_080_000, ms(80)), 80_000);
+
+    // Wrapping around
+    let mut clock = StreamClock::new();
+    assert_eq!(clock.video_time_at(u32::MAX - 19_999, ms(0)), 0);
+    assert_eq!(clock.video_time_at(20_000, ms(40)), 40_000);
+
+    // A reset of the camera's clock falls back to the arrival time
+    assert_eq!(clock.video_time_at(1_000_000_000, ms(140)), 140_000);
+    assert_eq!(clock.video_time_at(1_000_040_000, ms(180)), 180_000);
+}
+
+#[test]
+fn test_audio_clock() {
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let mut clock = StreamClock::new();
+    assert_eq!(clock.video_time_at(5_000_000, ms(0)), 0);
+
+    // Packets of 64ms arriving in bursts still follow on from each other
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(10)), 10_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(10)), 74_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(11)), 138_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(250)), 202_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(250)), 266_000);
+
+    // After a long stall they start again from their arrival
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(2_000)), 2_000_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(2_000)), 2_064_000);
+
+    // A packet we can't measure goes by its arrival, as does the one after it
+    assert_eq!(clock.audio_time_at(None, ms(2_100)), 2_100_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(2_100)), 2_100_000);
+    assert_eq!(clock.audio_time_at(Some(64_000), ms(2_100)), 2_164_000);
+}
+
+#[test]
+fn test_aac_duration() {
+    // Two 16 kHz ADTS frames of 9 bytes, each one raw data block
+    let frame = [0xFF, 0xF1, 0x60, 0x40, 0x01, 0x3F, 0xFC, 0xAB, 0xCD];
+    let packet = [&frame[..], &frame[..]].concat();
+    assert_eq!(aac_duration(&packet), Some(128_000));
+
+    assert_eq!(aac_duration(&packet[..12]), None);
+    assert_eq!(aac_duration(&[0x00; 9]), None);
+}
+
+#[test]
+fn test_stream_info() {
+    let mut data = vec![0x31, 0x30, 0x30, 0x31, 0x20, 0x00, 0x00, 0x00];
+    data.extend(&2560u32.to_le_bytes());
+    data.extend(&1440u32.to_le_bytes());
+    data.extend(&[0x00, 25]);
+    data.extend(&[120, 10, 17, 12, 30, 5]); // 2020-10-17 12:30:05
+    data.extend(&[0; 6]);
+    data.extend(&[0; 2]);
+    let packet = MediaData { data: data.into() };
+
+    assert_eq!(packet.kind(), MediaDataKind::InfoData);
+    let info = packet.stream_info().unwrap();
+    assert_eq!(info.width, 2560);
+    assert_eq!(info.height, 1440);
+    assert_eq!(info.fps, 25);
+    assert_eq!(
+        info.start_time,
+        Some(
+            time::date!(2020 - 10 - 17)
+                .with_time(time::time!(12:30:05))
+                .assume_utc()
+        )
+    );
+    assert_eq!(info.end_time, None);
+}
+
+#[test]
+fn test_media_data_buffer() {
+    let first = MediaData::from_adpcm_block(&[1; 10]).into_bytes();
+    let second = MediaData::from_adpcm_block(&[2; 16]).into_bytes();
+    // Something unrecognisable, then the packets split where they don't begin or end
+    let stream = [&[0xff, 0xff][..], &first[..], &second[..]].concat();
+    let mut buffer = MediaDataBuffer::new();
+
+    buffer.extend(Bytes::copy_from_slice(&stream[..5]));
+    assert_eq!(buffer.next_media_packet(), None);
+    buffer.extend(Bytes::copy_from_slice(&stream[5..30]));
+    assert_eq!(buffer.next_media_packet().unwrap().body(), &[1; 10][..]);
+    assert_eq!(buffer.next_media_packet(), None);
+    buffer.extend(Bytes::copy_from_slice(&stream[30..]));
+    assert_eq!(buffer.next_media_packet().unwrap().body(), &[2; 16][..]);
+    assert_eq!(buffer.next_media_packet(), None);
+}
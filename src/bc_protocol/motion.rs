@@ -0,0 +1,157 @@
+use super::{BcCamera, Error, Result, RX_TIMEOUT};
+use crate::bc::{model::*, xml::*};
+use crate::bc_protocol::connection::BcSubscription;
+use log::*;
+use std::collections::VecDeque;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MotionKind {
+    Start,
+    Stop,
+}
+
+/// One channel's entry from an AlarmEventList pushed by the camera
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MotionEvent {
+    pub channel_id: u32,
+    pub kind: MotionKind,
+    /// The raw status the camera reported, such as "MD" or "none"
+    pub status: String,
+    /// Whether the camera is recording to its own storage
+    pub recording: bool,
+    pub timestamp: i32,
+}
+
+impl From<AlarmEvent> for MotionEvent {
+    fn from(event: AlarmEvent) -> Self {
+        // Anything other than "none" means something is moving; some cameras report more
+        // specific statuses than MD
+        let kind = if event.status == "none" {
+            MotionKind::Stop
+        } else {
+            MotionKind::Start
+        };
+        MotionEvent {
+            channel_id: event.channel_id,
+            kind,
+            status: event.status,
+            recording: event.recording != 0,
+            timestamp: event.timestamp,
+        }
+    }
+}
+
+/// Receives the motion events that the camera pushes after `subscribe_motion`.  It is also an
+/// iterator, which ends when the connection to the camera is lost.
+pub struct MotionDataSubscriber<'a> {
+    events: VecDeque<MotionEvent>,
+    bc_sub: BcSubscription<'a>,
+}
+
+impl<'a> MotionDataSubscriber<'a> {
+    /// Blocks until the camera reports the next motion event
+    pub fn next_motion_event(&mut self) -> Result<MotionEvent> {
+        while self.events.is_empty() {
+            // The camera only speaks when something changes, so there is no timeout here
+            let msg = self.bc_sub.rx.recv()?;
+            if let BcBody::ModernMsg(ModernMsg {
+                xml:
+                    Some(BcXml {
+                        alarm_event_list: Some(alarm_event_list),
+                        ..
+                    }),
+                ..
+            }) = msg.body
+            {
+                self.events.extend(
+                    alarm_event_list
+                        .alarm_events
+                        .into_iter()
+                        .map(MotionEvent::from),
+                );
+            } else {
+                debug!("Ignoring motion message without an AlarmEventList");
+                trace!("Contents: {:?}", msg);
+            }
+        }
+        Ok(self.events.pop_front().unwrap())
+    }
+}
+
+impl<'a> Iterator for MotionDataSubscriber<'a> {
+    type Item = Result<MotionEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_motion_event() {
+            Err(Error::DroppedConnection(_)) => None,
+            result => Some(result),
+        }
+    }
+}
+
+impl BcCamera {
+    /// Asks the camera to start pushing motion alarms, and returns a subscriber that receives
+    /// them as they arrive
+    pub fn subscribe_motion(&self) -> Result<MotionDataSubscriber<'_>> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to listen to motion");
+        // Subscribe before asking, so that no alarm can slip past
+        let sub_motion = connection.subscribe_all(MSG_ID_MOTION)?;
+        let sub_motion_request = connection.subscribe(MSG_ID_MOTION_REQUEST)?;
+
+        let request = Bc {
+            meta: BcMeta {
+                msg_id: MSG_ID_MOTION_REQUEST,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            body: BcBody::ModernMsg(ModernMsg::default()),
+        };
+
+        sub_motion_request.send(request)?;
+        sub_motion_request.rx.recv_timeout(RX_TIMEOUT)?;
+
+        Ok(MotionDataSubscriber {
+            events: VecDeque::new(),
+            bc_sub: sub_motion,
+        })
+    }
+
+    /// Stops delivering motion events to the current `MotionDataSubscriber`, which makes it stop
+    /// iterating.  The camera will keep pushing alarms until the session ends, but they are
+    /// dropped.
+    pub fn unsubscribe_motion(&self) {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to listen to motion");
+        connection.hang_up(MSG_ID_MOTION);
+    }
+}
+
+#[test]
+fn test_motion_event_from_alarm() {
+    let event = MotionEvent::from(AlarmEvent {
+        version: xml_ver(),
+        channel_id: 1,
+        status: "MD".to_string(),
+        recording: 1,
+        timestamp: 0,
+        ..Default::default()
+    });
+    assert_eq!(event.channel_id, 1);
+    assert_eq!(event.kind, MotionKind::Start);
+    assert!(event.recording);
+
+    let event = MotionEvent::from(AlarmEvent {
+        status: "none".to_string(),
+        ..Default::default()
+    });
+    assert_eq!(event.kind, MotionKind::Stop);
+    assert!(!event.recording);
+}
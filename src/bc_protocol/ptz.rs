@@ -0,0 +1,206 @@
+use super::{BcCamera, Error, Result, RX_TIMEOUT};
+use crate::bc::{model::*, xml::*};
+use std::fmt;
+use std::str::FromStr;
+
+/// The movements that can be requested with a PtzControl message.  A movement carries on until
+/// the camera reaches its limit or is sent `Stop`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PtzCommand {
+    Left,
+    Right,
+    Up,
+    Down,
+    ZoomIn,
+    ZoomOut,
+    Stop,
+}
+
+impl PtzCommand {
+    /// The value of the `<command>` element the camera expects for this movement
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PtzCommand::Left => "left",
+            PtzCommand::Right => "right",
+            PtzCommand::Up => "up",
+            PtzCommand::Down => "down",
+            PtzCommand::ZoomIn => "zoomIn",
+            PtzCommand::ZoomOut => "zoomOut",
+            PtzCommand::Stop => "stop",
+        }
+    }
+}
+
+impl fmt::Display for PtzCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PtzCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "left" => Ok(PtzCommand::Left),
+            "right" => Ok(PtzCommand::Right),
+            "up" => Ok(PtzCommand::Up),
+            "down" => Ok(PtzCommand::Down),
+            "zoomin" => Ok(PtzCommand::ZoomIn),
+            "zoomout" => Ok(PtzCommand::ZoomOut),
+            "stop" => Ok(PtzCommand::Stop),
+            _ => Err(format!(
+                "Unknown PTZ command {}; expected left, right, up, down, zoomin, zoomout or stop",
+                s
+            )),
+        }
+    }
+}
+
+impl BcCamera {
+    pub fn ptz(&self, channel_id: u32, command: PtzCommand, speed: u32) -> Result<()> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to control PTZ");
+        let sub_ptz = connection.subscribe(MSG_ID_PTZ_CONTROL)?;
+        let ptz = Bc::new_from_xml(
+            BcMeta {
+                msg_id: MSG_ID_PTZ_CONTROL,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            BcXml {
+                ptz_control: Some(PtzControl {
+                    version: xml_ver(),
+                    channel_id,
+                    speed,
+                    command: command.as_str().to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+
+        sub_ptz.send(ptz)?;
+        sub_ptz.rx.recv_timeout(RX_TIMEOUT)?;
+
+        Ok(())
+    }
+
+    /// Lists the preset positions the camera has saved for this channel
+    pub fn get_ptz_presets(&self, channel_id: u32) -> Result<Vec<Preset>> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to get PTZ presets");
+        let sub_get_presets = connection.subscribe(MSG_ID_GET_PTZ_PRESET)?;
+        let get = Bc::new_from_ext(
+            BcMeta {
+                msg_id: MSG_ID_GET_PTZ_PRESET,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            Extension {
+                version: xml_ver(),
+                channel_id: Some(channel_id),
+                ..Default::default()
+            },
+        );
+
+        sub_get_presets.send(get)?;
+        let msg = sub_get_presets.rx.recv_timeout(RX_TIMEOUT)?;
+
+        if let BcBody::ModernMsg(ModernMsg {
+            xml:
+                Some(BcXml {
+                    ptz_preset: Some(ptz_preset),
+                    ..
+                }),
+            ..
+        }) = msg.body
+        {
+            Ok(ptz_preset.preset_list.preset)
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: Box::new(msg),
+                why: "Expected PtzPreset list",
+            })
+        }
+    }
+
+    /// Saves the camera's current position as preset `preset_id`, named `name`
+    pub fn set_ptz_preset(&self, channel_id: u32, preset_id: u32, name: &str) -> Result<()> {
+        self.send_ptz_preset(
+            channel_id,
+            Preset {
+                id: preset_id,
+                name: Some(name.to_string()),
+                command: Some("setPos".to_string()),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Moves the camera to the previously saved preset `preset_id`
+    pub fn goto_ptz_preset(&self, channel_id: u32, preset_id: u32) -> Result<()> {
+        self.send_ptz_preset(
+            channel_id,
+            Preset {
+                id: preset_id,
+                name: None,
+                command: Some("toPos".to_string()),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn send_ptz_preset(&self, channel_id: u32, preset: Preset) -> Result<()> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to control PTZ presets");
+        let sub_preset = connection.subscribe(MSG_ID_PTZ_CONTROL_PRESET)?;
+        let msg = Bc::new_from_xml(
+            BcMeta {
+                msg_id: MSG_ID_PTZ_CONTROL_PRESET,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            BcXml {
+                ptz_preset: Some(PtzPreset {
+                    version: xml_ver(),
+                    channel_id,
+                    preset_list: PresetList {
+                        preset: vec![preset],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+
+        sub_preset.send(msg)?;
+        sub_preset.rx.recv_timeout(RX_TIMEOUT)?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ptz_command_parse() {
+    assert_eq!("left".parse(), Ok(PtzCommand::Left));
+    assert_eq!("zoomIn".parse(), Ok(PtzCommand::ZoomIn));
+    assert_eq!(PtzCommand::ZoomOut.as_str(), "zoomOut");
+    assert!("sideways".parse::<PtzCommand>().is_err());
+}
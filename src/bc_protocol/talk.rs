@@ -0,0 +1,174 @@
+use super::adpcm::pcm_to_adpcm;
+use super::media_packet::MediaData;
+use super::{BcCamera, Error, Result, RX_TIMEOUT};
+use crate::bc::{model::*, xml::*};
+use std::time::{Duration, Instant};
+
+impl BcCamera {
+    /// Asks the camera what audio it can play through its speaker
+    pub fn talk_ability(&self, channel_id: u32) -> Result<TalkAbility> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to get talk ability");
+        let sub_talk_ability = connection.subscribe(MSG_ID_TALK_ABILITY)?;
+
+        let get = Bc::new_from_ext(
+            BcMeta {
+                msg_id: MSG_ID_TALK_ABILITY,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            Extension {
+                version: xml_ver(),
+                channel_id: Some(channel_id),
+                ..Default::default()
+            },
+        );
+
+        sub_talk_ability.send(get)?;
+        let msg = sub_talk_ability.rx.recv_timeout(RX_TIMEOUT)?;
+
+        if let BcBody::ModernMsg(ModernMsg {
+            xml:
+                Some(BcXml {
+                    talk_ability: Some(talk_ability),
+                    ..
+                }),
+            ..
+        }) = msg.body
+        {
+            Ok(talk_ability)
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: Box::new(msg),
+                why: "Expected TalkAbility",
+            })
+        }
+    }
+
+    /// Opens the camera's speaker.  `config` should be made from one of the options offered by
+    /// `talk_ability`.
+    pub fn talk_start(&self, config: &TalkConfig) -> Result<()> {
+        let connection = self.connection.as_ref().expect("Must be connected to talk");
+        let sub_talk_config = connection.subscribe(MSG_ID_TALK_CONFIG)?;
+
+        let start = Bc {
+            meta: BcMeta {
+                msg_id: MSG_ID_TALK_CONFIG,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            body: BcBody::ModernMsg(ModernMsg {
+                extension: Some(Extension {
+                    version: xml_ver(),
+                    channel_id: Some(config.channel_id),
+                    ..Default::default()
+                }),
+                xml: Some(BcXml {
+                    talk_config: Some(config.clone()),
+                    ..Default::default()
+                }),
+                binary: None,
+            }),
+        };
+
+        sub_talk_config.send(start)?;
+        sub_talk_config.rx.recv_timeout(RX_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Plays 16 bit mono PCM audio through the speaker opened by `talk_start` with the same
+    /// `config`.  The sample rate must match the config.  This blocks until the camera should
+    /// have finished playing it, since the audio is sent no faster than it plays.
+    pub fn talk(&self, config: &TalkConfig, samples: &[i16]) -> Result<()> {
+        let connection = self.connection.as_ref().expect("Must be connected to talk");
+        if config.audio_config.audio_type != "adpcm" {
+            return Err(Error::Other("Talk only supports ADPCM audio"));
+        }
+        if config.audio_config.sample_rate == 0 || config.audio_config.length_per_encoder < 2 {
+            return Err(Error::Other("Talk config has an invalid audio format"));
+        }
+        let sub_talk = connection.subscribe(MSG_ID_TALK)?;
+
+        // Blocks hold two samples per byte, so the encoder needs an even number of them
+        let block_samples = (config.audio_config.length_per_encoder & !1) as usize;
+        let adpcm = pcm_to_adpcm(samples, block_samples);
+        // Frame header, predictor state, then the samples
+        let block_size = 8 + block_samples / 2;
+        let block_duration =
+            Duration::from_secs(1) * block_samples as u32 / config.audio_config.sample_rate;
+
+        let start = Instant::now();
+        for (i, block) in adpcm.chunks(block_size).enumerate() {
+            // The camera only buffers a little audio, so don't get ahead of it
+            let due = start + block_duration * i as u32;
+            let now = Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
+            }
+
+            let talk = Bc {
+                meta: BcMeta {
+                    msg_id: MSG_ID_TALK,
+                    channel_id: 0,
+                    stream_type: 0,
+                    msg_num: 0,
+                    encrypted: true,
+                    class: 0x6414,
+                },
+                body: BcBody::ModernMsg(ModernMsg {
+                    extension: Some(Extension {
+                        version: xml_ver(),
+                        binary_data: Some(1),
+                        channel_id: Some(config.channel_id),
+                    }),
+                    xml: None,
+                    binary: Some(MediaData::from_adpcm_block(block).into_bytes()),
+                }),
+            };
+            sub_talk.send(talk)?;
+        }
+
+        // Let the last block play out before the caller closes the speaker
+        let blocks = (adpcm.len() / block_size) as u32;
+        let end = start + block_duration * blocks;
+        let now = Instant::now();
+        if end > now {
+            std::thread::sleep(end - now);
+        }
+        Ok(())
+    }
+
+    /// Closes the camera's speaker again
+    pub fn talk_stop(&self, channel_id: u32) -> Result<()> {
+        let connection = self.connection.as_ref().expect("Must be connected to talk");
+        let sub_talk_reset = connection.subscribe(MSG_ID_TALK_RESET)?;
+
+        let stop = Bc::new_from_ext(
+            BcMeta {
+                msg_id: MSG_ID_TALK_RESET,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
+                encrypted: true,
+                class: 0x6414,
+            },
+            Extension {
+                version: xml_ver(),
+                channel_id: Some(channel_id),
+                ..Default::default()
+            },
+        );
+
+        sub_talk_reset.send(stop)?;
+        sub_talk_reset.rx.recv_timeout(RX_TIMEOUT)?;
+        Ok(())
+    }
+}
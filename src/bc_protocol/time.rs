@@ -9,68 +9,9 @@ impl BcCamera {
             .as_ref()
             .expect("Must be connected to get time");
         let sub_get_general = connection.subscribe(MSG_ID_GET_GENERAL)?;
-        let get = Bc {
-            meta: BcMeta {
-                msg_id: MSG_ID_GET_GENERAL,
-                client_idx: 0,
-                encrypted: true,
-                class: 0x6414,
-            },
-            body: BcBody::ModernMsg(ModernMsg::default()),
-        };
 
-        sub_get_general.send(get)?;
-        let msg = sub_get_general.rx.recv_timeout(RX_TIMEOUT)?;
-
-        if let BcBody::ModernMsg(ModernMsg {
-            xml:
-                Some(BcXml {
-                    system_general:
-                        Some(SystemGeneral {
-                            time_zone: Some(time_zone),
-                            year: Some(year),
-                            month: Some(month),
-                            day: Some(day),
-                            hour: Some(hour),
-                            minute: Some(minute),
-                            second: Some(second),
-                            ..
-                        }),
-                    ..
-                }),
-            ..
-        }) = msg.body
-        {
-            let datetime =
-                match try_build_timestamp(time_zone, year, month, day, hour, minute, second) {
-                    Ok(dt) => dt,
-                    Err(e) => {
-                        return Err(Error::UnintelligibleReply {
-                            reply: msg,
-                            why: "Could not parse date",
-                        })
-                    }
-                };
-
-            // This code was written in 2020; I'm trying to catch all the possible epochs that
-            // cameras might reset themselves to. My B800 resets to Jan 1, 1999, but I can't
-            // guarantee that Reolink won't pick some newer date.  Therefore, last year ought
-            // to be new enough, yet still distant enough that it won't interfere with anything
-            const BOUNDARY: Date = date!(2019 - 01 - 01);
-
-            // detect if no time is actually set, and return Ok(None): that is, operation
-            // succeeded, and there is no time set
-            if datetime.date() < BOUNDARY {
-                Ok(None)
-            } else {
-                Ok(Some(datetime))
-            }
-        } else {
-            Err(Error::UnintelligibleReply {
-                reply: msg,
-                why: "Reply did not contain SystemGeneral with all time fields filled out",
-            })
-        }
+        sub_get_general.send(get_general_msg())?;
+        time_from_reply(sub_get_general.rx.recv_timeout(RX_TIMEOUT)?)
     }
 
     pub fn set_time(&self, timestamp: OffsetDateTime) -> Result<()> {
@@ -82,7 +23,9 @@ impl BcCamera {
         let set = Bc::new_from_xml(
             BcMeta {
                 msg_id: MSG_ID_SET_GENERAL,
-                client_idx: 0,
+                channel_id: 0,
+                stream_type: 0,
+                msg_num: 0,
                 encrypted: true,
                 class: 0x6414,
             },
@@ -112,6 +55,73 @@ impl BcCamera {
     }
 }
 
+pub(super) fn get_general_msg() -> Bc {
+    Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_GET_GENERAL,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        body: BcBody::ModernMsg(ModernMsg::default()),
+    }
+}
+
+/// The time in the camera's reply to `get_general_msg`, or None if it has not been set
+pub(super) fn time_from_reply(msg: Bc) -> Result<Option<OffsetDateTime>> {
+    if let BcBody::ModernMsg(ModernMsg {
+        xml:
+            Some(BcXml {
+                system_general:
+                    Some(SystemGeneral {
+                        time_zone: Some(time_zone),
+                        year: Some(year),
+                        month: Some(month),
+                        day: Some(day),
+                        hour: Some(hour),
+                        minute: Some(minute),
+                        second: Some(second),
+                        ..
+                    }),
+                ..
+            }),
+        ..
+    }) = msg.body
+    {
+        let datetime = match try_build_timestamp(time_zone, year, month, day, hour, minute, second)
+        {
+            Ok(dt) => dt,
+            Err(e) => {
+                return Err(Error::UnintelligibleReply {
+                    reply: Box::new(msg),
+                    why: "Could not parse date",
+                })
+            }
+        };
+
+        // This code was written in 2020; I'm trying to catch all the possible epochs that
+        // cameras might reset themselves to. My B800 resets to Jan 1, 1999, but I can't
+        // guarantee that Reolink won't pick some newer date.  Therefore, last year ought
+        // to be new enough, yet still distant enough that it won't interfere with anything
+        const BOUNDARY: Date = date!(2019 - 01 - 01);
+
+        // detect if no time is actually set, and return Ok(None): that is, operation
+        // succeeded, and there is no time set
+        if datetime.date() < BOUNDARY {
+            Ok(None)
+        } else {
+            Ok(Some(datetime))
+        }
+    } else {
+        Err(Error::UnintelligibleReply {
+            reply: Box::new(msg),
+            why: "Reply did not contain SystemGeneral with all time fields filled out",
+        })
+    }
+}
+
 fn try_build_timestamp(
     timezone: i32,
     year: i32,
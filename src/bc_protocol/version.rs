@@ -0,0 +1,63 @@
+use super::{BcCamera, Error, Result, RX_TIMEOUT};
+use crate::bc::{model::*, xml::*};
+
+impl BcCamera {
+    /// Asks the camera for its model, serial number and firmware version
+    pub fn version_info(&self) -> Result<VersionInfo> {
+        let connection = self
+            .connection
+            .as_ref()
+            .expect("Must be connected to get version info");
+        let sub_version = connection.subscribe(MSG_ID_VERSION)?;
+
+        sub_version.send(version_msg())?;
+        let msg = sub_version.rx.recv_timeout(RX_TIMEOUT)?;
+
+        if let BcBody::ModernMsg(ModernMsg {
+            xml:
+                Some(BcXml {
+                    version_info: Some(version_info),
+                    ..
+                }),
+            ..
+        }) = msg.body
+        {
+            Ok(version_info)
+        } else {
+            Err(Error::UnintelligibleReply {
+                reply: Box::new(msg),
+                why: "Expected VersionInfo",
+            })
+        }
+    }
+}
+
+/// Asks for the camera's VersionInfo.  The request has no body.
+fn version_msg() -> Bc {
+    Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_VERSION,
+            channel_id: 0,
+            stream_type: 0,
+            msg_num: 0,
+            encrypted: true,
+            class: 0x6414,
+        },
+        body: BcBody::ModernMsg(ModernMsg::default()),
+    }
+}
+
+#[test]
+fn test_version_msg() {
+    let sent = version_msg().serialize(vec![]).unwrap();
+    #[rustfmt::skip]
+    let expected = [
+        0xf0, 0xde, 0xbc, 0x0a, // Magic
+        0x50, 0x00, 0x00, 0x00, // Message ID 80
+        0x00, 0x00, 0x00, 0x00, // No body
+        0x00, 0x00, 0x00, 0x00, // Channel, stream type and message number
+        0x01, 0xdc, 0x14, 0x64, // Encrypted, class 0x6414
+        0x00, 0x00, 0x00, 0x00, // Binary offset
+    ];
+    assert_eq!(sent, expected);
+}
@@ -0,0 +1,33 @@
+const UDP_KEY: [u32; 8] = [
+    0x1f2d_3c4b,
+    0x5a6c_7f8d,
+    0x3817_2e4b,
+    0x8271_635a,
+    0x863f_1a2b,
+    0xa5c6_f7d8,
+    0x8371_e1b4,
+    0x17f2_d3a5,
+];
+
+/// Encrypts or decrypts a UDP payload, since XOR is its own inverse
+pub fn crypt(offset: u32, buf: &[u8]) -> Vec<u8> {
+    let key: Vec<u8> = UDP_KEY
+        .iter()
+        .flat_map(|word| word.wrapping_add(offset).to_le_bytes().to_vec())
+        .collect();
+    buf.iter()
+        .zip(key.iter().cycle())
+        .map(|(b, k)| b ^ k)
+        .collect()
+}
+
+#[test]
+fn test_udp_crypto_roundtrip() {
+    let plaintext = b"<P2P><C2D_S><to><port>3000</port></to></C2D_S></P2P>";
+
+    let encrypted = crypt(0x1234_5678, &plaintext[..]);
+    assert_ne!(encrypted, &plaintext[..]);
+    assert_eq!(crypt(0x1234_5678, &encrypted), &plaintext[..]);
+    // The key depends on the transaction ID
+    assert_ne!(crypt(1, &encrypted), &plaintext[..]);
+}
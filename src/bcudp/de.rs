@@ -0,0 +1,154 @@
+use super::crypto;
+use super::model::*;
+use super::xml::UdpXml;
+use err_derive::Error;
+use nom::IResult;
+use nom::{branch::alt, bytes::complete::take, combinator::*, number::complete::*};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "Parsing error")]
+    NomError(&'static str),
+    #[error(display = "Checksum mismatch")]
+    ChecksumError,
+    #[error(display = "XML error: {}", _0)]
+    XmlError(String),
+}
+
+type NomErrorTuple<'a> = (&'a [u8], nom::error::ErrorKind);
+
+impl<'a> From<nom::Err<NomErrorTuple<'a>>> for Error {
+    fn from(k: nom::Err<NomErrorTuple<'a>>) -> Self {
+        let reason = match k {
+            nom::Err::Error(_) => "Nom Error",
+            nom::Err::Failure(_) => "Nom Failure",
+            _ => "Unknown Nom error",
+        };
+        Error::NomError(reason)
+    }
+}
+
+impl BcUdp {
+    /// Reads a message from a whole datagram
+    pub fn deserialize(buf: &[u8]) -> Result<BcUdp, Error> {
+        let (_, msg) = alt((
+            map(bcudp_discovery, |discovery| discovery.map(BcUdp::Discovery)),
+            map(bcudp_data, |data| Ok(BcUdp::Data(data))),
+            map(bcudp_ack, |ack| Ok(BcUdp::Ack(ack))),
+        ))(buf)?;
+        msg
+    }
+}
+
+/// The parse only fails if the datagram isn't a discovery message at all; a message that is
+/// one but is damaged gives an `Error`
+fn bcudp_discovery(buf: &[u8]) -> IResult<&[u8], Result<UdpDiscovery, Error>> {
+    let (buf, _magic) = verify(le_u32, |magic| *magic == MAGIC_HEADER_UDP_DISCOVERY)(buf)?;
+    let (buf, payload_len) = le_u32(buf)?;
+    let (buf, _unknown) = le_u32(buf)?;
+    let (buf, tid) = le_u32(buf)?;
+    let (buf, checksum) = le_u32(buf)?;
+    let (buf, payload) = take(payload_len)(buf)?;
+
+    if crc32fast::hash(payload) != checksum {
+        return Ok((buf, Err(Error::ChecksumError)));
+    }
+    let xml = crypto::crypt(tid, payload);
+    let discovery = UdpXml::try_parse(&xml[..])
+        .map(|payload| UdpDiscovery { tid, payload })
+        .map_err(Error::XmlError);
+    Ok((buf, discovery))
+}
+
+fn bcudp_data(buf: &[u8]) -> IResult<&[u8], UdpData> {
+    let (buf, _magic) = verify(le_u32, |magic| *magic == MAGIC_HEADER_UDP_DATA)(buf)?;
+    let (buf, connection_id) = le_i32(buf)?;
+    let (buf, _unknown) = le_u32(buf)?;
+    let (buf, _unknown) = le_u32(buf)?;
+    let (buf, packet_id) = le_u32(buf)?;
+    let (buf, payload_len) = le_u32(buf)?;
+    let (buf, payload) = take(payload_len)(buf)?;
+    Ok((
+        buf,
+        UdpData {
+            connection_id,
+            packet_id,
+            payload: payload.to_vec(),
+        },
+    ))
+}
+
+fn bcudp_ack(buf: &[u8]) -> IResult<&[u8], UdpAck> {
+    let (buf, _magic) = verify(le_u32, |magic| *magic == MAGIC_HEADER_UDP_ACK)(buf)?;
+    let (buf, connection_id) = le_i32(buf)?;
+    let (buf, _unknown) = le_u32(buf)?;
+    let (buf, _unknown) = le_u32(buf)?;
+    let (buf, packet_id) = le_u32(buf)?;
+    let (buf, _unknown) = le_u32(buf)?;
+    let (buf, payload_len) = le_u32(buf)?;
+    let (buf, payload) = take(payload_len)(buf)?;
+    Ok((
+        buf,
+        UdpAck {
+            connection_id,
+            packet_id,
+            received: payload.iter().map(|&r| r != 0).collect(),
+        },
+    ))
+}
+
+#[test]
+fn test_discovery_roundtrip() {
+    use super::xml::*;
+
+    let msg = BcUdp::Discovery(UdpDiscovery {
+        tid: 0x0bad_cafe,
+        payload: UdpXml {
+            c2d_s: Some(C2dS {
+                to: PortList { port: 3000 },
+            }),
+            ..Default::default()
+        },
+    });
+    let bytes = msg.serialize(vec![]).unwrap();
+    assert_eq!(&bytes[..4], &[0x3a, 0xcf, 0x87, 0x2a]);
+    let payload_len = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    assert_eq!(bytes.len(), UDP_HEADER_LEN + payload_len as usize);
+    assert_eq!(BcUdp::deserialize(&bytes).unwrap(), msg);
+
+    // Damaged in transit
+    let mut damaged = bytes.clone();
+    *damaged.last_mut().unwrap() ^= 0xff;
+    assert!(matches!(
+        BcUdp::deserialize(&damaged),
+        Err(Error::ChecksumError)
+    ));
+    // Not ours at all
+    assert!(BcUdp::deserialize(&bytes[4..]).is_err());
+}
+
+#[test]
+fn test_data_and_ack_roundtrip() {
+    let data = BcUdp::Data(UdpData {
+        connection_id: -42,
+        packet_id: 7,
+        payload: vec![0xf0, 0xde, 0xbc, 0x0a],
+    });
+    let bytes = data.serialize(vec![]).unwrap();
+    assert_eq!(&bytes[..4], &[0x10, 0xcf, 0x87, 0x2a]);
+    assert_eq!(bytes.len(), UDP_DATA_HEADER_LEN + 4);
+    assert_eq!(BcUdp::deserialize(&bytes).unwrap(), data);
+
+    let ack = BcUdp::Ack(UdpAck {
+        connection_id: 1234,
+        packet_id: 6,
+        received: vec![false, true, true],
+    });
+    let bytes = ack.serialize(vec![]).unwrap();
+    assert_eq!(&bytes[..4], &[0x20, 0xcf, 0x87, 0x2a]);
+    assert_eq!(&bytes[UDP_ACK_HEADER_LEN..], &[0, 1, 1]);
+    assert_eq!(BcUdp::deserialize(&bytes).unwrap(), ack);
+
+    // Cut short in transit
+    assert!(BcUdp::deserialize(&bytes[..bytes.len() - 1]).is_err());
+}
@@ -0,0 +1,37 @@
+//! Baichuan over UDP.  Cameras answer searches of the LAN with it, it is how a client finds a
+//! camera by its UID, and battery and some Wi-Fi cameras speak Baichuan only over it.  Each
+//! datagram is one message, and there are three kinds.  All fields are little endian.
+//!
+//! Discovery messages set up connections, with a 20 byte header:
+//!
+//! | Magic      | Payload length | Unknown (1) | Transaction ID | Payload checksum |
+//! |------------|----------------|-------------|----------------|------------------|
+//! | 0x2a87cf3a | u32            | u32         | u32            | u32 (CRC-32)     |
+//!
+//! followed by a payload of "encrypted" XML, whose root element is `<P2P>`.  The XOR key is offset
+//! by the transaction ID, which the camera echoes in its reply.
+//!
+//! Once connected, the TCP byte stream of Baichuan messages is cut into numbered data packets,
+//! with a 24 byte header:
+//!
+//! | Magic      | Connection ID | Unknown (0) | Unknown (0) | Packet ID | Payload length |
+//! |------------|---------------|-------------|-------------|-----------|----------------|
+//! | 0x2a87cf10 | i32           | u32         | u32         | u32       | u32            |
+//!
+//! which the receiver acknowledges with a 28 byte header:
+//!
+//! | Magic      | Connection ID | Unknown (0) | Unknown (0) | Packet ID | Unknown (0) | Payload length |
+//! |------------|---------------|-------------|-------------|-----------|-------------|----------------|
+//! | 0x2a87cf20 | i32           | u32         | u32         | u32       | u32         | u32            |
+//!
+//! An acknowledgement's packet ID is the last one received with none missing before it.  Its
+//! payload has a byte for each packet after that, 1 if it has arrived and 0 if not.  The
+//! connection ID is the one chosen by the receiver of the packet during the discovery exchange.
+
+pub mod model;
+
+pub mod de;
+pub mod ser;
+pub mod xml;
+
+mod crypto;
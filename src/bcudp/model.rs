@@ -0,0 +1,48 @@
+pub use super::xml::UdpXml;
+
+pub const MAGIC_HEADER_UDP_DISCOVERY: u32 = 0x2a87_cf3a;
+pub const MAGIC_HEADER_UDP_DATA: u32 = 0x2a87_cf10;
+pub const MAGIC_HEADER_UDP_ACK: u32 = 0x2a87_cf20;
+
+/// The size of a UDP discovery message's header
+pub const UDP_HEADER_LEN: usize = 20;
+/// The size of a UDP data packet's header
+pub const UDP_DATA_HEADER_LEN: usize = 24;
+/// The size of a UDP acknowledgement's header
+pub const UDP_ACK_HEADER_LEN: usize = 28;
+
+#[derive(Debug, PartialEq)]
+pub enum BcUdp {
+    Discovery(UdpDiscovery),
+    Data(UdpData),
+    Ack(UdpAck),
+}
+
+/// A message used to find cameras and set up connections to them
+#[derive(Debug, PartialEq)]
+pub struct UdpDiscovery {
+    /// Chosen by the client, and echoed by the camera in its reply
+    pub tid: u32,
+    pub payload: UdpXml,
+}
+
+/// A piece of the stream of Baichuan messages
+#[derive(Debug, PartialEq)]
+pub struct UdpData {
+    /// The receiver's ID for the connection
+    pub connection_id: i32,
+    /// Counts up from 0 in each direction
+    pub packet_id: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Tells the other end which data packets have arrived
+#[derive(Debug, PartialEq)]
+pub struct UdpAck {
+    /// The receiver's ID for the connection
+    pub connection_id: i32,
+    /// This packet and all those before it have arrived
+    pub packet_id: u32,
+    /// Whether each packet after `packet_id` has arrived
+    pub received: Vec<bool>,
+}
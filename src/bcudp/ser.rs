@@ -0,0 +1,62 @@
+use super::crypto;
+use super::model::*;
+use cookie_factory::bytes::*;
+use cookie_factory::sequence::tuple;
+use cookie_factory::{combinator::*, gen};
+use cookie_factory::{GenError, SerializeFn};
+use std::io::Write;
+
+pub type Error = GenError;
+
+impl BcUdp {
+    pub fn serialize<W: Write>(&self, buf: W) -> Result<W, GenError> {
+        let (buf, _) = match self {
+            BcUdp::Discovery(discovery) => gen(bcudp_discovery(discovery)?, buf)?,
+            BcUdp::Data(data) => gen(bcudp_data(data), buf)?,
+            BcUdp::Ack(ack) => gen(bcudp_ack(ack), buf)?,
+        };
+        Ok(buf)
+    }
+}
+
+fn bcudp_discovery<W: Write>(discovery: &UdpDiscovery) -> Result<impl SerializeFn<W>, GenError> {
+    let xml = discovery
+        .payload
+        .serialize(vec![])
+        .map_err(|_| GenError::CustomError(0))?;
+    let payload = crypto::crypt(discovery.tid, &xml);
+    Ok(tuple((
+        le_u32(MAGIC_HEADER_UDP_DISCOVERY),
+        le_u32(payload.len() as u32),
+        le_u32(1),
+        le_u32(discovery.tid),
+        le_u32(crc32fast::hash(&payload)),
+        slice(payload),
+    )))
+}
+
+fn bcudp_data<'a, W: Write + 'a>(data: &'a UdpData) -> impl SerializeFn<W> + 'a {
+    tuple((
+        le_u32(MAGIC_HEADER_UDP_DATA),
+        le_i32(data.connection_id),
+        le_u32(0),
+        le_u32(0),
+        le_u32(data.packet_id),
+        le_u32(data.payload.len() as u32),
+        slice(&data.payload),
+    ))
+}
+
+fn bcudp_ack<'a, W: Write + 'a>(ack: &'a UdpAck) -> impl SerializeFn<W> + 'a {
+    let received: Vec<u8> = ack.received.iter().map(|&r| r as u8).collect();
+    tuple((
+        le_u32(MAGIC_HEADER_UDP_ACK),
+        le_i32(ack.connection_id),
+        le_u32(0),
+        le_u32(0),
+        le_u32(ack.packet_id),
+        le_u32(0),
+        le_u32(received.len() as u32),
+        slice(received),
+    ))
+}
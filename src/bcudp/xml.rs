@@ -0,0 +1,132 @@
+// YaSerde currently macro-expands names like __type__value from type_
+#![allow(non_snake_case)]
+
+use std::io::{Read, Write};
+// YaSerde is currently naming the traits and the derive macros identically
+use yaserde::{ser::Config, YaDeserialize, YaSerialize};
+use yaserde_derive::{YaDeserialize, YaSerialize};
+
+#[cfg(test)]
+use indoc::indoc;
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "P2P")]
+pub struct UdpXml {
+    /// A search for cameras, from a client
+    #[yaserde(rename = "C2D_S")]
+    pub c2d_s: Option<C2dS>,
+    /// A camera's answer to a search
+    #[yaserde(rename = "D2C_S")]
+    pub d2c_s: Option<D2cS>,
+    /// A request to connect, from a client
+    #[yaserde(rename = "C2D_C")]
+    pub c2d_c: Option<C2dC>,
+    /// A camera's answer to a request to connect
+    #[yaserde(rename = "D2C_C_R")]
+    pub d2c_c_r: Option<D2cCR>,
+    /// A client hanging up
+    #[yaserde(rename = "C2D_DISC")]
+    pub c2d_disc: Option<C2dDisc>,
+}
+
+impl UdpXml {
+    pub fn try_parse(s: impl Read) -> Result<Self, String> {
+        yaserde::de::from_reader(s)
+    }
+    pub fn serialize<W: Write>(&self, w: W) -> Result<W, String> {
+        yaserde::ser::serialize_with_writer(self, w, &Config::default())
+    }
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct C2dS {
+    /// Where the client wants answers sent
+    pub to: PortList,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct PortList {
+    pub port: u16,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct D2cS {
+    pub uid: String,
+    /// The TCP port the camera takes Baichuan connections on
+    pub port: u16,
+    #[yaserde(rename = "type")]
+    pub model: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct C2dC {
+    pub uid: String,
+    /// Where the client wants data packets sent
+    pub cli: PortList,
+    /// The client's ID for the connection
+    pub cid: i32,
+    /// The largest datagram the client wants to receive
+    pub mtu: u32,
+    pub debug: bool,
+    /// The client's operating system
+    #[yaserde(rename = "p")]
+    pub os: String,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct D2cCR {
+    /// 0 if the connection was accepted
+    pub rsp: i32,
+    /// The client's ID for the connection, echoed back
+    pub cid: i32,
+    /// The camera's ID for the connection
+    pub did: i32,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, YaDeserialize, YaSerialize)]
+pub struct C2dDisc {
+    pub cid: i32,
+    pub did: i32,
+}
+
+#[test]
+fn test_search_reply() {
+    let sample = indoc!(
+        r#"
+        <P2P>
+        <D2C_S>
+        <uid>95270000ABCDEFGH</uid>
+        <port>9000</port>
+        <type>E1 Zoom</type>
+        <name>Driveway</name>
+        </D2C_S>
+        </P2P>"#
+    );
+    let xml = UdpXml::try_parse(sample.as_bytes()).unwrap();
+    let reply = xml.d2c_s.unwrap();
+    assert_eq!(reply.uid, "95270000ABCDEFGH");
+    assert_eq!(reply.port, 9000);
+    assert_eq!(reply.model.as_deref(), Some("E1 Zoom"));
+    assert_eq!(reply.name.as_deref(), Some("Driveway"));
+    assert!(xml.c2d_s.is_none());
+}
+
+#[test]
+fn test_connect_reply() {
+    let sample = indoc!(
+        r#"
+        <P2P>
+        <D2C_C_R>
+        <rsp>0</rsp>
+        <cid>82000</cid>
+        <did>-1530</did>
+        </D2C_C_R>
+        </P2P>"#
+    );
+    let xml = UdpXml::try_parse(sample.as_bytes()).unwrap();
+    let reply = xml.d2c_c_r.unwrap();
+    assert_eq!(reply.rsp, 0);
+    assert_eq!(reply.cid, 82000);
+    assert_eq!(reply.did, -1530);
+}
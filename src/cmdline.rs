@@ -1,3 +1,4 @@
+use neolink::bc_protocol::PtzCommand;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -8,4 +9,68 @@ pub struct Opt {
     /// main configuration file
     #[structopt(short, long, parse(from_os_str))]
     pub config: PathBuf,
+
+    /// Runs the RTSP server if no command is given
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Moves a pan/tilt/zoom camera
+    Ptz {
+        /// name of the camera in the configuration file
+        camera: String,
+        /// one of left, right, up, down, zoomin, zoomout or stop
+        command: PtzCommand,
+        /// how fast the camera should move
+        #[structopt(short, long, default_value = "32")]
+        speed: u32,
+    },
+    /// Lists, saves or recalls the preset positions of a pan/tilt/zoom camera
+    PtzPreset {
+        /// name of the camera in the configuration file
+        camera: String,
+        /// lists the presets if not given
+        #[structopt(subcommand)]
+        action: Option<PresetAction>,
+    },
+    /// Searches the LAN for cameras, listing their addresses and UIDs
+    Discover {
+        /// how many seconds to wait for answers
+        #[structopt(short, long, default_value = "3")]
+        timeout: u64,
+    },
+    /// Prints the model, serial number and firmware version of a camera, or of every camera
+    Info {
+        /// name of the camera in the configuration file; every camera if not given
+        camera: Option<String>,
+        /// print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Plays a WAV file through the camera's speaker
+    Talk {
+        /// name of the camera in the configuration file
+        camera: String,
+        /// the WAV file to play
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum PresetAction {
+    /// Saves the current position as a preset
+    Save {
+        /// preset number
+        id: u32,
+        /// preset name
+        name: String,
+    },
+    /// Moves to a saved preset
+    Goto {
+        /// preset number
+        id: u32,
+    },
 }
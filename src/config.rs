@@ -1,4 +1,5 @@
 use lazy_static::lazy_static;
+use neolink::bc_protocol::{CameraAddress, Transport};
 use regex::Regex;
 use serde::Deserialize;
 use std::clone::Clone;
@@ -9,9 +10,14 @@ use validator_derive::Validate;
 lazy_static! {
     static ref RE_STREAM_SRC: Regex = Regex::new(r"^(mainStream|subStream|both)$").unwrap();
     static ref RE_TLS_CLIENT_AUTH: Regex = Regex::new(r"^(none|request|require)$").unwrap();
+    static ref RE_RECORD_FORMAT: Regex = Regex::new(r"^(mp4|mkv)$").unwrap();
+    static ref RE_RECORD_MODE: Regex = Regex::new(r"^(continuous|motion)$").unwrap();
+    static ref RE_TRANSPORT: Regex = Regex::new(r"^(tcp|udp)$").unwrap();
+    static ref RE_PUSH_URL: Regex = Regex::new(r"^(rtmp|rtsp)://").unwrap();
 }
 
 #[derive(Debug, Deserialize, Validate, Clone)]
+#[validate(schema(function = "validate_mqtt_camera_names"))]
 pub struct Config {
     #[validate]
     pub cameras: Vec<CameraConfig>,
@@ -23,6 +29,10 @@ pub struct Config {
     #[serde(default = "default_bind_port")]
     pub bind_port: u16,
 
+    // The HTTP snapshot server only runs if this is given, and listens on bind_addr too
+    #[serde(default)]
+    pub http_bind_port: Option<u16>,
+
     #[serde(default = "default_certificate")]
     pub certificate: Option<String>,
 
@@ -37,14 +47,38 @@ pub struct Config {
     #[validate]
     #[serde(default)]
     pub users: Vec<UserConfig>,
+
+    #[validate]
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+
+    #[validate]
+    #[serde(default)]
+    pub hls: Option<HlsConfig>,
+
+    #[validate]
+    #[serde(default)]
+    pub onvif: Option<OnvifConfig>,
 }
 
 #[derive(Debug, Deserialize, Validate, Clone)]
+#[validate(schema(function = "validate_camera_address"))]
 pub struct CameraConfig {
     pub name: String,
 
     #[serde(rename = "address")]
-    pub camera_addr: String,
+    pub camera_addr: Option<String>,
+
+    // Found on the LAN each time the camera is connected to, instead of giving its address
+    pub uid: Option<String>,
+
+    #[validate(regex(
+        path = "RE_TRANSPORT",
+        message = "Incorrect transport",
+        code = "transport"
+    ))]
+    #[serde(default = "default_transport")]
+    pub transport: String,
 
     pub username: String,
     pub password: Option<String>,
@@ -68,6 +102,140 @@ pub struct CameraConfig {
     #[validate(range(min = 0, max = 31, message = "Invalid channel", code = "channel_id"))]
     #[serde(default = "default_channel_id")]
     pub channel_id: u32,
+
+    // Stream all the time, not just while a client is watching.  With MQTT the camera stays
+    // connected anyway, to hear about motion.
+    #[serde(default)]
+    pub always_on: bool,
+
+    // Seconds to keep streaming after the last client leaves, when not always on
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+
+    #[validate]
+    #[serde(default)]
+    pub record: Option<RecordConfig>,
+
+    #[validate]
+    #[serde(default)]
+    pub push: Option<PushConfig>,
+}
+
+#[derive(Debug, Deserialize, Validate, Clone)]
+pub struct RecordConfig {
+    pub directory: String,
+
+    #[validate(regex(
+        path = "RE_RECORD_FORMAT",
+        message = "Incorrect recording format",
+        code = "format"
+    ))]
+    #[serde(default = "default_record_format")]
+    pub format: String,
+
+    // Seconds per file
+    #[validate(range(min = 1, message = "Invalid segment length", code = "segment_length"))]
+    #[serde(default = "default_segment_length")]
+    pub segment_length: u64,
+
+    // Files older than this are deleted
+    pub max_age_hours: Option<u64>,
+    // The oldest files are deleted to keep the total under this
+    pub max_size_mb: Option<u64>,
+
+    #[validate(regex(
+        path = "RE_RECORD_MODE",
+        message = "Incorrect recording mode",
+        code = "mode"
+    ))]
+    #[serde(default = "default_record_mode")]
+    pub mode: String,
+
+    // Seconds of video from before the motion to include in motion clips
+    #[serde(default = "default_pre_roll")]
+    pub pre_roll: u64,
+
+    // Seconds to carry on recording after the motion stops
+    #[serde(default = "default_post_roll")]
+    pub post_roll: u64,
+}
+
+// Where to publish each stream, as an rtmp:// or rtsp:// URL
+#[derive(Debug, Deserialize, Validate, Clone)]
+pub struct PushConfig {
+    #[validate(regex(
+        path = "RE_PUSH_URL",
+        message = "Incorrect push URL",
+        code = "mainStream"
+    ))]
+    #[serde(rename = "mainStream")]
+    pub main_stream: Option<String>,
+
+    #[validate(regex(
+        path = "RE_PUSH_URL",
+        message = "Incorrect push URL",
+        code = "subStream"
+    ))]
+    #[serde(rename = "subStream")]
+    pub sub_stream: Option<String>,
+}
+
+impl CameraConfig {
+    pub fn address(&self) -> CameraAddress {
+        match (&self.camera_addr, &self.uid) {
+            (Some(addr), _) => CameraAddress::Host(addr.clone()),
+            (None, Some(uid)) => CameraAddress::Uid(uid.clone()),
+            (None, None) => unreachable!("Checked by validate_camera_address"),
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        match self.transport.as_str() {
+            "udp" => Transport::Udp,
+            _ => Transport::Tcp,
+        }
+    }
+
+    /// The URL to push a stream to, if it is pushed
+    pub fn push_url(&self, stream_name: &str) -> Option<&str> {
+        let push = self.push.as_ref()?;
+        match stream_name {
+            "mainStream" => push.main_stream.as_deref(),
+            "subStream" => push.sub_stream.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate, Clone)]
+pub struct HlsConfig {
+    // Each stream gets its own directory inside this one
+    pub directory: String,
+
+    // Seconds per segment; segments only start on I-frames, so some may be longer
+    #[validate(range(
+        min = 1,
+        message = "Invalid segment duration",
+        code = "segment_duration"
+    ))]
+    #[serde(default = "default_hls_segment_duration")]
+    pub segment_duration: u64,
+
+    #[validate(range(min = 1, message = "Invalid playlist length", code = "playlist_length"))]
+    #[serde(default = "default_hls_playlist_length")]
+    pub playlist_length: u32,
+}
+
+#[derive(Debug, Deserialize, Validate, Clone)]
+pub struct OnvifConfig {
+    // The ONVIF server listens on bind_addr too
+    #[validate(range(min = 0, max = 65535, message = "Invalid port", code = "port"))]
+    #[serde(default = "default_onvif_port")]
+    pub port: u16,
+
+    // Whether to answer WS-Discovery probes, so that NVRs can find the cameras by themselves
+    #[serde(default = "default_onvif_discovery")]
+    pub discovery: bool,
 }
 
 #[derive(Debug, Deserialize, Validate, Clone)]
@@ -80,6 +248,31 @@ pub struct UserConfig {
     pub pass: String,
 }
 
+#[derive(Debug, Deserialize, Validate, Clone)]
+pub struct MqttConfig {
+    #[serde(rename = "address")]
+    pub broker_addr: String,
+
+    #[validate(range(min = 0, max = 65535, message = "Invalid port", code = "port"))]
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+
+    #[validate(custom = "validate_topic_prefix")]
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    // Path to a PEM CA certificate; setting this turns on TLS
+    pub ca: Option<String>,
+    // Paths to a PEM certificate and key, for brokers that want client certificates
+    pub client_auth: Option<(String, String)>,
+}
+
 fn default_bind_addr() -> String {
     "0.0.0.0".to_string()
 }
@@ -92,6 +285,10 @@ fn default_stream() -> String {
     "both".to_string()
 }
 
+fn default_transport() -> String {
+    "tcp".to_string()
+}
+
 fn default_certificate() -> Option<String> {
     None
 }
@@ -104,6 +301,75 @@ fn default_channel_id() -> u32 {
     0
 }
 
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "neolink".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "neolink".to_string()
+}
+
+fn default_idle_timeout() -> u64 {
+    30
+}
+
+fn default_record_format() -> String {
+    "mkv".to_string()
+}
+
+fn default_segment_length() -> u64 {
+    300
+}
+
+fn default_hls_segment_duration() -> u64 {
+    4
+}
+
+fn default_hls_playlist_length() -> u32 {
+    5
+}
+
+fn default_onvif_port() -> u16 {
+    8000
+}
+
+fn default_onvif_discovery() -> bool {
+    true
+}
+
+fn default_record_mode() -> String {
+    "continuous".to_string()
+}
+
+fn default_pre_roll() -> u64 {
+    5
+}
+
+fn default_post_roll() -> u64 {
+    10
+}
+
+fn validate_camera_address(camera: &CameraConfig) -> Result<(), ValidationError> {
+    match (&camera.camera_addr, &camera.uid) {
+        (Some(_), None) | (None, Some(_)) => {}
+        _ => {
+            return Err(ValidationError::new(
+                "A camera needs either an address or a uid, but not both",
+            ))
+        }
+    }
+    if camera.transport == "udp" && camera.uid.is_none() {
+        return Err(ValidationError::new(
+            "A camera can only be reached over udp by its uid",
+        ));
+    }
+    Ok(())
+}
+
 pub static RESERVED_NAMES: &[&str] = &["anyone", "anonymous"];
 fn validate_username(name: &str) -> Result<(), ValidationError> {
     if name.trim().is_empty() {
@@ -114,3 +380,53 @@ fn validate_username(name: &str) -> Result<(), ValidationError> {
     }
     Ok(())
 }
+
+fn validate_topic_prefix(prefix: &str) -> Result<(), ValidationError> {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        return Err(ValidationError::new(
+            "topic_prefix cannot be empty or end with /",
+        ));
+    }
+    if prefix.contains(&['+', '#'][..]) {
+        return Err(ValidationError::new(
+            "topic_prefix cannot contain MQTT wildcards",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_mqtt_camera_names(config: &Config) -> Result<(), ValidationError> {
+    // Camera names become a level of the MQTT topics, so they must be one level on their own
+    if config.mqtt.is_some()
+        && config
+            .cameras
+            .iter()
+            .any(|camera| camera.name.is_empty() || camera.name.contains(&['/', '+', '#'][..]))
+    {
+        return Err(ValidationError::new(
+            "Camera names cannot be empty or contain /, + or # when using MQTT",
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_mqtt_camera_names() {
+    let config = |name: &str, mqtt: bool| -> Config {
+        let mut text = format!(
+            "[[cameras]]\nname = \"{}\"\nusername = \"admin\"\naddress = \"camera:9000\"\n",
+            name
+        );
+        if mqtt {
+            text.push_str("[mqtt]\naddress = \"broker\"\n");
+        }
+        toml::from_str(&text).unwrap()
+    };
+
+    assert!(config("driveway", true).validate().is_ok());
+    for name in &["front/door", "cam+", "#", ""] {
+        assert!(config(name, true).validate().is_err(), "{:?}", name);
+    }
+    // Without MQTT the name is only ever a label
+    assert!(config("front/door", false).validate().is_ok());
+}
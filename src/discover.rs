@@ -0,0 +1,34 @@
+use crate::config::Config;
+use crate::Error;
+use log::*;
+use neolink::bc_protocol::{discover, CameraAddress};
+use std::time::Duration;
+
+/// Lists the cameras that answer a search of the LAN, and which of them are already configured
+pub fn main(config: &Config, timeout: u64) -> Result<(), Error> {
+    info!("Searching the LAN for cameras for {}s", timeout);
+    let cameras = discover(Duration::from_secs(timeout))?;
+    if cameras.is_empty() {
+        info!("No cameras answered");
+        return Ok(());
+    }
+
+    println!("UID\tAddress\tModel\tName\tConfigured as");
+    for camera in cameras {
+        let configured = config.cameras.iter().find(|c| match c.address() {
+            CameraAddress::Uid(uid) => uid.eq_ignore_ascii_case(&camera.uid),
+            CameraAddress::Host(host) => {
+                host == camera.address.to_string() || host == camera.address.ip().to_string()
+            }
+        });
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            camera.uid,
+            camera.address,
+            camera.model.as_deref().unwrap_or("-"),
+            camera.name.as_deref().unwrap_or("-"),
+            configured.map_or("-", |c| c.name.as_str())
+        );
+    }
+    Ok(())
+}
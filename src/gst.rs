@@ -1,10 +1,16 @@
 //! This module provides an "RtspServer" abstraction that allows consumers of its API to feed it
 //! data using an ordinary std::io::Write interface.
 pub use self::maybe_app_src::MaybeAppSrc;
+pub use self::push::Pusher;
+pub use self::record::{
+    MotionOptions, RecordFormat, RecordOptions, RecordTrigger, Recorder, HLS_PLAYLIST,
+};
+use crate::bc_protocol::StreamInfo;
 use gstreamer::prelude::Cast;
-use gstreamer::{Bin, Structure};
-use gstreamer_app::AppSrc;
+use gstreamer::{Bin, Caps, ClockTime, Pipeline, State, Structure};
+use gstreamer_app::{AppSink, AppSrc};
 //use gstreamer_rtsp::RTSPLowerTrans;
+use bytes::Bytes;
 use gio::{TlsAuthenticationMode, TlsCertificate};
 use gstreamer_rtsp::RTSPAuthMethod;
 use gstreamer_rtsp_server::prelude::*;
@@ -19,6 +25,15 @@ use std::collections::HashSet;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+mod mux;
+mod push;
+mod record;
+
+/// Encodes raw audio to AAC, for sinks that can't hold it raw.  It comes with gst-libav.
+pub const AAC_ENCODER: &str = "avenc_aac";
 
 type Result<T> = std::result::Result<T, ()>;
 
@@ -40,6 +55,25 @@ pub struct GstOutputs {
     video_format: Option<StreamFormat>,
     audio_format: Option<StreamFormat>,
     factory: RTSPMediaFactory,
+    clock_anchor: Option<ClockAnchor>,
+    stream_info: Option<StreamInfo>,
+    stream_info_callbacks: Vec<StreamInfoCallback>,
+    video_format_callbacks: Vec<VideoFormatCallback>,
+    demand: StreamDemand,
+    /// Only kept once something takes snapshots
+    keyframe: Option<LatestKeyframe>,
+    recorders: Vec<Recorder>,
+    pushers: Vec<Pusher>,
+}
+
+type StreamInfoCallback = Box<dyn FnMut(&StreamInfo) + Send>;
+type VideoFormatCallback = Box<dyn FnMut(StreamFormat) + Send>;
+
+/// Ties a moment in the camera's stream time to the running time of the pipeline playing it
+struct ClockAnchor {
+    base_time: ClockTime,
+    running_time: u64,
+    stream_time: u64,
 }
 
 impl GstOutputs {
@@ -50,6 +84,14 @@ impl GstOutputs {
             video_format: None,
             audio_format: None,
             factory: RTSPMediaFactory::new(),
+            clock_anchor: None,
+            stream_info: None,
+            stream_info_callbacks: vec![],
+            video_format_callbacks: vec![],
+            demand: StreamDemand::new(),
+            keyframe: None,
+            recorders: vec![],
+            pushers: vec![],
         };
         result.apply_format();
         result
@@ -57,8 +99,11 @@ impl GstOutputs {
 
     pub fn set_format(&mut self, format: Option<StreamFormat>) {
         match format {
-            Some(StreamFormat::H264) | Some(StreamFormat::H265) => {
+            Some(video_format @ StreamFormat::H264) | Some(video_format @ StreamFormat::H265) => {
                 if format != self.video_format {
+                    for callback in &mut self.video_format_callbacks {
+                        callback(video_format);
+                    }
                     self.video_format = format;
                     self.apply_format();
                 }
@@ -73,6 +118,161 @@ impl GstOutputs {
         }
     }
 
+    /// Records the description the camera sent of the video stream, which is used to give the
+    /// pipeline exact caps
+    pub fn set_stream_info(&mut self, stream_info: StreamInfo) {
+        if self.stream_info.as_ref() != Some(&stream_info) {
+            for callback in &mut self.stream_info_callbacks {
+                callback(&stream_info);
+            }
+            self.stream_info = Some(stream_info);
+            self.apply_format();
+        }
+    }
+
+    /// Tracks whether any RTSP clients are watching these outputs
+    pub fn demand(&self) -> &StreamDemand {
+        &self.demand
+    }
+
+    /// The most recent I-frame written to these outputs, for taking snapshots.  The outputs only
+    /// keep a copy of each I-frame once this has been asked for.
+    pub fn keyframe(&mut self) -> &LatestKeyframe {
+        self.keyframe.get_or_insert_with(LatestKeyframe::new)
+    }
+
+    /// Also records everything written to these outputs from now on
+    pub fn add_recorder(&mut self, recorder: Recorder) {
+        self.recorders.push(recorder);
+    }
+
+    /// Also pushes everything written to these outputs to a server from now on
+    pub fn add_pusher(&mut self, pusher: Pusher) {
+        self.pushers.push(pusher);
+    }
+
+    /// Lets motion start the recorder, if one only records on motion
+    pub fn record_trigger(&self) -> Option<RecordTrigger> {
+        self.recorders
+            .iter()
+            .find_map(|recorder| recorder.trigger().cloned())
+    }
+
+    /// Finishes the files being recorded and the streams being pushed, if any, for when the
+    /// stream has ended
+    pub fn stream_ended(&mut self) {
+        for recorder in &mut self.recorders {
+            recorder.stop();
+        }
+        for pusher in &mut self.pushers {
+            pusher.stop();
+        }
+    }
+
+    /// Sends a video frame, in the format last given to `set_format`, to the RTSP clients, the
+    /// recorders and the pushers.  `stream_time` is in microseconds (see `StreamClock`).
+    pub fn write_video(&mut self, data: Bytes, stream_time: u64, keyframe: bool) -> io::Result<()> {
+        if keyframe {
+            self.set_keyframe(data.clone());
+        }
+        if let Some(video_format) = self.video_format {
+            for recorder in &mut self.recorders {
+                recorder.write_video(
+                    video_format,
+                    self.audio_format,
+                    &data,
+                    stream_time,
+                    keyframe,
+                );
+            }
+            for pusher in &mut self.pushers {
+                pusher.write_video(
+                    video_format,
+                    self.audio_format,
+                    &data,
+                    stream_time,
+                    keyframe,
+                );
+            }
+        }
+        let pts = self.pts(stream_time);
+        self.vidsrc.write_timestamped(data, pts)
+    }
+
+    /// Sends audio to the RTSP clients, the recorders and the pushers, like `write_video`
+    pub fn write_audio(&mut self, data: Bytes, stream_time: u64) -> io::Result<()> {
+        for recorder in &mut self.recorders {
+            recorder.write_audio(&data, stream_time);
+        }
+        for pusher in &mut self.pushers {
+            pusher.write_audio(&data, stream_time);
+        }
+        let pts = self.pts(stream_time);
+        self.audsrc.write_timestamped(data, pts)
+    }
+
+    /// Keeps hold of an I-frame, which must be in the format last given to `set_format`, if
+    /// anything takes snapshots
+    fn set_keyframe(&mut self, data: Bytes) {
+        if let (Some(format), Some(keyframe)) = (self.video_format, &self.keyframe) {
+            keyframe.set(Keyframe {
+                format,
+                data,
+                received: Instant::now(),
+            });
+        }
+    }
+
+    pub fn stream_info(&self) -> Option<&StreamInfo> {
+        self.stream_info.as_ref()
+    }
+
+    /// Calls `callback` whenever the camera describes the video stream differently, which is
+    /// usually once per stream.  Earlier callbacks are still called too.
+    pub fn on_stream_info(&mut self, callback: impl FnMut(&StreamInfo) + Send + 'static) {
+        self.stream_info_callbacks.push(Box::new(callback));
+    }
+
+    /// Calls `callback` whenever the video arrives in a different format, which is usually once
+    /// per stream
+    pub fn on_video_format(&mut self, callback: impl FnMut(StreamFormat) + Send + 'static) {
+        self.video_format_callbacks.push(Box::new(callback));
+    }
+
+    /// Forgets how stream time lines up with the pipeline, for when a new stream starts from
+    /// zero again
+    pub fn reset_clock(&mut self) {
+        self.clock_anchor = None;
+        // Recordings can't go back in time either, so start a new file
+        self.stream_ended();
+    }
+
+    /// Converts a stream time in microseconds (see `StreamClock`) into a PTS for the pipeline.
+    /// The first time the pipeline is seen playing, the current stream time is tied to its
+    /// current running time, and everything after is spaced out by the camera's own clock.
+    fn pts(&mut self, stream_time: u64) -> ClockTime {
+        let (running_time, base_time) = match self
+            .vidsrc
+            .running_time()
+            .or_else(|| self.audsrc.running_time())
+        {
+            Some(times) => times,
+            // Not playing yet, so there's nothing to line up with
+            None => return ClockTime::none(),
+        };
+        // A new base time means the media was rebuilt with a fresh pipeline
+        if self.clock_anchor.as_ref().map(|anchor| anchor.base_time) != Some(base_time) {
+            self.clock_anchor = Some(ClockAnchor {
+                base_time,
+                running_time: running_time.nseconds().unwrap_or(0),
+                stream_time,
+            });
+        }
+        let anchor = self.clock_anchor.as_ref().unwrap();
+        let offset = (stream_time as i64 - anchor.stream_time as i64) * 1000;
+        ClockTime::from_nseconds((anchor.running_time as i64 + offset).max(0) as u64)
+    }
+
     fn apply_format(&self) {
         let launch_vid = match self.video_format {
             Some(StreamFormat::H264) => {
@@ -90,12 +290,37 @@ impl GstOutputs {
             _ => "! fakesink",
         };
 
+        // Tell the parser what the camera told us, rather than leaving it to work it out
+        let vid_caps = match (self.video_format, &self.stream_info) {
+            (Some(format @ StreamFormat::H264), Some(info))
+            | (Some(format @ StreamFormat::H265), Some(info)) => {
+                let media_type = if format == StreamFormat::H264 {
+                    "video/x-h264"
+                } else {
+                    "video/x-h265"
+                };
+                let framerate = match info.fps {
+                    0 => "".to_string(),
+                    fps => format!(",framerate={}/1", fps),
+                };
+                format!(
+                    "caps=\"{},stream-format=byte-stream,width={},height={}{}\"",
+                    media_type, info.width, info.height, framerate
+                )
+            }
+            _ => "".to_string(),
+        };
+        let launch_vidsrc = format!(
+            "appsrc name=vidsrc is-live=true block=true emit-signals=false max-bytes=52428800 format=GST_FORMAT_TIME {}", // 50MB max size so that it won't grow to infinite if the queue blocks
+            vid_caps
+        );
+
         self.factory.set_launch(
             &vec![
             "( ",
-            "appsrc name=vidsrc is-live=true block=true emit-signals=false max-bytes=52428800 do-timestamp=true format=GST_FORMAT_TIME", // 50MB max size so that it won't grow to infinite if the queue blocks
+            &launch_vidsrc,
             launch_vid,
-            "appsrc name=audsrc is-live=true block=true emit-signals=false max-bytes=52428800 do-timestamp=true format=GST_FORMAT_TIME", // 50MB max size so that it won't grow to infinite if the queue blocks
+            "appsrc name=audsrc is-live=true block=true emit-signals=false max-bytes=52428800 format=GST_FORMAT_TIME", // 50MB max size so that it won't grow to infinite if the queue blocks
             launch_aud,
             ")"
         ]
@@ -104,6 +329,216 @@ impl GstOutputs {
     }
 }
 
+/// Counts the RTSP clients watching a stream, so that the camera only needs to send it while
+/// someone is watching.  Cloning it gives another handle on the same count.
+#[derive(Clone)]
+pub struct StreamDemand {
+    inner: Arc<(Mutex<DemandState>, Condvar)>,
+}
+
+struct DemandState {
+    clients: usize,
+    idle_since: Option<Instant>,
+    /// Counts as watched until then too, for clients that poll rather than stay connected
+    watched_until: Option<Instant>,
+}
+
+impl DemandState {
+    fn is_watched(&self) -> bool {
+        self.clients > 0 || matches!(self.watched_until, Some(until) if until > Instant::now())
+    }
+}
+
+impl Default for StreamDemand {
+    fn default() -> Self {
+        StreamDemand::new()
+    }
+}
+
+impl StreamDemand {
+    /// A count nobody is watching yet
+    pub fn new() -> StreamDemand {
+        StreamDemand {
+            inner: Arc::new((
+                Mutex::new(DemandState {
+                    clients: 0,
+                    // Nobody is watching yet, and nobody has been since we started
+                    idle_since: Some(Instant::now()),
+                    watched_until: None,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// Counts as a client until the returned guard is dropped.  This makes an on-demand stream
+    /// start, without needing an RTSP client.
+    pub fn watch(&self) -> DemandGuard {
+        self.client_arrived();
+        DemandGuard {
+            demand: self.clone(),
+        }
+    }
+
+    /// Counts as a client until `until`.  A client that keeps asking, such as an HLS player
+    /// fetching the playlist, moves it on each time.
+    pub fn watch_until(&self, until: Instant) {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        state.watched_until = state.watched_until.max(Some(until));
+        condvar.notify_all();
+    }
+
+    fn client_arrived(&self) {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        state.clients += 1;
+        state.idle_since = None;
+        condvar.notify_all();
+    }
+
+    fn client_left(&self) {
+        let (state, _) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        state.clients = state.clients.saturating_sub(1);
+        if state.clients == 0 {
+            state.idle_since = Some(Instant::now());
+        }
+    }
+
+    pub fn is_watched(&self) -> bool {
+        self.inner.0.lock().unwrap().is_watched()
+    }
+
+    /// How long it has been since the last client left, or None while someone is watching
+    pub fn idle_for(&self) -> Option<Duration> {
+        let state = self.inner.0.lock().unwrap();
+        if state.clients > 0 {
+            return None;
+        }
+        let since = state.idle_since.max(state.watched_until)?;
+        Instant::now().checked_duration_since(since)
+    }
+
+    /// Blocks until at least one client is watching
+    pub fn wait_for_client(&self) {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        while !state.is_watched() {
+            state = condvar.wait(state).unwrap();
+        }
+    }
+}
+
+/// A complete video frame that can be decoded without any other
+#[derive(Clone)]
+pub struct Keyframe {
+    pub format: StreamFormat,
+    pub data: Bytes,
+    pub received: Instant,
+}
+
+/// Holds the latest I-frame of a stream, so that other threads can take snapshots of it.
+/// Cloning it gives another handle on the same frame.
+#[derive(Clone)]
+pub struct LatestKeyframe {
+    inner: Arc<(Mutex<Option<Keyframe>>, Condvar)>,
+}
+
+impl LatestKeyframe {
+    fn new() -> LatestKeyframe {
+        LatestKeyframe {
+            inner: Arc::new((Mutex::new(None), Condvar::new())),
+        }
+    }
+
+    fn set(&self, keyframe: Keyframe) {
+        let (latest, condvar) = &*self.inner;
+        *latest.lock().unwrap() = Some(keyframe);
+        condvar.notify_all();
+    }
+
+    pub fn get(&self) -> Option<Keyframe> {
+        self.inner.0.lock().unwrap().clone()
+    }
+
+    /// Waits up to `timeout` for an I-frame received after `since`
+    pub fn wait_newer_than(&self, since: Instant, timeout: Duration) -> Option<Keyframe> {
+        let (latest, condvar) = &*self.inner;
+        let (latest, _) = condvar
+            .wait_timeout_while(
+                latest.lock().unwrap(),
+                timeout,
+                |latest| !matches!(latest, Some(keyframe) if keyframe.received > since),
+            )
+            .unwrap();
+        latest.clone().filter(|keyframe| keyframe.received > since)
+    }
+}
+
+/// Decodes an I-frame and encodes it as a JPEG image
+pub fn keyframe_to_jpeg(keyframe: &Keyframe) -> Result<Vec<u8>> {
+    let (caps, decode) = match keyframe.format {
+        StreamFormat::H264 => ("video/x-h264", "h264parse ! avdec_h264"),
+        StreamFormat::H265 => ("video/x-h265", "h265parse ! avdec_h265"),
+        _ => return Err(()),
+    };
+    let launch = format!(
+        "appsrc name=src ! {} ! videoconvert ! jpegenc ! appsink name=sink sync=false",
+        decode
+    );
+    let pipeline = gstreamer::parse_launch(&launch)
+        .map_err(|e| warn!("Could not build snapshot pipeline: {}", e))?
+        .dynamic_cast::<Pipeline>()
+        .expect("A launch line with several elements should make a pipeline");
+    let src = pipeline
+        .get_by_name("src")
+        .expect("src must be present in snapshot pipeline")
+        .dynamic_cast::<AppSrc>()
+        .expect("Source element is expected to be an appsrc!");
+    let sink = pipeline
+        .get_by_name("sink")
+        .expect("sink must be present in snapshot pipeline")
+        .dynamic_cast::<AppSink>()
+        .expect("Sink element is expected to be an appsink!");
+    src.set_caps(Some(&Caps::new_simple(
+        caps,
+        &[("stream-format", &"byte-stream")],
+    )));
+
+    let result = (|| {
+        pipeline.set_state(State::Playing).map_err(|_| ())?;
+        // The decoder hands over its only frame once it knows no more are coming
+        src.push_buffer(gstreamer::Buffer::from_slice(keyframe.data.clone()))
+            .map_err(|_| ())?;
+        src.end_of_stream().map_err(|_| ())?;
+        let sample = sink
+            .try_pull_sample(ClockTime::from_seconds(5))
+            .ok_or_else(|| warn!("Snapshot pipeline did not produce a JPEG"))?;
+        let buffer = sample.get_buffer().ok_or(())?;
+        let map = buffer.map_readable().map_err(|_| ())?;
+        Ok(map.as_slice().to_vec())
+    })();
+    let _ = pipeline.set_state(State::Null);
+    result
+}
+
+pub struct DemandGuard {
+    demand: StreamDemand,
+}
+
+impl Drop for DemandGuard {
+    fn drop(&mut self) {
+        self.demand.client_left();
+    }
+}
+
+/// Whether the AAC encoder that MP4 recording, HLS and pushing need for ADPCM audio is
+/// installed.  Gstreamer must be initialised first.
+pub fn can_encode_aac() -> bool {
+    gstreamer::ElementFactory::find(AAC_ENCODER).is_some()
+}
+
 impl Default for RtspServer {
     fn default() -> RtspServer {
         Self::new()
@@ -153,8 +588,18 @@ impl RtspServer {
 
         factory.set_shared(true);
 
+        let demand = outputs.demand.clone();
         factory.connect_media_configure(move |_factory, media| {
             debug!("RTSP: media was configured");
+            // The media is shared, so it is configured for the first client and unprepared once
+            // the last one has gone
+            demand.client_arrived();
+            let unprepared_demand = demand.clone();
+            media.connect_unprepared(move |_media| {
+                debug!("RTSP: media was unprepared");
+                unprepared_demand.client_left();
+            });
+
             let bin = media
                 .get_element()
                 .expect("Media should have an element")
@@ -287,37 +732,59 @@ mod maybe_app_src {
             }
         }
 
-        /// Attempts to retrieve the AppSrc that should be passed in by the caller of new_with_tx
-        /// at some point after this struct has been created.  At that point, we swap over to
-        /// owning the AppSrc directly.  This function handles either case and returns the AppSrc,
-        /// or None if the caller has not yet sent one.
-        fn try_get_src(&mut self) -> Option<&AppSrc> {
-            while let Some(src) = self.rx.try_recv().ok() {
-                self.app_src = Some(src);
-            }
-            self.app_src.as_ref()
+        /// Returns the running time of the pipeline, and the base time it is counted from, or
+        /// None if there is no pipeline or it is not playing yet
+        pub fn running_time(&mut self) -> Option<(ClockTime, ClockTime)> {
+            let src = self.try_get_src()?;
+            let clock = src.get_clock()?;
+            let base_time = src.get_base_time();
+            Some((clock.get_time() - base_time, base_time))
         }
-    }
 
-    impl Write for MaybeAppSrc {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            // If we have no AppSrc yet, throw away the data and claim that it was written
+        /// Pushes `buf` like write() does, stamped with `pts`.  GStreamer is handed the memory
+        /// `buf` is in rather than a copy of it.
+        pub fn write_timestamped(&mut self, buf: Bytes, pts: ClockTime) -> io::Result<()> {
+            self.push(buf, pts);
+            Ok(())
+        }
+
+        fn push(&mut self, buf: Bytes, pts: ClockTime) {
+            // If we have no AppSrc yet, throw away the data
             let app_src = match self.try_get_src() {
                 Some(src) => src,
-                None => return Ok(buf.len()),
+                None => return,
             };
 
-            let mut gst_buf = gstreamer::Buffer::with_size(buf.len()).unwrap();
+            let mut gst_buf = gstreamer::Buffer::from_slice(buf);
             {
                 let gst_buf_mut = gst_buf.get_mut().unwrap();
-                let mut gst_buf_data = gst_buf_mut.map_writable().unwrap();
-                gst_buf_data.copy_from_slice(buf);
+                // The cameras don't use B frames, so frames are decoded in the order they are shown
+                gst_buf_mut.set_pts(pts);
+                gst_buf_mut.set_dts(pts);
             }
 
             let res = app_src.push_buffer(gst_buf); //.map_err(|e| io::Error::new(io::ErrorKind::Other, Box::new(e)))?;
             if res.is_err() {
                 self.app_src = None;
             }
+        }
+
+        /// Attempts to retrieve the AppSrc that should be passed in by the caller of new_with_tx
+        /// at some point after this struct has been created.  At that point, we swap over to
+        /// owning the AppSrc directly.  This function handles either case and returns the AppSrc,
+        /// or None if the caller has not yet sent one.
+        fn try_get_src(&mut self) -> Option<&AppSrc> {
+            while let Some(src) = self.rx.try_recv().ok() {
+                self.app_src = Some(src);
+            }
+            self.app_src.as_ref()
+        }
+    }
+
+    impl Write for MaybeAppSrc {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            // Without a timestamp, the buffer is shown whenever it gets through the pipeline
+            self.push(Bytes::copy_from_slice(buf), ClockTime::none());
             Ok(buf.len())
         }
 
@@ -326,3 +793,77 @@ mod maybe_app_src {
         }
     }
 }
+
+#[test]
+fn test_stream_demand() {
+    let demand = StreamDemand::new();
+    assert!(!demand.is_watched());
+    assert!(demand.idle_for().is_some());
+
+    demand.client_arrived();
+    assert!(demand.is_watched());
+    assert_eq!(demand.idle_for(), None);
+    // Returns straight away, since someone is watching
+    demand.wait_for_client();
+
+    demand.client_left();
+    assert!(!demand.is_watched());
+    assert!(demand.idle_for().is_some());
+    // More leaving than arrived must not wrap around
+    demand.client_left();
+    assert!(!demand.is_watched());
+
+    {
+        let _guard = demand.watch();
+        assert!(demand.is_watched());
+    }
+    assert!(!demand.is_watched());
+
+    let waiter = demand.clone();
+    let handle = std::thread::spawn(move || waiter.wait_for_client());
+    demand.client_arrived();
+    handle.join().unwrap();
+    demand.client_left();
+
+    // Watching for a while counts until it runs out, and asking again moves it on
+    demand.watch_until(Instant::now() + Duration::from_millis(50));
+    assert!(demand.is_watched());
+    assert_eq!(demand.idle_for(), None);
+    demand.watch_until(Instant::now() + Duration::from_millis(200));
+    demand.watch_until(Instant::now());
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(demand.is_watched());
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(!demand.is_watched());
+    assert!(demand.idle_for().unwrap() < Duration::from_millis(50));
+}
+
+#[test]
+fn test_latest_keyframe() {
+    let latest = LatestKeyframe::new();
+    let start = Instant::now();
+    assert!(latest.get().is_none());
+    assert!(latest
+        .wait_newer_than(start, Duration::from_millis(10))
+        .is_none());
+
+    let waiter = latest.clone();
+    let handle = std::thread::spawn(move || waiter.wait_newer_than(start, Duration::from_secs(10)));
+    std::thread::sleep(Duration::from_millis(10));
+    latest.set(Keyframe {
+        format: StreamFormat::H264,
+        data: Bytes::from_static(&[0, 0, 0, 1]),
+        received: Instant::now(),
+    });
+    let keyframe = handle
+        .join()
+        .unwrap()
+        .expect("Should have seen the new keyframe");
+    assert_eq!(keyframe.data, &[0, 0, 0, 1][..]);
+
+    // The frame we already have is too old
+    assert!(latest
+        .wait_newer_than(Instant::now(), Duration::from_millis(10))
+        .is_none());
+    assert!(latest.get().is_some());
+}
@@ -0,0 +1,203 @@
+//! A pipeline that takes the camera's video and audio as it is written to `GstOutputs`, and
+//! muxes it into some sink without decoding the video.  Recording and pushing are built on it.
+use super::StreamFormat;
+use bytes::Bytes;
+use gstreamer::prelude::*;
+use gstreamer::{ClockTime, Element, MessageType, MessageView, Pipeline, State};
+use gstreamer_app::AppSrc;
+use log::*;
+
+/// Where a `MuxPipeline` sends its streams
+pub(super) struct MuxSink {
+    /// Launch line for the sink, which must be named "mux"
+    pub launch: String,
+    pub video_pad: &'static str,
+    pub audio_pad: &'static str,
+    /// Whether the sink can take raw audio, or it needs encoding first
+    pub raw_audio: bool,
+    /// The name of an element in the launch line, and the location to give it.  It is set as a
+    /// property, rather than written into the launch line, so that it can hold any characters.
+    pub location: Option<(&'static str, String)>,
+}
+
+pub(super) struct MuxPipeline {
+    pipeline: Pipeline,
+    vidsrc: AppSrc,
+    audsrc: Option<AppSrc>,
+    video_format: StreamFormat,
+    audio_format: Option<StreamFormat>,
+    /// The stream time of the first frame, which becomes time zero for the sink
+    base_time: u64,
+}
+
+impl MuxPipeline {
+    /// Builds a pipeline for streams in these formats, starting at `stream_time`.  It does not
+    /// run until `start` is called, so that the sink can be set up first.
+    pub fn new(
+        sink: &MuxSink,
+        video_format: StreamFormat,
+        audio_format: Option<StreamFormat>,
+        stream_time: u64,
+    ) -> Result<MuxPipeline, String> {
+        let launch_vid = match video_format {
+            StreamFormat::H264 => "video/x-h264,stream-format=byte-stream\" ! h264parse",
+            StreamFormat::H265 => "video/x-h265,stream-format=byte-stream\" ! h265parse",
+            _ => return Err("Only H.264 and H.265 video can be muxed".to_string()),
+        };
+        // The camera's audio is copied as it is when the sink can take it.  ADPCM is turned into
+        // raw audio before we see it, which most containers can't hold, so for those it is
+        // encoded to AAC; `can_encode_aac` checks for the encoder at startup.
+        let raw_aud =
+            "! rawaudioparse format=pcm pcm-format=s16le sample-rate=8000 num-channels=1 interleaved=true";
+        let launch_aud = match audio_format {
+            Some(StreamFormat::AAC) => Some("! aacparse".to_string()),
+            Some(StreamFormat::ADPCM) if sink.raw_audio => Some(raw_aud.to_string()),
+            Some(StreamFormat::ADPCM) => Some(format!(
+                "{} ! audioconvert ! {}",
+                raw_aud,
+                super::AAC_ENCODER
+            )),
+            _ => None,
+        };
+
+        let mut launch = vec![
+            sink.launch.clone(),
+            format!(
+                "appsrc name=vidsrc format=GST_FORMAT_TIME max-bytes=52428800 caps=\"{} ! queue ! {}",
+                launch_vid, sink.video_pad
+            ),
+        ];
+        if let Some(launch_aud) = launch_aud {
+            launch.push(format!(
+                "appsrc name=audsrc format=GST_FORMAT_TIME max-bytes=52428800 {} ! queue ! {}",
+                launch_aud, sink.audio_pad
+            ));
+        }
+
+        let pipeline = gstreamer::parse_launch(&launch.join(" "))
+            .map_err(|e| format!("Could not build pipeline: {}", e))?
+            .dynamic_cast::<Pipeline>()
+            .expect("A launch line with several elements should make a pipeline");
+
+        let get_src = |name| {
+            pipeline.get_by_name(name).map(|src| {
+                src.dynamic_cast::<AppSrc>()
+                    .expect("Source element is expected to be an appsrc!")
+            })
+        };
+        let vidsrc = get_src("vidsrc").expect("vidsrc must be present in mux pipeline");
+        let audsrc = get_src("audsrc");
+
+        if let Some((element, location)) = &sink.location {
+            pipeline
+                .get_by_name(element)
+                .expect("The location's element must be in the sink's launch line")
+                .set_property("location", location)
+                .map_err(|e| format!("Could not set location: {}", e))?;
+        }
+
+        Ok(MuxPipeline {
+            pipeline,
+            vidsrc,
+            audsrc,
+            video_format,
+            audio_format,
+            base_time: stream_time,
+        })
+    }
+
+    pub fn mux(&self) -> Element {
+        self.pipeline
+            .get_by_name("mux")
+            .expect("mux must be present in mux pipeline")
+    }
+
+    pub fn start(&self) -> Result<(), String> {
+        self.pipeline
+            .set_state(State::Playing)
+            .map(|_| ())
+            .map_err(|e| format!("Could not start pipeline: {}", e))
+    }
+
+    /// Whether this pipeline was built for streams in these formats
+    pub fn matches(&self, video_format: StreamFormat, audio_format: Option<StreamFormat>) -> bool {
+        self.video_format == video_format && self.audio_format == audio_format
+    }
+
+    pub fn push_video(&self, data: &Bytes, stream_time: u64) {
+        self.push(&self.vidsrc, data, stream_time);
+    }
+
+    pub fn push_audio(&self, data: &Bytes, stream_time: u64) {
+        if let Some(audsrc) = &self.audsrc {
+            self.push(audsrc, data, stream_time);
+        }
+    }
+
+    fn push(&self, src: &AppSrc, data: &Bytes, stream_time: u64) {
+        let pts = ClockTime::from_useconds(stream_time.saturating_sub(self.base_time));
+        // Shares the data rather than copying it
+        let mut buf = gstreamer::Buffer::from_slice(data.clone());
+        {
+            let buf = buf.get_mut().unwrap();
+            buf.set_pts(pts);
+            buf.set_dts(pts);
+        }
+        if let Err(e) = src.push_buffer(buf) {
+            debug!("Could not push to mux pipeline: {:?}", e);
+        }
+    }
+
+    /// The first error the pipeline has hit since this was last called, if any
+    pub fn error(&self) -> Option<String> {
+        let bus = self.pipeline.get_bus()?;
+        let msg = bus.pop_filtered(&[MessageType::Error])?;
+        match msg.view() {
+            MessageView::Error(err) => Some(err.get_error().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Ends the streams, then waits for the sink to finish with them on another thread, so as
+    /// not to hold up the stream, and calls `finished` once it has
+    pub fn finish(self, finished: impl FnOnce() + Send + 'static) {
+        let _ = self.vidsrc.end_of_stream();
+        if let Some(audsrc) = &self.audsrc {
+            let _ = audsrc.end_of_stream();
+        }
+        std::thread::spawn(move || {
+            if let Some(bus) = self.pipeline.get_bus() {
+                let msg = bus.timed_pop_filtered(
+                    ClockTime::from_seconds(5),
+                    &[MessageType::Eos, MessageType::Error],
+                );
+                if msg.is_none() {
+                    warn!("Timed out finishing mux pipeline");
+                }
+            }
+            let _ = self.pipeline.set_state(State::Null);
+            finished();
+        });
+    }
+
+    /// Stops the pipeline without waiting for the sink, which a broken one may never finish
+    pub fn abandon(self) {
+        let _ = self.pipeline.set_state(State::Null);
+    }
+}
+
+/// Whether an I-frame in these formats should start a new pipeline in place of `pipeline`.
+/// Audio usually arrives just after the first I-frame, so this gives it until the next one
+/// before starting without it.
+pub(super) fn wants_new_pipeline(
+    pipeline: Option<&MuxPipeline>,
+    video_format: StreamFormat,
+    audio_format: Option<StreamFormat>,
+    keyframes_seen: u32,
+) -> bool {
+    let changed = match pipeline {
+        Some(pipeline) => !pipeline.matches(video_format, audio_format),
+        None => true,
+    };
+    changed && (audio_format.is_some() || keyframes_seen >= 2)
+}
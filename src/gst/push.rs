@@ -0,0 +1,191 @@
+//! Publishes a stream to an RTMP or RTSP server, for when clients can't connect to us
+use super::mux::{wants_new_pipeline, MuxPipeline, MuxSink};
+use super::StreamFormat;
+use bytes::Bytes;
+use log::*;
+use std::time::{Duration, Instant};
+
+// The same as the camera connection's backoff
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(15);
+
+/// Publishes the video and audio written to it to a server.  If the server can't be reached or
+/// drops the stream, it tries again after a backoff.
+pub struct Pusher {
+    /// Identifies the stream in logs
+    name: String,
+    url: String,
+    pipeline: Option<PushPipeline>,
+    audio_format: Option<StreamFormat>,
+    keyframes_seen: u32,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+struct PushPipeline {
+    pipeline: MuxPipeline,
+    started: Instant,
+}
+
+impl Pusher {
+    /// Pushes to `url`, which must be an `rtmp://` or `rtsp://` URL
+    pub fn new(name: &str, url: &str) -> Pusher {
+        Pusher {
+            name: name.to_string(),
+            url: url.to_string(),
+            pipeline: None,
+            audio_format: None,
+            keyframes_seen: 0,
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    /// Pushes a video frame, with `stream_time` in microseconds.  Publishing only starts on an
+    /// I-frame.
+    pub fn write_video(
+        &mut self,
+        video_format: StreamFormat,
+        audio_format: Option<StreamFormat>,
+        data: &Bytes,
+        stream_time: u64,
+        keyframe: bool,
+    ) {
+        self.audio_format = audio_format;
+        self.check_for_error();
+        if keyframe {
+            self.keyframes_seen = self.keyframes_seen.saturating_add(1);
+            let waiting = matches!(self.retry_at, Some(at) if Instant::now() < at);
+            if !waiting
+                && wants_new_pipeline(
+                    self.pipeline.as_ref().map(|p| &p.pipeline),
+                    video_format,
+                    audio_format,
+                    self.keyframes_seen,
+                )
+            {
+                self.start(video_format, stream_time);
+            }
+        }
+        if let Some(pipeline) = &self.pipeline {
+            pipeline.pipeline.push_video(data, stream_time);
+        }
+    }
+
+    /// Pushes audio, with `stream_time` in microseconds
+    pub fn write_audio(&mut self, data: &Bytes, stream_time: u64) {
+        if let Some(pipeline) = &self.pipeline {
+            pipeline.pipeline.push_audio(data, stream_time);
+        }
+    }
+
+    /// Stops publishing, for when the stream has ended.  The next I-frame starts again.
+    pub fn stop(&mut self) {
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline.pipeline.finish(|| ());
+        }
+        self.keyframes_seen = 0;
+    }
+
+    fn start(&mut self, video_format: StreamFormat, stream_time: u64) {
+        self.abandon();
+        let result = push_sink(&self.url, video_format).and_then(|sink| {
+            let pipeline = MuxPipeline::new(&sink, video_format, self.audio_format, stream_time)?;
+            pipeline.start()?;
+            Ok(pipeline)
+        });
+        match result {
+            Ok(pipeline) => {
+                info!("{}: Pushing to {}", self.name, self.url);
+                self.pipeline = Some(PushPipeline {
+                    pipeline,
+                    started: Instant::now(),
+                });
+                self.retry_at = None;
+            }
+            Err(e) => self.failed(&e),
+        }
+    }
+
+    fn check_for_error(&mut self) {
+        let error = match &self.pipeline {
+            Some(pipeline) => pipeline.pipeline.error(),
+            None => None,
+        };
+        if let Some(e) = error {
+            // A push that worked for a while before failing starts its backoff again
+            if self.pipeline.as_ref().unwrap().started.elapsed() > MAX_BACKOFF {
+                self.backoff = MIN_BACKOFF;
+            }
+            self.abandon();
+            self.failed(&e);
+        }
+    }
+
+    fn failed(&mut self, error: &str) {
+        error!(
+            "{}: Error pushing to {}, will retry in {}s: {}",
+            self.name,
+            self.url,
+            self.backoff.as_secs(),
+            error
+        );
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = std::cmp::min(MAX_BACKOFF, self.backoff * 2);
+    }
+
+    fn abandon(&mut self) {
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline.pipeline.abandon();
+        }
+    }
+}
+
+impl Drop for Pusher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn push_sink(url: &str, video_format: StreamFormat) -> Result<MuxSink, String> {
+    if url.starts_with("rtmp://") {
+        if video_format != StreamFormat::H264 {
+            return Err("RTMP can only carry H.264 video".to_string());
+        }
+        Ok(MuxSink {
+            launch: "flvmux name=mux streamable=true ! rtmpsink name=sink".to_string(),
+            video_pad: "mux.video",
+            audio_pad: "mux.audio",
+            raw_audio: false,
+            location: Some(("sink", url.to_string())),
+        })
+    } else if url.starts_with("rtsp://") {
+        Ok(MuxSink {
+            launch: "rtspclientsink name=mux".to_string(),
+            video_pad: "mux.sink_0",
+            audio_pad: "mux.sink_1",
+            raw_audio: false,
+            location: Some(("mux", url.to_string())),
+        })
+    } else {
+        Err(format!("Can't push to {}", url))
+    }
+}
+
+#[test]
+fn test_push_sink() {
+    let rtmp = push_sink("rtmp://example.com/live/key", StreamFormat::H264).unwrap();
+    assert!(rtmp.launch.starts_with("flvmux"));
+    assert_eq!(
+        rtmp.location,
+        Some(("sink", "rtmp://example.com/live/key".to_string()))
+    );
+    // The URL is never parsed as part of the launch line
+    let quoted = push_sink("rtmp://example.com/live/\" ! fakesink", StreamFormat::H264).unwrap();
+    assert!(!quoted.launch.contains("fakesink"));
+    assert!(push_sink("rtmp://example.com/live/key", StreamFormat::H265).is_err());
+
+    let rtsp = push_sink("rtsp://example.com/cam", StreamFormat::H265).unwrap();
+    assert!(rtsp.launch.starts_with("rtspclientsink"));
+    assert!(push_sink("http://example.com/", StreamFormat::H264).is_err());
+}
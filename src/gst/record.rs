@@ -0,0 +1,705 @@
+//! Records a stream to a directory of video files, each starting on an I-frame, without
+//! decoding the video.  Old files are deleted as new ones are started.  The files can also be
+//! the segments of a live HLS playlist.
+use super::mux::{wants_new_pipeline, MuxPipeline, MuxSink};
+use super::StreamFormat;
+use bytes::Bytes;
+use gstreamer::prelude::*;
+use log::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use time::{Format, OffsetDateTime};
+
+/// The name of the playlist in an HLS recording's directory
+pub const HLS_PLAYLIST: &str = "index.m3u8";
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RecordFormat {
+    Mp4,
+    Matroska,
+    /// MPEG-TS segments listed in `index.m3u8`, which names the last `playlist_length` of them.
+    /// The muxer deletes old segments itself, so the age and size limits do not apply.
+    Hls {
+        playlist_length: u32,
+    },
+}
+
+impl RecordFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            RecordFormat::Mp4 => "mp4",
+            RecordFormat::Matroska => "mkv",
+            RecordFormat::Hls { .. } => "ts",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordOptions {
+    pub directory: PathBuf,
+    /// Starts every file name, to tell apart the cameras recording into the same directory
+    pub prefix: String,
+    pub format: RecordFormat,
+    pub segment_length: Duration,
+    /// Files older than this are deleted
+    pub max_age: Option<Duration>,
+    /// The oldest files are deleted while all of them together take up more bytes than this
+    pub max_size: Option<u64>,
+    /// Only record around motion, or all the time if None
+    pub motion: Option<MotionOptions>,
+}
+
+struct RecordPipeline {
+    pipeline: MuxPipeline,
+    /// The files the muxer has started so far
+    files: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+/// Records only around motion, rather than all the time
+#[derive(Debug, Copy, Clone)]
+pub struct MotionOptions {
+    /// How much of the stream from before the motion started goes into each clip.  Clips start
+    /// on an I-frame, so they may begin a little earlier than this.
+    pub pre_roll: Duration,
+    /// How long to carry on recording after the motion stops
+    pub post_roll: Duration,
+}
+
+/// Tells a `Recorder` recording on motion when the camera sees some.  Cloning it gives another
+/// handle on the same recorder.
+#[derive(Clone, Default)]
+pub struct RecordTrigger {
+    inner: Arc<Mutex<TriggerState>>,
+}
+
+#[derive(Default)]
+struct TriggerState {
+    motion: bool,
+    stopped_at: Option<Instant>,
+}
+
+impl RecordTrigger {
+    pub fn motion_started(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.motion = true;
+        state.stopped_at = None;
+    }
+
+    pub fn motion_stopped(&self) {
+        let mut state = self.inner.lock().unwrap();
+        if state.motion {
+            state.motion = false;
+            state.stopped_at = Some(Instant::now());
+        }
+    }
+
+    /// Whether there is motion, or was within the last `post_roll`
+    fn is_active(&self, post_roll: Duration) -> bool {
+        let state = self.inner.lock().unwrap();
+        state.motion
+            || matches!(state.stopped_at, Some(stopped_at) if stopped_at.elapsed() < post_roll)
+    }
+}
+
+/// Muxes the video and audio written to it into files, as described by its `RecordOptions`
+pub struct Recorder {
+    options: RecordOptions,
+    pipeline: Option<RecordPipeline>,
+    video_format: Option<StreamFormat>,
+    audio_format: Option<StreamFormat>,
+    /// I-frames seen since the stream started; audio has normally shown up by the second
+    keyframes_seen: u32,
+    trigger: RecordTrigger,
+    /// The last part of the stream, kept while waiting for motion
+    pre_roll: VecDeque<Packet>,
+    clip: Option<Clip>,
+}
+
+/// Shares its data with the other outputs, so that keeping it for the pre-roll doesn't copy it
+enum Packet {
+    Video {
+        data: Bytes,
+        stream_time: u64,
+        keyframe: bool,
+    },
+    Audio {
+        data: Bytes,
+        stream_time: u64,
+    },
+}
+
+impl Packet {
+    fn stream_time(&self) -> u64 {
+        match self {
+            Packet::Video { stream_time, .. } | Packet::Audio { stream_time, .. } => *stream_time,
+        }
+    }
+
+    fn is_keyframe(&self) -> bool {
+        matches!(self, Packet::Video { keyframe: true, .. })
+    }
+}
+
+/// A recording made because of motion, which may span several files
+struct Clip {
+    start: OffsetDateTime,
+    files: Vec<PathBuf>,
+}
+
+/// Written next to each clip, as JSON
+#[derive(Serialize)]
+struct ClipMetadata<'a> {
+    camera: &'a str,
+    start: String,
+    end: String,
+    trigger: &'static str,
+    files: Vec<String>,
+}
+
+impl Recorder {
+    pub fn new(options: RecordOptions) -> Recorder {
+        Recorder {
+            options,
+            pipeline: None,
+            video_format: None,
+            audio_format: None,
+            keyframes_seen: 0,
+            trigger: RecordTrigger::default(),
+            pre_roll: VecDeque::new(),
+            clip: None,
+        }
+    }
+
+    /// The trigger for recording, if this records on motion
+    pub fn trigger(&self) -> Option<&RecordTrigger> {
+        self.options.motion.as_ref().map(|_| &self.trigger)
+    }
+
+    /// Records a video frame, with `stream_time` in microseconds.  Files only start (or start
+    /// over, if the formats have changed) on an I-frame.
+    pub fn write_video(
+        &mut self,
+        video_format: StreamFormat,
+        audio_format: Option<StreamFormat>,
+        data: &Bytes,
+        stream_time: u64,
+        keyframe: bool,
+    ) {
+        self.video_format = Some(video_format);
+        self.audio_format = audio_format;
+        if keyframe {
+            self.keyframes_seen = self.keyframes_seen.saturating_add(1);
+        }
+        self.write(Packet::Video {
+            data: data.clone(),
+            stream_time,
+            keyframe,
+        });
+    }
+
+    /// Records audio, with `stream_time` in microseconds
+    pub fn write_audio(&mut self, data: &Bytes, stream_time: u64) {
+        self.write(Packet::Audio {
+            data: data.clone(),
+            stream_time,
+        });
+    }
+
+    /// Finishes the file being written, which must be done before it can be played (MP4 files
+    /// are not readable until they are finished).  This happens in the background.  The next
+    /// I-frame starts a new file.
+    pub fn stop(&mut self) {
+        self.end_clip();
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline.finish();
+        }
+        self.pre_roll.clear();
+        self.keyframes_seen = 0;
+    }
+
+    fn write(&mut self, packet: Packet) {
+        let motion = match self.options.motion {
+            Some(motion) => motion,
+            None => return self.record(packet),
+        };
+        let active = self.trigger.is_active(motion.post_roll);
+        if active && self.clip.is_none() {
+            self.start_clip(packet.stream_time());
+        } else if !active && self.clip.is_some() {
+            self.end_clip();
+        }
+
+        if self.clip.is_some() {
+            self.record(packet);
+        } else {
+            buffer_pre_roll(&mut self.pre_roll, packet, motion.pre_roll);
+        }
+    }
+
+    fn record(&mut self, packet: Packet) {
+        let video_format = match self.video_format {
+            Some(video_format) => video_format,
+            None => return,
+        };
+        if packet.is_keyframe()
+            && wants_new_pipeline(
+                self.pipeline.as_ref().map(|p| &p.pipeline),
+                video_format,
+                self.audio_format,
+                self.keyframes_seen,
+            )
+        {
+            self.finish_pipeline();
+            match RecordPipeline::new(
+                &self.options,
+                video_format,
+                self.audio_format,
+                packet.stream_time(),
+            ) {
+                Ok(pipeline) => self.pipeline = Some(pipeline),
+                Err(e) => error!("Could not start recording: {}", e),
+            }
+        }
+        if let Some(RecordPipeline { pipeline, .. }) = &self.pipeline {
+            match &packet {
+                Packet::Video {
+                    data, stream_time, ..
+                } => pipeline.push_video(data, *stream_time),
+                Packet::Audio { data, stream_time } => pipeline.push_audio(data, *stream_time),
+            }
+        }
+    }
+
+    fn start_clip(&mut self, stream_time: u64) {
+        // The clip really starts at the oldest part of the pre-roll
+        let pre_roll_length = self
+            .pre_roll
+            .front()
+            .map_or(0, |first| stream_time.saturating_sub(first.stream_time()));
+        let now = OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        info!("{}: Motion, recording a clip", self.options.prefix);
+        self.clip = Some(Clip {
+            start: now - Duration::from_micros(pre_roll_length),
+            files: vec![],
+        });
+        for packet in std::mem::take(&mut self.pre_roll) {
+            self.record(packet);
+        }
+    }
+
+    /// Finishes the clip being recorded, if any, and writes its metadata next to it
+    fn end_clip(&mut self) {
+        let mut clip = match self.clip.take() {
+            Some(clip) => clip,
+            None => return,
+        };
+        let pipeline = self.pipeline.take();
+        if let Some(pipeline) = &pipeline {
+            clip.files.extend(pipeline.take_files());
+        }
+        let first_file = match clip.files.first() {
+            Some(first_file) => first_file.clone(),
+            // The stream stopped before the clip got going
+            None => {
+                if let Some(pipeline) = pipeline {
+                    pipeline.finish();
+                }
+                return;
+            }
+        };
+        let end = OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let metadata = ClipMetadata {
+            camera: &self.options.prefix,
+            start: clip.start.format(Format::Rfc3339),
+            end: end.format(Format::Rfc3339),
+            trigger: "motion",
+            files: clip
+                .files
+                .iter()
+                .filter_map(|file| file.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect(),
+        };
+        let json = serde_json::to_vec_pretty(&metadata).map_err(|e| e.to_string());
+        let prefix = self.options.prefix.clone();
+        // Only written once the clip's files are finished, so that it never names an unreadable
+        // file
+        let write_sidecar = move || {
+            let sidecar = first_file.with_extension("json");
+            match json.and_then(|json| fs::write(&sidecar, json).map_err(|e| e.to_string())) {
+                Ok(()) => info!("{}: Recorded clip {}", prefix, sidecar.display()),
+                Err(e) => warn!("Could not write {}: {}", sidecar.display(), e),
+            }
+        };
+        match pipeline {
+            Some(pipeline) => pipeline.pipeline.finish(write_sidecar),
+            None => write_sidecar(),
+        }
+    }
+
+    /// Finishes the current file, keeping track of it if it is part of a clip
+    fn finish_pipeline(&mut self) {
+        if let Some(pipeline) = self.pipeline.take() {
+            let files = pipeline.finish();
+            if let Some(clip) = &mut self.clip {
+                clip.files.extend(files);
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Adds `packet` to the pre-roll, keeping it starting on the latest I-frame that is at least
+/// `length` before the newest packet
+fn buffer_pre_roll(pre_roll: &mut VecDeque<Packet>, packet: Packet, length: Duration) {
+    // It's no use without an I-frame to start from
+    if pre_roll.is_empty() && !packet.is_keyframe() {
+        return;
+    }
+    let keyframe = packet.is_keyframe();
+    let newest = packet.stream_time();
+    pre_roll.push_back(packet);
+    if !keyframe {
+        return;
+    }
+    let cutoff = newest.saturating_sub(length.as_micros() as u64);
+    let start = pre_roll
+        .iter()
+        .rposition(|packet| packet.is_keyframe() && packet.stream_time() <= cutoff);
+    if let Some(start) = start {
+        pre_roll.drain(..start);
+    }
+}
+
+impl RecordPipeline {
+    fn new(
+        options: &RecordOptions,
+        video_format: StreamFormat,
+        audio_format: Option<StreamFormat>,
+        stream_time: u64,
+    ) -> Result<RecordPipeline, String> {
+        fs::create_dir_all(&options.directory).map_err(|e| {
+            format!(
+                "Could not create directory {}: {}",
+                options.directory.display(),
+                e
+            )
+        })?;
+
+        let sink = match options.format {
+            RecordFormat::Mp4 | RecordFormat::Matroska => MuxSink {
+                launch: format!(
+                    "splitmuxsink name=mux muxer={} max-size-time={}",
+                    if options.format == RecordFormat::Mp4 {
+                        "mp4mux"
+                    } else {
+                        "matroskamux"
+                    },
+                    options.segment_length.as_nanos()
+                ),
+                video_pad: "mux.video",
+                audio_pad: "mux.audio_0",
+                raw_audio: options.format == RecordFormat::Matroska,
+                location: None,
+            },
+            RecordFormat::Hls { playlist_length } => MuxSink {
+                launch: format!(
+                    // Keep one segment more than the playlist, for players still fetching it
+                    "hlssink2 name=mux target-duration={} playlist-length={} max-files={}",
+                    options.segment_length.as_secs().max(1),
+                    playlist_length,
+                    playlist_length + 1
+                ),
+                video_pad: "mux.video",
+                audio_pad: "mux.audio",
+                raw_audio: false,
+                location: None,
+            },
+        };
+        let pipeline = MuxPipeline::new(&sink, video_format, audio_format, stream_time)?;
+
+        let mux = pipeline.mux();
+        let files = Arc::new(Mutex::new(vec![]));
+        if let RecordFormat::Hls { .. } = options.format {
+            // Players may still hold the playlist of the last run, so the segments of this one
+            // mustn't reuse its names
+            delete_hls_segments(&options.directory);
+            let run = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            let segments = options.directory.join(format!("segment-{}-%05d.ts", run));
+            let playlist = options.directory.join(HLS_PLAYLIST);
+            mux.set_property("location", &segments.to_string_lossy().to_value())
+                .and_then(|_| {
+                    mux.set_property("playlist-location", &playlist.to_string_lossy().to_value())
+                })
+                .map_err(|e| format!("Could not set HLS locations: {}", e))?;
+        } else {
+            let location_options = options.clone();
+            let location_files = files.clone();
+            mux.connect("format-location", false, move |_| {
+                // Make room for the new file before it is started
+                delete_expired(&location_options);
+                let location = next_location(&location_options);
+                info!("Recording to {}", location.display());
+                let value = location.to_string_lossy().to_value();
+                location_files.lock().unwrap().push(location);
+                Some(value)
+            })
+            .map_err(|e| format!("Could not name recordings: {}", e))?;
+        }
+
+        pipeline.start()?;
+        Ok(RecordPipeline { pipeline, files })
+    }
+
+    /// Takes the files the muxer has started since this was last called
+    fn take_files(&self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.files.lock().unwrap())
+    }
+
+    /// Ends the stream and returns the files that were written.  The muxer finishes writing them
+    /// in the background.
+    fn finish(self) -> Vec<PathBuf> {
+        let files = self.take_files();
+        self.pipeline.finish(|| ());
+        files
+    }
+}
+
+/// Deletes the segments left behind by earlier HLS recordings into `directory`, which the muxer
+/// only deletes while it is running
+fn delete_hls_segments(directory: &Path) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not list {}: {}", directory.display(), e);
+            return;
+        }
+    };
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if is_hls_segment(&path) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not delete {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn is_hls_segment(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    name.starts_with("segment") && name.ends_with(".ts")
+}
+
+/// Names a file after the time it was started, which also sorts the files in order
+fn next_location(options: &RecordOptions) -> PathBuf {
+    let now = OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let stem = format!("{}-{}", options.prefix, now.format("%Y%m%d-%H%M%S"));
+    let ext = options.format.extension();
+    let mut location = options.directory.join(format!("{}.{}", stem, ext));
+    // Only likely if the stream restarts straight away
+    let mut n = 1;
+    while location.exists() {
+        location = options.directory.join(format!("{}-{}.{}", stem, n, ext));
+        n += 1;
+    }
+    location
+}
+
+fn delete_expired(options: &RecordOptions) {
+    let segments = match list_segments(options) {
+        Ok(segments) => segments,
+        Err(e) => {
+            warn!(
+                "Could not list recordings in {}: {}",
+                options.directory.display(),
+                e
+            );
+            return;
+        }
+    };
+    for path in expired_segments(segments, SystemTime::now(), options) {
+        debug!("Deleting old recording {}", path.display());
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Could not delete {}: {}", path.display(), e);
+        }
+        // Motion clips have their metadata alongside
+        let _ = fs::remove_file(path.with_extension("json"));
+    }
+}
+
+/// Lists the files this recorder has made, with when they were last written and their size
+fn list_segments(options: &RecordOptions) -> std::io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(&options.directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !is_segment(&path, options) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        segments.push((path, metadata.modified()?, metadata.len()));
+    }
+    Ok(segments)
+}
+
+fn is_segment(path: &Path, options: &RecordOptions) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    // The date after the prefix stops "cam" from matching "cam2"'s files
+    let rest = match name
+        .strip_prefix(&options.prefix)
+        .and_then(|rest| rest.strip_prefix('-'))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    rest.starts_with(|c: char| c.is_ascii_digit())
+        && path.extension().and_then(|ext| ext.to_str()) == Some(options.format.extension())
+}
+
+/// Picks the segments to delete so that the rest are within the age and size limits
+fn expired_segments(
+    mut segments: Vec<(PathBuf, SystemTime, u64)>,
+    now: SystemTime,
+    options: &RecordOptions,
+) -> Vec<PathBuf> {
+    segments.sort_by_key(|&(_, modified, _)| modified);
+    let mut total_size: u64 = segments.iter().map(|&(_, _, size)| size).sum();
+    let mut expired = vec![];
+    for (path, modified, size) in segments {
+        let too_old = match options.max_age {
+            Some(max_age) => matches!(now.duration_since(modified), Ok(age) if age > max_age),
+            None => false,
+        };
+        let too_big = match options.max_size {
+            Some(max_size) => total_size > max_size,
+            None => false,
+        };
+        if !too_old && !too_big {
+            break;
+        }
+        total_size -= size;
+        expired.push(path);
+    }
+    expired
+}
+
+#[test]
+fn test_expired_segments() {
+    let options = RecordOptions {
+        directory: PathBuf::from("/recordings"),
+        prefix: "driveway".to_string(),
+        format: RecordFormat::Mp4,
+        segment_length: Duration::from_secs(60),
+        max_age: Some(Duration::from_secs(3600)),
+        max_size: Some(250),
+        motion: None,
+    };
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100_000);
+    let ago = |secs| now - Duration::from_secs(secs);
+    let segments = vec![
+        (PathBuf::from("c.mp4"), ago(60), 100),
+        (PathBuf::from("a.mp4"), ago(7200), 10),
+        (PathBuf::from("b.mp4"), ago(1800), 100),
+        (PathBuf::from("d.mp4"), ago(0), 100),
+    ];
+
+    // a is too old, and b has to go to get under the size limit
+    assert_eq!(
+        expired_segments(segments.clone(), now, &options),
+        vec![PathBuf::from("a.mp4"), PathBuf::from("b.mp4")]
+    );
+
+    let unlimited = RecordOptions {
+        max_age: None,
+        max_size: None,
+        ..options.clone()
+    };
+    assert!(expired_segments(segments, now, &unlimited).is_empty());
+
+    assert!(is_segment(
+        Path::new("/recordings/driveway-20201231-235959.mp4"),
+        &options
+    ));
+    assert!(!is_segment(
+        Path::new("/recordings/driveway2-20201231-235959.mp4"),
+        &options
+    ));
+    assert!(!is_segment(
+        Path::new("/recordings/driveway-20201231-235959.mkv"),
+        &options
+    ));
+    assert!(!is_segment(Path::new("/recordings/notes.txt"), &options));
+}
+
+#[test]
+fn test_pre_roll() {
+    let video = |stream_time, keyframe| Packet::Video {
+        data: Bytes::new(),
+        stream_time,
+        keyframe,
+    };
+    let audio = |stream_time| Packet::Audio {
+        data: Bytes::new(),
+        stream_time,
+    };
+    let length = Duration::from_secs(3);
+    let mut pre_roll = VecDeque::new();
+
+    // Nothing is kept until there is an I-frame to start from
+    buffer_pre_roll(&mut pre_roll, audio(0), length);
+    buffer_pre_roll(&mut pre_roll, video(0, false), length);
+    assert!(pre_roll.is_empty());
+
+    // An I-frame every two seconds, with a P-frame and audio in between
+    for second in 1..=8 {
+        let t = second * 1_000_000;
+        buffer_pre_roll(&mut pre_roll, video(t, second % 2 == 1), length);
+        buffer_pre_roll(&mut pre_roll, audio(t + 500_000), length);
+    }
+    // The I-frame at 3s is the latest one at least three seconds before the I-frame at 7s
+    let times: Vec<u64> = pre_roll.iter().map(Packet::stream_time).collect();
+    assert_eq!(
+        times,
+        vec![
+            3_000_000, 3_500_000, 4_000_000, 4_500_000, 5_000_000, 5_500_000, 6_000_000, 6_500_000,
+            7_000_000, 7_500_000, 8_000_000, 8_500_000
+        ]
+    );
+    assert!(pre_roll.front().unwrap().is_keyframe());
+}
+
+#[test]
+fn test_record_trigger() {
+    let trigger = RecordTrigger::default();
+    let post_roll = Duration::from_millis(50);
+    assert!(!trigger.is_active(post_roll));
+    // Stopping without starting doesn't start the post-roll
+    trigger.motion_stopped();
+    assert!(!trigger.is_active(post_roll));
+
+    trigger.motion_started();
+    assert!(trigger.is_active(post_roll));
+    trigger.motion_stopped();
+    assert!(trigger.is_active(post_roll));
+    std::thread::sleep(post_roll);
+    assert!(!trigger.is_active(post_roll));
+}
@@ -0,0 +1,280 @@
+use crate::config::UserConfig;
+use crate::Error;
+use log::*;
+use neolink::gst::{keyframe_to_jpeg, GstOutputs, LatestKeyframe, StreamDemand, HLS_PLAYLIST};
+use percent_encoding::percent_decode_str;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// A snapshot from a running stream is used if its I-frame is no older than this
+const MAX_KEYFRAME_AGE: Duration = Duration::from_secs(10);
+/// How long to wait for video from a stream that had to be started for a request
+const START_TIMEOUT: Duration = Duration::from_secs(15);
+/// HLS players fetch the playlist every segment or so, and each fetch keeps the stream running
+/// for this long
+const HLS_WATCH_TIME: Duration = Duration::from_secs(30);
+/// How many requests are handled at once.  Snapshots may have to wait for a stream to start.
+const WORKERS: usize = 8;
+
+/// Serves `/<camera name>/snapshot.jpg` for each camera added to it, and
+/// `/<camera name>/<stream>/index.m3u8` for each HLS stream
+pub struct HttpServer {
+    server: Server,
+    users: Vec<UserConfig>,
+    snapshots: HashMap<String, SnapshotSource>,
+    /// Keyed by "<camera name>/<stream>"
+    hls: HashMap<String, HlsSource>,
+}
+
+struct SnapshotSource {
+    keyframe: LatestKeyframe,
+    demand: StreamDemand,
+    permitted_users: HashSet<String>,
+}
+
+struct HlsSource {
+    directory: PathBuf,
+    demand: StreamDemand,
+    permitted_users: HashSet<String>,
+}
+
+impl HttpServer {
+    pub fn bind(addr: &str, port: u16, users: &[UserConfig]) -> Result<HttpServer, Error> {
+        let server = Server::http((addr, port)).map_err(Error::HttpServerError)?;
+        Ok(HttpServer {
+            server,
+            users: users.to_vec(),
+            snapshots: HashMap::new(),
+            hls: HashMap::new(),
+        })
+    }
+
+    /// Takes snapshots of `camera_name` from `outputs`, unless the camera already has a stream
+    /// to take them from
+    pub fn add_snapshots(
+        &mut self,
+        camera_name: &str,
+        outputs: &mut GstOutputs,
+        permitted_users: &HashSet<&str>,
+    ) {
+        self.snapshots
+            .entry(camera_name.to_string())
+            .or_insert_with(|| SnapshotSource {
+                keyframe: outputs.keyframe().clone(),
+                demand: outputs.demand().clone(),
+                permitted_users: to_owned_set(permitted_users),
+            });
+    }
+
+    /// Serves the HLS playlist and segments that `outputs` is recording into `directory`
+    pub fn add_hls(
+        &mut self,
+        camera_name: &str,
+        stream_name: &str,
+        directory: &Path,
+        outputs: &GstOutputs,
+        permitted_users: &HashSet<&str>,
+    ) {
+        self.hls.insert(
+            format!("{}/{}", camera_name, stream_name),
+            HlsSource {
+                directory: directory.to_path_buf(),
+                demand: outputs.demand().clone(),
+                permitted_users: to_owned_set(permitted_users),
+            },
+        );
+    }
+
+    pub fn run(self) {
+        let this = Arc::new(self);
+        // Starting a stream takes a while, so a few requests are handled at once, each worker
+        // taking the next request as soon as it is free
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|_| {
+                let this = this.clone();
+                std::thread::spawn(move || {
+                    for request in this.server.incoming_requests() {
+                        let response = this.handle(&request);
+                        if let Err(e) = request.respond(response) {
+                            debug!("HTTP: Could not send response: {}", e);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+
+    fn handle(&self, request: &Request) -> Response<io::Cursor<Vec<u8>>> {
+        if *request.method() != Method::Get {
+            return status(405);
+        }
+        let path = request.url().split('?').next().unwrap_or("");
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        let (dir, file) = match path
+            .strip_prefix('/')
+            .and_then(|path| path.rsplit_once('/'))
+        {
+            Some(split) => split,
+            None => return status(404),
+        };
+
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str());
+        let permitted = |permitted_users| is_permitted(authorization, &self.users, permitted_users);
+
+        if let (Some(source), "snapshot.jpg") = (self.snapshots.get(dir), file) {
+            if !permitted(&source.permitted_users) {
+                return unauthorized();
+            }
+            snapshot(dir, source)
+        } else if let Some(source) = self.hls.get(dir) {
+            if !permitted(&source.permitted_users) {
+                return unauthorized();
+            }
+            hls_file(dir, file, source)
+        } else {
+            status(404)
+        }
+    }
+}
+
+fn snapshot(camera_name: &str, source: &SnapshotSource) -> Response<io::Cursor<Vec<u8>>> {
+    let keyframe = match source.keyframe.get() {
+        Some(keyframe) if keyframe.received.elapsed() <= MAX_KEYFRAME_AGE => keyframe,
+        // The stream isn't running, so start it (if it's on demand) and take the next one
+        _ => {
+            debug!("HTTP: Starting {} for a snapshot", camera_name);
+            let _watching = source.demand.watch();
+            match source
+                .keyframe
+                .wait_newer_than(Instant::now(), START_TIMEOUT)
+            {
+                Some(keyframe) => keyframe,
+                None => {
+                    warn!("HTTP: No video from {} to take a snapshot of", camera_name);
+                    return status(503);
+                }
+            }
+        }
+    };
+
+    match keyframe_to_jpeg(&keyframe) {
+        Ok(jpeg) => with_content_type(Response::from_data(jpeg), "image/jpeg"),
+        Err(()) => status(500),
+    }
+}
+
+fn hls_file(stream: &str, file: &str, source: &HlsSource) -> Response<io::Cursor<Vec<u8>>> {
+    let content_type = if file == HLS_PLAYLIST {
+        "application/vnd.apple.mpegurl"
+    } else if file.starts_with("segment") && file.ends_with(".ts") && !file.contains("..") {
+        "video/mp2t"
+    } else {
+        return status(404);
+    };
+    let path = source.directory.join(file);
+
+    if file == HLS_PLAYLIST {
+        // Keep (or start) the stream going while the player is still asking for more
+        source.demand.watch_until(Instant::now() + HLS_WATCH_TIME);
+        if !path.exists() {
+            debug!("HTTP: Waiting for HLS playlist of {}", stream);
+        }
+        let deadline = Instant::now() + START_TIMEOUT;
+        while !path.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    match fs::read(&path) {
+        Ok(data) => with_content_type(Response::from_data(data), content_type),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => status(404),
+        Err(e) => {
+            warn!("HTTP: Could not read {}: {}", path.display(), e);
+            status(500)
+        }
+    }
+}
+
+fn to_owned_set(users: &HashSet<&str>) -> HashSet<String> {
+    users.iter().map(|u| u.to_string()).collect()
+}
+
+fn with_content_type(
+    response: Response<io::Cursor<Vec<u8>>>,
+    content_type: &str,
+) -> Response<io::Cursor<Vec<u8>>> {
+    response.with_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap())
+}
+
+fn unauthorized() -> Response<io::Cursor<Vec<u8>>> {
+    status(401).with_header(
+        Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic realm=\"neolink\""[..]).unwrap(),
+    )
+}
+
+fn status(code: u16) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_data(vec![]).with_status_code(code)
+}
+
+/// Applies the same rules as the RTSP server: `anonymous` lets anyone in, otherwise the request
+/// must carry the basic credentials of a permitted user
+fn is_permitted(
+    authorization: Option<&str>,
+    users: &[UserConfig],
+    permitted_users: &HashSet<String>,
+) -> bool {
+    if permitted_users.contains("anonymous") {
+        return true;
+    }
+    let credentials = authorization
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let (name, pass) = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    permitted_users.contains(name) && users.iter().any(|u| u.name == name && u.pass == pass)
+}
+
+#[test]
+fn test_is_permitted() {
+    let users = vec![
+        UserConfig {
+            name: "me".to_string(),
+            pass: "mepass".to_string(),
+        },
+        UserConfig {
+            name: "someone".to_string(),
+            pass: "someonepass".to_string(),
+        },
+    ];
+    let only_me: HashSet<String> = vec!["me".to_string()].into_iter().collect();
+    let anonymous: HashSet<String> = vec!["anonymous".to_string()].into_iter().collect();
+    let basic = |credentials: &str| format!("Basic {}", base64::encode(credentials));
+
+    assert!(is_permitted(None, &users, &anonymous));
+    assert!(is_permitted(Some(&basic("me:mepass")), &users, &only_me));
+
+    assert!(!is_permitted(None, &users, &only_me));
+    assert!(!is_permitted(Some(&basic("me:wrong")), &users, &only_me));
+    assert!(!is_permitted(
+        Some(&basic("someone:someonepass")),
+        &users,
+        &only_me
+    ));
+    assert!(!is_permitted(Some("Basic !!!"), &users, &only_me));
+    assert!(!is_permitted(Some("Bearer abc"), &users, &only_me));
+}
@@ -0,0 +1,91 @@
+use crate::config::{CameraConfig, Config};
+use crate::{connect_and_login, find_camera, Error};
+use log::*;
+use neolink::bc::xml::VersionInfo;
+use serde::Serialize;
+
+/// A camera's entry in the inventory, printed as JSON
+#[derive(Serialize)]
+struct CameraVersion<'a> {
+    camera: &'a str,
+    name: &'a str,
+    model: &'a str,
+    serial_number: &'a str,
+    build_day: &'a str,
+    hardware_version: &'a str,
+    firmware_version: &'a str,
+    config_version: &'a str,
+}
+
+/// Prints the model and versions of the named camera, or of every camera in the configuration
+pub fn main(config: &Config, camera_name: Option<&str>, json: bool) -> Result<(), Error> {
+    let camera_configs: Vec<&CameraConfig> = match camera_name {
+        Some(camera_name) => vec![find_camera(config, camera_name)?],
+        None => config.cameras.iter().collect(),
+    };
+
+    // A camera that can't be reached is reported, but doesn't keep the others out of the list
+    let mut first_error = None;
+    let mut version_infos = vec![];
+    for camera_config in camera_configs {
+        match get_version_info(camera_config) {
+            Ok(version_info) => version_infos.push((camera_config, version_info)),
+            Err(e) => {
+                error!(
+                    "Could not get version info from camera {}: {}",
+                    camera_config.name, e
+                );
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    let versions: Vec<CameraVersion> = version_infos
+        .iter()
+        .map(|(camera_config, info)| CameraVersion {
+            camera: &camera_config.name,
+            name: &info.name,
+            model: &info.type_,
+            serial_number: &info.serial_number,
+            build_day: &info.build_day,
+            hardware_version: &info.hardware_version,
+            firmware_version: &info.firmware_version,
+            config_version: &info.cfg_version,
+        })
+        .collect();
+
+    if json {
+        let json = serde_json::to_string_pretty(&versions).map_err(std::io::Error::from)?;
+        println!("{}", json);
+    } else {
+        println!("Camera\tName\tModel\tSerial number\tBuild\tHardware\tFirmware\tConfig");
+        for version in &versions {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                version.camera,
+                version.name,
+                version.model,
+                version.serial_number,
+                version.build_day,
+                version.hardware_version,
+                version.firmware_version,
+                version.config_version
+            );
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+fn get_version_info(camera_config: &CameraConfig) -> Result<VersionInfo, neolink::Error> {
+    let mut camera = connect_and_login(camera_config)?;
+    let version_info = camera.version_info();
+    // We already have what we came for, so failing to log out isn't worth more than a warning
+    if let Err(e) = camera.logout() {
+        warn!("{}: Could not log out: {}", camera_config.name, e);
+    }
+    version_info
+}
@@ -2,6 +2,7 @@
 #![allow(unused_variables)]
 pub mod bc;
 pub mod bc_protocol;
+pub mod bcudp;
 pub mod gst;
 
 #[derive(Debug)]
@@ -3,10 +3,13 @@ use err_derive::Error;
 use gio::TlsAuthenticationMode;
 use log::*;
 use neolink::bc_protocol::BcCamera;
-use neolink::gst::{GstOutputs, RtspServer};
-use neolink::Never;
+use neolink::gst::{
+    can_encode_aac, GstOutputs, MotionOptions, Pusher, RecordFormat, RecordOptions, Recorder,
+    RtspServer, AAC_ENCODER,
+};
 use std::collections::HashSet;
 use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use structopt::StructOpt;
@@ -14,9 +17,21 @@ use validator::Validate;
 
 mod cmdline;
 mod config;
-
-use cmdline::Opt;
-use config::{CameraConfig, Config, UserConfig};
+mod discover;
+mod http;
+mod info;
+mod mqtt;
+mod onvif;
+mod ptz;
+mod session;
+mod talk;
+
+use cmdline::{Command, Opt};
+use config::{CameraConfig, Config, HlsConfig, RecordConfig, UserConfig};
+use http::HttpServer;
+use mqtt::Mqtt;
+use onvif::{Discovery, OnvifServer};
+use session::CameraSession;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -30,6 +45,14 @@ pub enum Error {
     ValidationError(#[error(source)] validator::ValidationErrors),
     #[error(display = "ADPCM Decoding Error")]
     AdpcmDecodingError(&'static str),
+    #[error(display = "Camera {} is not in the configuration file", _0)]
+    CameraNotFound(String),
+    #[error(display = "WAV file error")]
+    WavError(#[error(source)] hound::Error),
+    #[error(display = "Could not start HTTP server: {}", _0)]
+    HttpServerError(Box<dyn std::error::Error + Send + Sync>),
+    #[error(display = "The Gstreamer element {} is missing; install {}", _0, _1)]
+    MissingGstElement(&'static str, &'static str),
 }
 
 fn main() -> Result<(), Error> {
@@ -46,8 +69,49 @@ fn main() -> Result<(), Error> {
 
     config.validate()?;
 
+    match opt.cmd {
+        None => rtsp_main(config),
+        Some(Command::Ptz {
+            camera,
+            command,
+            speed,
+        }) => ptz::main(&config, &camera, command, speed),
+        Some(Command::PtzPreset { camera, action }) => ptz::preset_main(&config, &camera, action),
+        Some(Command::Talk { camera, file }) => talk::main(&config, &camera, &file),
+        Some(Command::Discover { timeout }) => discover::main(&config, timeout),
+        Some(Command::Info { camera, json }) => info::main(&config, camera.as_deref(), json),
+    }
+}
+
+fn find_camera<'a>(config: &'a Config, name: &str) -> Result<&'a CameraConfig, Error> {
+    config
+        .cameras
+        .iter()
+        .find(|cam| cam.name == name)
+        .ok_or_else(|| Error::CameraNotFound(name.to_string()))
+}
+
+fn connect_and_login(camera_config: &CameraConfig) -> Result<BcCamera, neolink::Error> {
+    let mut camera =
+        BcCamera::connect_with_transport(camera_config.address(), camera_config.transport())?;
+    camera.login(&camera_config.username, camera_config.password.as_deref())?;
+    Ok(camera)
+}
+
+fn rtsp_main(config: Config) -> Result<(), Error> {
     let rtsp = &RtspServer::new();
 
+    // We only learn whether a camera's audio is ADPCM, which these need encoding to AAC, once
+    // it is connected, so check for the encoder now rather than fail then
+    let needs_aac = config.hls.is_some()
+        || config.cameras.iter().any(|camera| {
+            camera.push.is_some()
+                || matches!(&camera.record, Some(record) if record.format == "mp4")
+        });
+    if needs_aac && !can_encode_aac() {
+        return Err(Error::MissingGstElement(AAC_ENCODER, "gst-libav"));
+    }
+
     set_up_tls(&config, &rtsp);
 
     set_up_users(&config.users, &rtsp);
@@ -58,10 +122,33 @@ fn main() -> Result<(), Error> {
         )
     }
 
+    let mqtt = config.mqtt.as_ref().map(Mqtt::connect).transpose()?;
+    let mqtt = mqtt.as_ref();
+
+    let mut http = match config.http_bind_port {
+        Some(port) => Some(HttpServer::bind(&config.bind_addr, port, &config.users)?),
+        None => None,
+    };
+    if config.hls.is_some() && http.is_none() {
+        warn!("HLS is only written while a camera streams, and without http_bind_port");
+        warn!("fetching a playlist can't start it; set always_on = true to watch over HLS");
+    }
+    let mut onvif = match &config.onvif {
+        Some(onvif) => Some(OnvifServer::bind(&config, onvif)?),
+        None => None,
+    };
+    let discovery = match &config.onvif {
+        Some(onvif) if onvif.discovery => {
+            let names = config.cameras.iter().map(|c| c.name.clone()).collect();
+            Some(Discovery::bind(&config.bind_addr, onvif.port, names)?)
+        }
+        _ => None,
+    };
+
     crossbeam::scope(|s| {
         for camera in config.cameras {
             if camera.format.is_some() {
-                warn!("The format config option of the camera has been removed in favour of auto detection.")
+                warn!("The camera's format option has been removed in favour of auto detection")
             }
             // Let subthreads share the camera object; in principle I think they could share
             // the object as it sits in the config.cameras block, but I have not figured out the
@@ -71,27 +158,75 @@ fn main() -> Result<(), Error> {
             let permitted_users =
                 get_permitted_users(config.users.as_slice(), &arc_cam.permitted_users);
 
+            let mut session = CameraSession::new(arc_cam.clone(), mqtt);
+
             // Set up each main and substream according to all the RTSP mount paths we support
             if ["both", "mainStream"].iter().any(|&e| e == arc_cam.stream) {
                 let paths = &[
                     &*format!("/{}", arc_cam.name),
                     &*format!("/{}/mainStream", arc_cam.name),
                 ];
-                let mut outputs = rtsp
-                    .add_stream(paths, &permitted_users)
-                    .unwrap();
-                let main_camera = arc_cam.clone();
-                s.spawn(move |_| camera_loop(&*main_camera, "mainStream", &mut outputs, true));
+                let mut outputs = rtsp.add_stream(paths, &permitted_users).unwrap();
+                if let Some(http) = &mut http {
+                    http.add_snapshots(&arc_cam.name, &mut outputs, &permitted_users);
+                }
+                if let Some(record) = &arc_cam.record {
+                    outputs.add_recorder(Recorder::new(record_options(&arc_cam.name, record)));
+                }
+                if let Some(hls) = &config.hls {
+                    let stream = (&*arc_cam.name, "mainStream");
+                    add_hls(hls, stream, &mut outputs, http.as_mut(), &permitted_users);
+                }
+                add_pusher(&arc_cam, "mainStream", &mut outputs);
+                if let Some(onvif) = &mut onvif {
+                    onvif.add_stream(
+                        &arc_cam,
+                        "mainStream",
+                        &mut outputs,
+                        &session.commands(),
+                        &permitted_users,
+                    );
+                }
+                session.add_stream("mainStream", outputs);
             }
             if ["both", "subStream"].iter().any(|&e| e == arc_cam.stream) {
                 let paths = &[&*format!("/{}/subStream", arc_cam.name)];
-                let mut outputs = rtsp
-                    .add_stream(paths, &permitted_users)
-                    .unwrap();
-                let sub_camera = arc_cam.clone();
-                let manage = arc_cam.stream == "subStream";
-                s.spawn(move |_| camera_loop(&*sub_camera, "subStream", &mut outputs, manage));
+                let mut outputs = rtsp.add_stream(paths, &permitted_users).unwrap();
+                // Snapshots come from the main stream if there is one, since it is sharper
+                if let Some(http) = &mut http {
+                    http.add_snapshots(&arc_cam.name, &mut outputs, &permitted_users);
+                }
+                // Likewise only one stream is recorded
+                if let (Some(record), "subStream") = (&arc_cam.record, &*arc_cam.stream) {
+                    outputs.add_recorder(Recorder::new(record_options(&arc_cam.name, record)));
+                }
+                if let Some(hls) = &config.hls {
+                    let stream = (&*arc_cam.name, "subStream");
+                    add_hls(hls, stream, &mut outputs, http.as_mut(), &permitted_users);
+                }
+                add_pusher(&arc_cam, "subStream", &mut outputs);
+                if let Some(onvif) = &mut onvif {
+                    onvif.add_stream(
+                        &arc_cam,
+                        "subStream",
+                        &mut outputs,
+                        &session.commands(),
+                        &permitted_users,
+                    );
+                }
+                session.add_stream("subStream", outputs);
             }
+            s.spawn(move |_| session.run());
+        }
+
+        if let Some(http) = http {
+            s.spawn(move |_| http.run());
+        }
+        if let Some(onvif) = onvif {
+            s.spawn(move |_| onvif.run());
+        }
+        if let Some(discovery) = discovery {
+            s.spawn(move |_| discovery.run());
         }
 
         rtsp.run(&config.bind_addr, config.bind_port);
@@ -101,48 +236,71 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn camera_loop(
-    camera_config: &CameraConfig,
-    stream_name: &str,
-    outputs: &mut GstOutputs,
-    manage: bool,
-) -> Result<Never, Error> {
-    let min_backoff = Duration::from_secs(1);
-    let max_backoff = Duration::from_secs(15);
-    let mut current_backoff = min_backoff;
-
-    loop {
-        let cam_err = camera_main(camera_config, stream_name, outputs, manage).unwrap_err();
-        outputs.vidsrc.on_stream_error();
-        outputs.audsrc.on_stream_error();
-        // Authentication failures are permanent; we retry everything else
-        if cam_err.connected {
-            current_backoff = min_backoff;
-        }
-        match cam_err.err {
-            neolink::Error::AuthFailed => {
-                error!(
-                    "Authentication failed to camera {}, not retrying",
-                    camera_config.name
-                );
-                return Err(cam_err.err.into());
-            }
-            _ => error!(
-                "Error streaming from camera {}, will retry in {}s: {}",
-                camera_config.name,
-                current_backoff.as_secs(),
-                cam_err.err
-            ),
-        }
+/// Publishes the stream to the server it is configured to be pushed to, if any
+fn add_pusher(camera_config: &CameraConfig, stream_name: &str, outputs: &mut GstOutputs) {
+    if let Some(url) = camera_config.push_url(stream_name) {
+        let name = format!("{}/{}", camera_config.name, stream_name);
+        outputs.add_pusher(Pusher::new(&name, url));
+    }
+}
 
-        std::thread::sleep(current_backoff);
-        current_backoff = std::cmp::min(max_backoff, current_backoff * 2);
+/// Writes an HLS playlist of the stream into its own directory, and serves it over HTTP if
+/// there is an HTTP server
+fn add_hls(
+    hls: &HlsConfig,
+    (camera_name, stream_name): (&str, &str),
+    outputs: &mut GstOutputs,
+    http: Option<&mut HttpServer>,
+    permitted_users: &HashSet<&str>,
+) {
+    let directory = Path::new(&hls.directory)
+        .join(camera_name)
+        .join(stream_name);
+    if let Some(http) = http {
+        http.add_hls(
+            camera_name,
+            stream_name,
+            &directory,
+            outputs,
+            permitted_users,
+        );
     }
+    outputs.add_recorder(Recorder::new(RecordOptions {
+        directory,
+        prefix: camera_name.to_string(),
+        format: RecordFormat::Hls {
+            playlist_length: hls.playlist_length,
+        },
+        segment_length: Duration::from_secs(hls.segment_duration),
+        max_age: None,
+        max_size: None,
+        motion: None,
+    }));
 }
 
-struct CameraErr {
-    connected: bool,
-    err: neolink::Error,
+fn record_options(camera_name: &str, record: &RecordConfig) -> RecordOptions {
+    RecordOptions {
+        directory: record.directory.clone().into(),
+        prefix: camera_name.to_string(),
+        format: match &record.format as &str {
+            "mp4" => RecordFormat::Mp4,
+            "mkv" => RecordFormat::Matroska,
+            _ => unreachable!(),
+        },
+        segment_length: Duration::from_secs(record.segment_length),
+        max_age: record
+            .max_age_hours
+            .map(|hours| Duration::from_secs(hours * 60 * 60)),
+        max_size: record.max_size_mb.map(|mb| mb * 1024 * 1024),
+        motion: match &record.mode as &str {
+            "motion" => Some(MotionOptions {
+                pre_roll: Duration::from_secs(record.pre_roll),
+                post_roll: Duration::from_secs(record.post_roll),
+            }),
+            "continuous" => None,
+            _ => unreachable!(),
+        },
+    }
 }
 
 fn set_up_tls(config: &Config, rtsp: &RtspServer) {
@@ -189,76 +347,3 @@ fn get_permitted_users<'a>(
         None => ["anonymous"].iter().cloned().collect(),
     }
 }
-
-fn camera_main(
-    camera_config: &CameraConfig,
-    stream_name: &str,
-    outputs: &mut GstOutputs,
-    manage: bool,
-) -> Result<Never, CameraErr> {
-    let mut connected = false;
-    (|| {
-        if camera_config.timeout.is_some() {
-            warn!("The undocumented `timeout` config option has been removed and is no longer needed.");
-            warn!("Please update your config file.");
-        }
-
-        info!(
-            "{}: Connecting to camera at {}",
-            camera_config.name, camera_config.camera_addr
-        );
-
-        let mut camera = BcCamera::connect(&camera_config.camera_addr)?;
-
-        camera.login(&camera_config.username, camera_config.password.as_deref())?;
-
-        connected = true;
-        info!("{}: Connected and logged in", camera_config.name);
-
-        if manage {
-            do_camera_management(&mut camera, camera_config)?;
-        }
-
-        info!(
-            "{}: Starting video stream {}",
-            camera_config.name, stream_name
-        );
-        camera.start_video(outputs, stream_name, camera_config.channel_id)
-    })()
-    .map_err(|err| CameraErr { connected, err })
-}
-
-fn do_camera_management(
-    camera: &mut BcCamera,
-    camera_config: &CameraConfig,
-) -> Result<(), neolink::Error> {
-    let cam_time = camera.get_time()?;
-    if let Some(time) = cam_time {
-        info!(
-            "{}: Camera time is already set: {}",
-            camera_config.name, time
-        );
-    } else {
-        use time::OffsetDateTime;
-        // We'd like now_local() but it's deprecated - try to get the local time, but if no
-        // time zone, fall back to UTC.
-        let new_time =
-            OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
-
-        warn!(
-            "{}: Camera has no time set, setting to {}",
-            camera_config.name, new_time
-        );
-        camera.set_time(new_time)?;
-        let cam_time = camera.get_time()?;
-        if let Some(time) = cam_time {
-            info!("{}: Camera time is now set: {}", camera_config.name, time);
-        } else {
-            error!(
-                "{}: Camera did not accept new time (is {} an admin?)",
-                camera_config.name, camera_config.username
-            );
-        }
-    }
-    Ok(())
-}
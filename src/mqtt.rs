@@ -0,0 +1,196 @@
+use crate::config::MqttConfig;
+use crate::Error;
+use log::*;
+use rumqttc::{Client, Event, Incoming, LastWill, MqttOptions, QoS, TlsConfiguration, Transport};
+use std::fs;
+use std::time::Duration;
+
+/// A connection to the MQTT broker that camera threads publish through.  Cloning it is cheap and
+/// every clone shares the same connection, which a background thread keeps alive.
+#[derive(Clone)]
+pub struct Mqtt {
+    client: Client,
+    topic_prefix: String,
+}
+
+impl Mqtt {
+    pub fn connect(config: &MqttConfig) -> Result<Mqtt, Error> {
+        let mut options = MqttOptions::new(&config.client_id, &config.broker_addr, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+
+        // If neolink goes away without saying goodbye, the broker tells everyone for us
+        let status_topic = format!("{}/status", config.topic_prefix);
+        options.set_last_will(LastWill::new(
+            &status_topic,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+        }
+
+        if let Some(ca_path) = &config.ca {
+            let client_auth = match &config.client_auth {
+                Some((cert_path, key_path)) => Some((fs::read(cert_path)?, fs::read(key_path)?)),
+                None => None,
+            };
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Simple {
+                ca: fs::read(ca_path)?,
+                alpn: None,
+                client_auth,
+            }));
+        } else if config.client_auth.is_some() {
+            warn!("MQTT client_auth is ignored unless a ca is also given to turn on TLS");
+        }
+
+        let (client, mut connection) = Client::new(options, 16);
+
+        let status_client = client.clone();
+        std::thread::spawn(move || {
+            // rumqttc only makes progress (including reconnecting) while we poll it
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        // The broker may have published our last will since we were last here
+                        let _ = status_client.try_publish(
+                            &status_topic,
+                            QoS::AtLeastOnce,
+                            true,
+                            "online",
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("MQTT connection error, will retry in 5s: {}", e);
+                        std::thread::sleep(Duration::from_secs(5));
+                    }
+                }
+            }
+        });
+
+        Ok(Mqtt {
+            client,
+            topic_prefix: config.topic_prefix.clone(),
+        })
+    }
+
+    /// Publishes whether a camera currently sees motion, to `<prefix>/<camera>/motion`
+    pub fn publish_motion(&self, camera_name: &str, motion: bool) {
+        let payload = if motion { "on" } else { "off" };
+        self.publish(camera_name, "motion", false, payload);
+    }
+
+    /// Publishes whether neolink is connected to a camera, to `<prefix>/<camera>/status`.  This
+    /// is retained, so that subscribers learn the state as soon as they subscribe.
+    pub fn publish_status(&self, camera_name: &str, connected: bool) {
+        let payload = if connected { "online" } else { "offline" };
+        self.publish(camera_name, "status", true, payload);
+    }
+
+    fn publish(&self, camera_name: &str, sub_topic: &str, retain: bool, payload: &str) {
+        let topic = format!("{}/{}/{}", self.topic_prefix, camera_name, sub_topic);
+        // Never hold up a camera thread because the broker is unreachable; the event loop
+        // delivers queued messages once it reconnects
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            warn!("Could not publish to MQTT topic {}: {}", topic, e);
+        }
+    }
+}
+
+#[test]
+fn test_publish_to_broker() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // Just enough of a broker to accept a connection and acknowledge QoS 1 publishes
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let kind = byte[0];
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).unwrap();
+        (kind, body)
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let config = MqttConfig {
+        broker_addr: "127.0.0.1".to_string(),
+        port,
+        client_id: "neolink-test".to_string(),
+        topic_prefix: "neolink".to_string(),
+        username: None,
+        password: None,
+        ca: None,
+        client_auth: None,
+    };
+    let mqtt = Mqtt::connect(&config).unwrap();
+    mqtt.publish_status("driveway", true);
+    mqtt.publish_motion("driveway", true);
+    mqtt.publish_motion("driveway", false);
+
+    let (mut stream, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let (kind, _) = read_packet(&mut stream);
+    assert_eq!(kind, 0x10); // CONNECT
+    stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap(); // CONNACK, accepted
+
+    let mut published = vec![];
+    while published.len() < 4 {
+        let (kind, body) = read_packet(&mut stream);
+        if kind & 0xf0 != 0x30 {
+            continue;
+        }
+        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+        let packet_id = &body[2 + topic_len..4 + topic_len];
+        let payload = String::from_utf8(body[4 + topic_len..].to_vec()).unwrap();
+        stream
+            .write_all(&[0x40, 0x02, packet_id[0], packet_id[1]])
+            .unwrap(); // PUBACK
+        let retained = kind & 0x01 == 0x01;
+        published.push((topic, payload, retained));
+    }
+    // Order between the event loop's own status message and the camera ones is not fixed
+    published.sort();
+
+    assert_eq!(
+        published,
+        vec![
+            (
+                "neolink/driveway/motion".to_string(),
+                "off".to_string(),
+                false
+            ),
+            (
+                "neolink/driveway/motion".to_string(),
+                "on".to_string(),
+                false
+            ),
+            (
+                "neolink/driveway/status".to_string(),
+                "online".to_string(),
+                true
+            ),
+            ("neolink/status".to_string(), "online".to_string(), true),
+        ]
+    );
+}
@@ -0,0 +1,908 @@
+//! An ONVIF Profile S server, so that NVRs which only know how to find and stream ONVIF cameras
+//! can use Neolink's.  Each camera is its own ONVIF device, at
+//! `/onvif/<camera name>/device_service`; its streams are its media profiles, whose stream URIs
+//! point at the RTSP server, and PTZ requests are sent on to the camera.
+//!
+//! All of a camera's services share the one address, since requests are told apart by their
+//! operation alone.
+mod discovery;
+mod soap;
+
+pub use self::discovery::Discovery;
+
+use self::soap::{envelope, fault, SoapRequest};
+use crate::config::{CameraConfig, Config, OnvifConfig, UserConfig};
+use crate::session::CameraCommands;
+use crate::Error;
+use log::*;
+use neolink::bc_protocol::{PtzCommand, StreamInfo};
+use neolink::gst::{GstOutputs, StreamFormat};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+use tiny_http::{Header, Method, Request, Response, Server};
+use xml::escape::escape_str_pcdata;
+
+/// Characters left alone when a camera name is put in a URL
+const NAME_CHARS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+/// Requests bigger than this are refused rather than read
+const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+/// How many requests are handled at once.  PTZ requests may have to wait for the camera.
+const WORKERS: usize = 8;
+/// How far the Created time of a password digest may be from our clock.  Each nonce is refused
+/// a second time for as long as its digest would be accepted.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// The longest a move's timeout can keep it going for
+const MAX_MOVE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// The operations that ONVIF lets anyone call, so that clients can find out how to log in
+const PRE_AUTH_OPERATIONS: &[&str] = &["GetSystemDateAndTime", "GetCapabilities", "GetServices"];
+
+const PAN_TILT_VELOCITY_SPACE: &str =
+    "http://www.onvif.org/ver10/tptz/PanTiltSpaces/VelocityGenericSpace";
+const ZOOM_VELOCITY_SPACE: &str = "http://www.onvif.org/ver10/tptz/ZoomSpaces/VelocityGenericSpace";
+
+pub struct OnvifServer {
+    server: Server,
+    port: u16,
+    /// The RTSP server's URL scheme and port, for stream URIs
+    rtsp_scheme: &'static str,
+    rtsp_port: u16,
+    http_port: Option<u16>,
+    users: Vec<UserConfig>,
+    cameras: HashMap<String, Arc<OnvifCamera>>,
+    nonces: NonceCache,
+}
+
+/// The nonces of recently accepted password digests, so that a captured request can't be sent
+/// again
+#[derive(Default)]
+struct NonceCache {
+    seen: Mutex<HashMap<Vec<u8>, OffsetDateTime>>,
+}
+
+struct OnvifCamera {
+    config: CameraConfig,
+    streams: Vec<OnvifStream>,
+    permitted_users: HashSet<String>,
+    /// PTZ commands go over the camera's session
+    commands: CameraCommands,
+    /// Counts moves, so that a move's timeout doesn't stop a later one
+    moves: AtomicU64,
+}
+
+/// A stream, which ONVIF calls a profile
+struct OnvifStream {
+    name: String,
+    info: Arc<Mutex<Option<StreamInfo>>>,
+    format: Arc<Mutex<Option<StreamFormat>>>,
+}
+
+/// Why a request failed, to be sent back as a SOAP fault
+struct Fault {
+    /// Whether the client was at fault, rather than us or the camera
+    sender: bool,
+    subcode: &'static str,
+    reason: String,
+}
+
+impl Fault {
+    fn sender(subcode: &'static str, reason: impl Into<String>) -> Fault {
+        Fault {
+            sender: true,
+            subcode,
+            reason: reason.into(),
+        }
+    }
+
+    fn camera(camera_name: &str, error: neolink::Error) -> Fault {
+        warn!(
+            "ONVIF: Error sending PTZ command to {}: {}",
+            camera_name, error
+        );
+        Fault {
+            sender: false,
+            subcode: "ter:Action",
+            reason: error.to_string(),
+        }
+    }
+}
+
+impl OnvifServer {
+    pub fn bind(config: &Config, onvif: &OnvifConfig) -> Result<OnvifServer, Error> {
+        let server =
+            Server::http((&*config.bind_addr, onvif.port)).map_err(Error::HttpServerError)?;
+        Ok(OnvifServer {
+            server,
+            port: onvif.port,
+            rtsp_scheme: if config.certificate.is_some() {
+                "rtsps"
+            } else {
+                "rtsp"
+            },
+            rtsp_port: config.bind_port,
+            http_port: config.http_bind_port,
+            users: config.users.to_vec(),
+            cameras: HashMap::new(),
+            nonces: NonceCache::default(),
+        })
+    }
+
+    /// Offers `outputs` as one of the camera's profiles, named after the stream.  PTZ requests are
+    /// sent on through `commands`.
+    pub fn add_stream(
+        &mut self,
+        camera_config: &CameraConfig,
+        stream_name: &str,
+        outputs: &mut GstOutputs,
+        commands: &CameraCommands,
+        permitted_users: &HashSet<&str>,
+    ) {
+        let info = Arc::new(Mutex::new(None));
+        let stream_info = info.clone();
+        outputs
+            .on_stream_info(move |new_info| *stream_info.lock().unwrap() = Some(new_info.clone()));
+        let format = Arc::new(Mutex::new(None));
+        let video_format = format.clone();
+        outputs.on_video_format(move |new_format| *video_format.lock().unwrap() = Some(new_format));
+
+        let camera = self
+            .cameras
+            .entry(camera_config.name.clone())
+            .or_insert_with(|| {
+                Arc::new(OnvifCamera {
+                    config: camera_config.clone(),
+                    streams: vec![],
+                    permitted_users: permitted_users.iter().map(|u| u.to_string()).collect(),
+                    commands: commands.clone(),
+                    moves: AtomicU64::new(0),
+                })
+            });
+        Arc::get_mut(camera)
+            .expect("Streams are added before the server runs")
+            .streams
+            .push(OnvifStream {
+                name: stream_name.to_string(),
+                info,
+                format,
+            });
+    }
+
+    pub fn run(self) {
+        let this = Arc::new(self);
+        // PTZ commands may have to wait for the camera to connect first, so a few requests are
+        // handled at once, each worker taking the next request as soon as it is free
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|_| {
+                let this = this.clone();
+                std::thread::spawn(move || {
+                    for request in this.server.incoming_requests() {
+                        // A bug in one request mustn't take the worker down with it, as nothing
+                        // would replace it
+                        let respond = AssertUnwindSafe(|| this.respond(request));
+                        if std::panic::catch_unwind(respond).is_err() {
+                            error!("ONVIF: Panicked while handling a request");
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+
+    fn respond(&self, mut request: Request) {
+        let response = match self.handle(&mut request) {
+            Ok(body) => Response::from_string(envelope("", &body)),
+            Err(e) => {
+                let status = if e.sender { 400 } else { 500 };
+                Response::from_string(fault(e.sender, e.subcode, &e.reason))
+                    .with_status_code(status)
+            }
+        };
+        let response = response.with_header(
+            Header::from_bytes(
+                &b"Content-Type"[..],
+                &b"application/soap+xml; charset=utf-8"[..],
+            )
+            .unwrap(),
+        );
+        if let Err(e) = request.respond(response) {
+            debug!("ONVIF: Could not send response: {}", e);
+        }
+    }
+
+    fn handle(&self, request: &mut Request) -> Result<String, Fault> {
+        if *request.method() != Method::Post {
+            return Err(Fault::sender("ter:InvalidArgs", "Expected a SOAP request"));
+        }
+        let camera = self.camera_for(request.url()).ok_or_else(|| {
+            Fault::sender(
+                "ter:InvalidArgVal",
+                format!("No camera at {}", request.url()),
+            )
+        })?;
+        let host = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Host"))
+            .map(|h| h.value.as_str().to_string())
+            .unwrap_or_else(|| format!("localhost:{}", self.port));
+
+        let mut body = vec![];
+        request
+            .as_reader()
+            .take(MAX_REQUEST_SIZE)
+            .read_to_end(&mut body)
+            .map_err(|e| Fault::sender("ter:InvalidArgs", e.to_string()))?;
+        let soap = SoapRequest::parse(&body)
+            .map_err(|e| Fault::sender("ter:WellFormed", format!("Invalid request: {}", e)))?;
+        let operation = soap.operation().unwrap_or("");
+
+        if !PRE_AUTH_OPERATIONS.contains(&operation)
+            && !is_permitted(
+                &soap,
+                &self.users,
+                &camera.permitted_users,
+                &self.nonces,
+                OffsetDateTime::now_utc(),
+            )
+        {
+            return Err(Fault::sender("ter:NotAuthorized", "Sender not authorized"));
+        }
+        debug!("ONVIF: {} for {}", operation, camera.config.name);
+        self.operation(camera, operation, &soap, &host)
+    }
+
+    /// Finds the camera a request is for by its path.  If there is only one camera, it is also
+    /// at `/onvif/device_service`, for NVRs that can't be given a path.
+    fn camera_for(&self, url: &str) -> Option<&Arc<OnvifCamera>> {
+        let path = url.split('?').next().unwrap_or("");
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        let mut parts = path.trim_start_matches('/').split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("onvif"), Some(camera_name), Some(_service), None) => {
+                self.cameras.get(camera_name)
+            }
+            (Some("onvif"), Some(_service), None, None) if self.cameras.len() == 1 => {
+                self.cameras.values().next()
+            }
+            _ => None,
+        }
+    }
+
+    fn operation(
+        &self,
+        camera: &Arc<OnvifCamera>,
+        operation: &str,
+        soap: &SoapRequest,
+        host: &str,
+    ) -> Result<String, Fault> {
+        let name = &*camera.config.name;
+        let xaddr =
+            escape_str_pcdata(&format!("http://{}{}", host, device_path(name))).into_owned();
+        let host_name = host_name(host);
+
+        Ok(match operation {
+            // Device service
+            "GetSystemDateAndTime" => {
+                let now = OffsetDateTime::now_utc();
+                format!(
+                    concat!(
+                        "<tds:GetSystemDateAndTimeResponse><tds:SystemDateAndTime>",
+                        "<tt:DateTimeType>Manual</tt:DateTimeType>",
+                        "<tt:DaylightSavings>false</tt:DaylightSavings>",
+                        "<tt:TimeZone><tt:TZ>UTC0</tt:TZ></tt:TimeZone>",
+                        "<tt:UTCDateTime><tt:Time><tt:Hour>{}</tt:Hour><tt:Minute>{}</tt:Minute>",
+                        "<tt:Second>{}</tt:Second></tt:Time><tt:Date><tt:Year>{}</tt:Year>",
+                        "<tt:Month>{}</tt:Month><tt:Day>{}</tt:Day></tt:Date></tt:UTCDateTime>",
+                        "</tds:SystemDateAndTime></tds:GetSystemDateAndTimeResponse>"
+                    ),
+                    now.hour(),
+                    now.minute(),
+                    now.second(),
+                    now.year(),
+                    now.month(),
+                    now.day()
+                )
+            }
+            "GetDeviceInformation" => format!(
+                concat!(
+                    "<tds:GetDeviceInformationResponse>",
+                    "<tds:Manufacturer>Reolink</tds:Manufacturer><tds:Model>Neolink</tds:Model>",
+                    "<tds:FirmwareVersion>{}</tds:FirmwareVersion>",
+                    "<tds:SerialNumber>{}</tds:SerialNumber><tds:HardwareId>{}</tds:HardwareId>",
+                    "</tds:GetDeviceInformationResponse>"
+                ),
+                env!("NEOLINK_VERSION"),
+                escape_str_pcdata(name),
+                escape_str_pcdata(&camera.config.address().to_string()),
+            ),
+            "GetCapabilities" => format!(
+                concat!(
+                    "<tds:GetCapabilitiesResponse><tds:Capabilities>",
+                    "<tt:Device><tt:XAddr>{0}</tt:XAddr></tt:Device>",
+                    "<tt:Media><tt:XAddr>{0}</tt:XAddr><tt:StreamingCapabilities>",
+                    "<tt:RTPMulticast>false</tt:RTPMulticast><tt:RTP_TCP>true</tt:RTP_TCP>",
+                    "<tt:RTP_RTSP_TCP>true</tt:RTP_RTSP_TCP></tt:StreamingCapabilities></tt:Media>",
+                    "<tt:PTZ><tt:XAddr>{0}</tt:XAddr></tt:PTZ>",
+                    "</tds:Capabilities></tds:GetCapabilitiesResponse>"
+                ),
+                xaddr
+            ),
+            "GetServices" => {
+                let services = [
+                    ("http://www.onvif.org/ver10/device/wsdl", 2),
+                    ("http://www.onvif.org/ver10/media/wsdl", 2),
+                    ("http://www.onvif.org/ver20/ptz/wsdl", 2),
+                ]
+                .iter()
+                .map(|(namespace, major)| {
+                    format!(
+                        concat!(
+                            "<tds:Service><tds:Namespace>{}</tds:Namespace>",
+                            "<tds:XAddr>{}</tds:XAddr><tds:Version><tt:Major>{}</tt:Major>",
+                            "<tt:Minor>0</tt:Minor></tds:Version></tds:Service>"
+                        ),
+                        namespace, xaddr, major
+                    )
+                })
+                .collect::<String>();
+                format!(
+                    "<tds:GetServicesResponse>{}</tds:GetServicesResponse>",
+                    services
+                )
+            }
+            "GetScopes" => {
+                let scopes = scopes(name)
+                    .iter()
+                    .map(|scope| {
+                        format!(
+                            concat!(
+                                "<tds:Scopes><tt:ScopeDef>Fixed</tt:ScopeDef>",
+                                "<tt:ScopeItem>{}</tt:ScopeItem></tds:Scopes>"
+                            ),
+                            escape_str_pcdata(scope)
+                        )
+                    })
+                    .collect::<String>();
+                format!("<tds:GetScopesResponse>{}</tds:GetScopesResponse>", scopes)
+            }
+
+            // Media service
+            "GetProfiles" => {
+                let profiles = camera
+                    .streams
+                    .iter()
+                    .map(|stream| profile("trt:Profiles", camera, stream))
+                    .collect::<String>();
+                format!(
+                    "<trt:GetProfilesResponse>{}</trt:GetProfilesResponse>",
+                    profiles
+                )
+            }
+            "GetProfile" => {
+                let stream = camera.stream(soap)?;
+                format!(
+                    "<trt:GetProfileResponse>{}</trt:GetProfileResponse>",
+                    profile("trt:Profile", camera, stream)
+                )
+            }
+            "GetVideoSources" => {
+                let (width, height, fps) = camera.streams[0].size();
+                format!(
+                    concat!(
+                        r#"<trt:GetVideoSourcesResponse><trt:VideoSources token="source">"#,
+                        "<tt:Framerate>{}</tt:Framerate><tt:Resolution><tt:Width>{}</tt:Width>",
+                        "<tt:Height>{}</tt:Height></tt:Resolution>",
+                        "</trt:VideoSources></trt:GetVideoSourcesResponse>"
+                    ),
+                    fps, width, height
+                )
+            }
+            "GetStreamUri" => {
+                let stream = camera.stream(soap)?;
+                let uri = format!(
+                    "{}://{}:{}/{}/{}",
+                    self.rtsp_scheme,
+                    host_name,
+                    self.rtsp_port,
+                    utf8_percent_encode(name, NAME_CHARS),
+                    stream.name
+                );
+                format!(
+                    concat!(
+                        "<trt:GetStreamUriResponse><trt:MediaUri><tt:Uri>{}</tt:Uri>",
+                        "<tt:InvalidAfterConnect>false</tt:InvalidAfterConnect>",
+                        "<tt:InvalidAfterReboot>false</tt:InvalidAfterReboot>",
+                        "<tt:Timeout>PT0S</tt:Timeout></trt:MediaUri></trt:GetStreamUriResponse>"
+                    ),
+                    escape_str_pcdata(&uri)
+                )
+            }
+            "GetSnapshotUri" => {
+                let http_port = self.http_port.ok_or_else(|| {
+                    Fault::sender("ter:ActionNotSupported", "The HTTP server is not running")
+                })?;
+                let uri = format!(
+                    "http://{}:{}/{}/snapshot.jpg",
+                    host_name,
+                    http_port,
+                    utf8_percent_encode(name, NAME_CHARS)
+                );
+                format!(
+                    concat!(
+                        "<trt:GetSnapshotUriResponse><trt:MediaUri><tt:Uri>{}</tt:Uri>",
+                        "<tt:InvalidAfterConnect>false</tt:InvalidAfterConnect>",
+                        "<tt:InvalidAfterReboot>false</tt:InvalidAfterReboot>",
+                        "<tt:Timeout>PT0S</tt:Timeout></trt:MediaUri></trt:GetSnapshotUriResponse>"
+                    ),
+                    escape_str_pcdata(&uri)
+                )
+            }
+
+            // PTZ service
+            "GetNodes" => format!(
+                "<tptz:GetNodesResponse>{}</tptz:GetNodesResponse>",
+                ptz_node()
+            ),
+            "GetNode" => format!(
+                "<tptz:GetNodeResponse>{}</tptz:GetNodeResponse>",
+                ptz_node()
+            ),
+            "GetConfigurations" => format!(
+                "<tptz:GetConfigurationsResponse>{}</tptz:GetConfigurationsResponse>",
+                ptz_configuration("tptz:PTZConfiguration", camera)
+            ),
+            "GetConfiguration" => format!(
+                "<tptz:GetConfigurationResponse>{}</tptz:GetConfigurationResponse>",
+                ptz_configuration("tptz:PTZConfiguration", camera)
+            ),
+            "ContinuousMove" => {
+                let velocity = |element, attribute| {
+                    soap.find(element)
+                        .and_then(|e| e.attribute(attribute))
+                        .and_then(|v| v.parse::<f32>().ok())
+                        .unwrap_or(0.0)
+                };
+                let (command, speed) = velocity_to_command(
+                    velocity("PanTilt", "x"),
+                    velocity("PanTilt", "y"),
+                    velocity("Zoom", "x"),
+                );
+                let timeout = match soap.text("Timeout") {
+                    Some(timeout) => Some(
+                        parse_duration(timeout)
+                            .ok_or_else(|| Fault::sender("ter:InvalidArgVal", "Invalid Timeout"))?,
+                    ),
+                    None => None,
+                };
+                camera.start_move(command, speed, timeout)?;
+                "<tptz:ContinuousMoveResponse/>".to_string()
+            }
+            "Stop" => {
+                camera.moves.fetch_add(1, Ordering::SeqCst);
+                camera.ptz(PtzCommand::Stop, 0)?;
+                "<tptz:StopResponse/>".to_string()
+            }
+            "GetPresets" => {
+                let channel_id = camera.config.channel_id;
+                let presets = camera
+                    .commands
+                    .run(move |c| c.get_ptz_presets(channel_id))
+                    .map_err(|e| Fault::camera(name, e))?
+                    .into_iter()
+                    .map(|preset| {
+                        format!(
+                            r#"<tptz:Preset token="{}"><tt:Name>{}</tt:Name></tptz:Preset>"#,
+                            preset.id,
+                            escape_str_pcdata(&preset.name.unwrap_or_default())
+                        )
+                    })
+                    .collect::<String>();
+                format!(
+                    "<tptz:GetPresetsResponse>{}</tptz:GetPresetsResponse>",
+                    presets
+                )
+            }
+            "GotoPreset" => {
+                let preset_id = soap
+                    .text("PresetToken")
+                    .and_then(|token| token.parse().ok())
+                    .ok_or_else(|| Fault::sender("ter:InvalidArgVal", "Unknown preset"))?;
+                let channel_id = camera.config.channel_id;
+                camera
+                    .commands
+                    .run(move |c| c.goto_ptz_preset(channel_id, preset_id))
+                    .map_err(|e| Fault::camera(name, e))?;
+                "<tptz:GotoPresetResponse/>".to_string()
+            }
+
+            _ => {
+                return Err(Fault::sender(
+                    "ter:ActionNotSupported",
+                    format!("{} is not supported", operation),
+                ))
+            }
+        })
+    }
+}
+
+impl OnvifCamera {
+    /// The stream a request names by its ProfileToken
+    fn stream(&self, soap: &SoapRequest) -> Result<&OnvifStream, Fault> {
+        let token = soap.text("ProfileToken").unwrap_or("");
+        self.streams
+            .iter()
+            .find(|s| s.name == token)
+            .ok_or_else(|| Fault::sender("ter:InvalidArgVal", "No such profile"))
+    }
+
+    /// Starts moving the camera.  ONVIF moves can have a timeout, after which the camera is
+    /// stopped if it hasn't been sent another move since.
+    fn start_move(
+        self: &Arc<Self>,
+        command: PtzCommand,
+        speed: u32,
+        timeout: Option<Duration>,
+    ) -> Result<(), Fault> {
+        let this_move = self.moves.fetch_add(1, Ordering::SeqCst) + 1;
+        self.ptz(command, speed)?;
+        if let (Some(timeout), true) = (timeout, command != PtzCommand::Stop) {
+            let camera = self.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(timeout);
+                if camera.moves.load(Ordering::SeqCst) == this_move {
+                    let _ = camera.ptz(PtzCommand::Stop, 0);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn ptz(&self, command: PtzCommand, speed: u32) -> Result<(), Fault> {
+        let channel_id = self.config.channel_id;
+        self.commands
+            .run(move |c| c.ptz(channel_id, command, speed))
+            .map_err(|e| Fault::camera(&self.config.name, e))
+    }
+}
+
+impl OnvifStream {
+    /// Width, height and frame rate, which are zero until the stream has started once
+    fn size(&self) -> (u32, u32, u8) {
+        match &*self.info.lock().unwrap() {
+            Some(info) => (info.width, info.height, info.fps),
+            None => (0, 0, 0),
+        }
+    }
+}
+
+fn profile(element: &str, camera: &OnvifCamera, stream: &OnvifStream) -> String {
+    let (width, height, fps) = stream.size();
+    // Media profiles predate H.265, but the NVRs that take H.265 understand it here
+    let encoding = match *stream.format.lock().unwrap() {
+        Some(StreamFormat::H265) => "H265",
+        _ => "H264",
+    };
+    let (main_width, main_height, _) = camera.streams[0].size();
+    format!(
+        concat!(
+            r#"<{element} token="{name}" fixed="true"><tt:Name>{name}</tt:Name>"#,
+            r#"<tt:VideoSourceConfiguration token="source"><tt:Name>source</tt:Name>"#,
+            "<tt:UseCount>{streams}</tt:UseCount><tt:SourceToken>source</tt:SourceToken>",
+            r#"<tt:Bounds x="0" y="0" width="{main_width}" height="{main_height}"/>"#,
+            "</tt:VideoSourceConfiguration>",
+            r#"<tt:VideoEncoderConfiguration token="{name}"><tt:Name>{name}</tt:Name>"#,
+            "<tt:UseCount>1</tt:UseCount><tt:Encoding>{encoding}</tt:Encoding>",
+            "<tt:Resolution><tt:Width>{width}</tt:Width><tt:Height>{height}</tt:Height>",
+            "</tt:Resolution><tt:Quality>5</tt:Quality><tt:RateControl>",
+            "<tt:FrameRateLimit>{fps}</tt:FrameRateLimit>",
+            "<tt:EncodingInterval>1</tt:EncodingInterval><tt:BitrateLimit>0</tt:BitrateLimit>",
+            "</tt:RateControl><tt:SessionTimeout>PT60S</tt:SessionTimeout>",
+            "</tt:VideoEncoderConfiguration>{ptz}</{element}>"
+        ),
+        element = element,
+        name = stream.name,
+        streams = camera.streams.len(),
+        main_width = main_width,
+        main_height = main_height,
+        encoding = encoding,
+        width = width,
+        height = height,
+        fps = fps,
+        ptz = ptz_configuration("tt:PTZConfiguration", camera),
+    )
+}
+
+fn ptz_configuration(element: &str, camera: &OnvifCamera) -> String {
+    format!(
+        concat!(
+            r#"<{0} token="ptz"><tt:Name>ptz</tt:Name><tt:UseCount>{1}</tt:UseCount>"#,
+            "<tt:NodeToken>ptz</tt:NodeToken>",
+            "<tt:DefaultContinuousPanTiltVelocitySpace>{2}</tt:DefaultContinuousPanTiltVelocitySpace>",
+            "<tt:DefaultContinuousZoomVelocitySpace>{3}</tt:DefaultContinuousZoomVelocitySpace>",
+            "</{0}>"
+        ),
+        element,
+        camera.streams.len(),
+        PAN_TILT_VELOCITY_SPACE,
+        ZOOM_VELOCITY_SPACE
+    )
+}
+
+fn ptz_node() -> String {
+    let range = "<tt:Min>-1</tt:Min><tt:Max>1</tt:Max>";
+    format!(
+        concat!(
+            r#"<tptz:PTZNode token="ptz" FixedHomePosition="false"><tt:Name>ptz</tt:Name>"#,
+            "<tt:SupportedPTZSpaces><tt:ContinuousPanTiltVelocitySpace><tt:URI>{0}</tt:URI>",
+            "<tt:XRange>{2}</tt:XRange><tt:YRange>{2}</tt:YRange>",
+            "</tt:ContinuousPanTiltVelocitySpace><tt:ContinuousZoomVelocitySpace>",
+            "<tt:URI>{1}</tt:URI><tt:XRange>{2}</tt:XRange></tt:ContinuousZoomVelocitySpace>",
+            "</tt:SupportedPTZSpaces><tt:MaximumNumberOfPresets>64</tt:MaximumNumberOfPresets>",
+            "<tt:HomeSupported>false</tt:HomeSupported></tptz:PTZNode>"
+        ),
+        PAN_TILT_VELOCITY_SPACE, ZOOM_VELOCITY_SPACE, range
+    )
+}
+
+/// The path of a camera's ONVIF services
+fn device_path(camera_name: &str) -> String {
+    format!(
+        "/onvif/{}/device_service",
+        utf8_percent_encode(camera_name, NAME_CHARS)
+    )
+}
+
+/// The ONVIF scopes that describe a camera, which NVRs show when they discover it
+fn scopes(camera_name: &str) -> Vec<String> {
+    vec![
+        "onvif://www.onvif.org/type/video_encoder".to_string(),
+        "onvif://www.onvif.org/type/ptz".to_string(),
+        "onvif://www.onvif.org/Profile/Streaming".to_string(),
+        "onvif://www.onvif.org/hardware/Neolink".to_string(),
+        format!(
+            "onvif://www.onvif.org/name/{}",
+            utf8_percent_encode(camera_name, NAME_CHARS)
+        ),
+    ]
+}
+
+/// The host part of a Host header, without the port
+fn host_name(host: &str) -> &str {
+    match host.find(']') {
+        // An IPv6 address
+        Some(end) => &host[..=end],
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// ONVIF moves are velocities from -1 to 1 along each axis, but the camera only moves along one
+/// at a time.  This picks the fastest, with the camera's speed of 1 to 64.
+fn velocity_to_command(pan: f32, tilt: f32, zoom: f32) -> (PtzCommand, u32) {
+    let (command, velocity) = if pan.abs() >= tilt.abs() && pan.abs() >= zoom.abs() {
+        let command = if pan < 0.0 {
+            PtzCommand::Left
+        } else {
+            PtzCommand::Right
+        };
+        (command, pan.abs())
+    } else if tilt.abs() >= zoom.abs() {
+        let command = if tilt < 0.0 {
+            PtzCommand::Down
+        } else {
+            PtzCommand::Up
+        };
+        (command, tilt.abs())
+    } else {
+        let command = if zoom < 0.0 {
+            PtzCommand::ZoomOut
+        } else {
+            PtzCommand::ZoomIn
+        };
+        (command, zoom.abs())
+    };
+    if velocity < 0.01 {
+        return (PtzCommand::Stop, 0);
+    }
+    let speed = (velocity.min(1.0) * 64.0).round() as u32;
+    (command, speed.max(1))
+}
+
+/// Reads the simple xs:durations that ONVIF clients send, such as `PT1S` or `PT0.5S`.  Anything
+/// longer than `MAX_MOVE_TIMEOUT` is cut down to it.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let time = duration.strip_prefix("PT")?;
+    let mut total = 0.0;
+    let mut number = String::new();
+    for c in time.chars() {
+        match c {
+            '0'..='9' | '.' => number.push(c),
+            'H' | 'M' | 'S' => {
+                let unit = match c {
+                    'H' => 3600.0,
+                    'M' => 60.0,
+                    _ => 1.0,
+                };
+                total += number.parse::<f64>().ok()? * unit;
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    if !number.is_empty() || !total.is_finite() {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        total.min(MAX_MOVE_TIMEOUT.as_secs_f64()),
+    ))
+}
+
+/// Applies the same rules as the RTSP server to the request's WS-Security UsernameToken, which
+/// may carry the password as it is or as a digest
+fn is_permitted(
+    soap: &SoapRequest,
+    users: &[UserConfig],
+    permitted_users: &HashSet<String>,
+    nonces: &NonceCache,
+    now: OffsetDateTime,
+) -> bool {
+    if permitted_users.contains("anonymous") {
+        return true;
+    }
+    let (name, password) = match (soap.text("Username"), soap.find("Password")) {
+        (Some(name), Some(password)) => (name, password),
+        _ => return false,
+    };
+    let user = match users.iter().find(|u| u.name == name) {
+        Some(user) if permitted_users.contains(name) => user,
+        _ => return false,
+    };
+    if !matches!(password.attribute("Type"), Some(t) if t.ends_with("#PasswordDigest")) {
+        return password.text.trim() == user.pass;
+    }
+
+    let nonce = match soap.text("Nonce").map(base64::decode) {
+        Some(Ok(nonce)) => nonce,
+        _ => return false,
+    };
+    let created = soap.text("Created").unwrap_or("");
+    password.text.trim() == password_digest(&nonce, created, &user.pass)
+        && matches!(parse_created(created), Some(created) if nonces.check(&nonce, created, now))
+}
+
+impl NonceCache {
+    /// Whether a digest created at `created` with `nonce` is fresh and hasn't been seen before,
+    /// remembering it if so
+    fn check(&self, nonce: &[u8], created: OffsetDateTime, now: OffsetDateTime) -> bool {
+        if (now - created).abs() > MAX_CLOCK_SKEW {
+            return false;
+        }
+        let mut seen = self.seen.lock().unwrap();
+        // Anything older would be refused for its age anyway
+        seen.retain(|_, created| (now - *created).abs() <= MAX_CLOCK_SKEW);
+        seen.insert(nonce.to_vec(), created).is_none()
+    }
+}
+
+/// Reads the Created time of a password digest, such as `2020-01-01T00:00:00.123Z`
+fn parse_created(created: &str) -> Option<OffsetDateTime> {
+    let created = created.trim();
+    let date_time = PrimitiveDateTime::parse(created.get(..19)?, "%Y-%m-%dT%H:%M:%S").ok()?;
+    // Fractions of a second don't matter here
+    let zone = created[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" | "" => UtcOffset::UTC,
+        zone => UtcOffset::parse(zone.replace(':', ""), "%z").ok()?,
+    };
+    Some(date_time.assume_offset(offset))
+}
+
+/// Base64(SHA-1(nonce + created + password)), as WS-Security defines it
+fn password_digest(nonce: &[u8], created: &str, password: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(nonce);
+    sha1.update(created.as_bytes());
+    sha1.update(password.as_bytes());
+    base64::encode(sha1.digest().bytes())
+}
+
+#[test]
+fn test_velocity_to_command() {
+    assert_eq!(velocity_to_command(-1.0, 0.2, 0.0), (PtzCommand::Left, 64));
+    assert_eq!(velocity_to_command(0.5, 0.0, 0.0), (PtzCommand::Right, 32));
+    assert_eq!(velocity_to_command(0.1, -0.3, 0.0), (PtzCommand::Down, 19));
+    assert_eq!(velocity_to_command(0.0, 0.0, 0.001), (PtzCommand::Stop, 0));
+    assert_eq!(
+        velocity_to_command(0.0, 0.0, -2.0),
+        (PtzCommand::ZoomOut, 64)
+    );
+    assert_eq!(velocity_to_command(0.0, 0.0, 0.0), (PtzCommand::Stop, 0));
+
+    assert_eq!(parse_duration("PT1S"), Some(Duration::from_secs(1)));
+    assert_eq!(
+        parse_duration("PT1M0.5S"),
+        Some(Duration::from_millis(60_500))
+    );
+    assert_eq!(parse_duration("P1D"), None);
+    assert_eq!(
+        parse_duration("PT99999999999999999999S"),
+        Some(MAX_MOVE_TIMEOUT)
+    );
+    assert_eq!(parse_duration(&format!("PT{}S", "9".repeat(400))), None);
+}
+
+#[test]
+fn test_is_permitted() {
+    let users = vec![UserConfig {
+        name: "me".to_string(),
+        pass: "mepass".to_string(),
+    }];
+    let only_me: HashSet<String> = vec!["me".to_string()].into_iter().collect();
+    let anonymous: HashSet<String> = vec!["anonymous".to_string()].into_iter().collect();
+    let request = |token: &str| {
+        let request = format!(
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Header>
+                <Security><UsernameToken>{}</UsernameToken></Security>
+                </s:Header><s:Body><GetProfiles/></s:Body></s:Envelope>"#,
+            token
+        );
+        SoapRequest::parse(request.as_bytes()).unwrap()
+    };
+    let digest = |pass| {
+        let nonce = b"0123456789abcdef";
+        format!(
+            concat!(
+                "<Username>me</Username><Password Type=\"{}#PasswordDigest\">{}</Password>",
+                "<Nonce>{}</Nonce><Created>2020-01-01T00:00:00Z</Created>"
+            ),
+            "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0",
+            password_digest(nonce, "2020-01-01T00:00:00Z", pass),
+            base64::encode(nonce)
+        )
+    };
+
+    let nonces = NonceCache::default();
+    let now = time::date!(2020 - 01 - 01)
+        .with_time(time::time!(00:01))
+        .assume_utc();
+    let permitted = |token: &str, permitted_users| {
+        is_permitted(&request(token), &users, permitted_users, &nonces, now)
+    };
+
+    assert!(permitted("", &anonymous));
+    assert!(!permitted("", &only_me));
+    let text = "<Username>me</Username><Password>mepass</Password>";
+    assert!(permitted(text, &only_me));
+    assert!(!permitted(&digest("wrong"), &only_me));
+    assert!(permitted(&digest("mepass"), &only_me));
+    // The same digest can't be sent again
+    assert!(!permitted(&digest("mepass"), &only_me));
+
+    // Nor can one created too long ago
+    let later = now + MAX_CLOCK_SKEW;
+    let nonces = NonceCache::default();
+    assert!(!is_permitted(
+        &request(&digest("mepass")),
+        &users,
+        &only_me,
+        &nonces,
+        later
+    ));
+}
+
+#[test]
+fn test_parse_created() {
+    let midnight = time::date!(2020 - 01 - 01).midnight().assume_utc();
+    assert_eq!(parse_created("2020-01-01T00:00:00Z"), Some(midnight));
+    assert_eq!(parse_created(" 2020-01-01T00:00:00.123Z "), Some(midnight));
+    assert_eq!(parse_created("2020-01-01T01:00:00+01:00"), Some(midnight));
+    assert_eq!(parse_created("2020-01-01"), None);
+}
@@ -0,0 +1,193 @@
+//! Answers WS-Discovery probes, which is how NVRs find ONVIF cameras on the LAN
+use super::soap::{envelope, SoapRequest};
+use super::{device_path, scopes};
+use log::*;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::SystemTime;
+use xml::escape::escape_str_pcdata;
+
+const DISCOVERY_PORT: u16 = 3702;
+const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+pub struct Discovery {
+    socket: UdpSocket,
+    /// Our address, if the server is bound to one
+    bind_ip: Option<IpAddr>,
+    /// The ONVIF server's port
+    port: u16,
+    camera_names: Vec<String>,
+}
+
+impl Discovery {
+    pub fn bind(
+        bind_addr: &str,
+        port: u16,
+        camera_names: Vec<String>,
+    ) -> std::io::Result<Discovery> {
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        // Other ONVIF software on this host may be listening too
+        socket.set_reuse_address(true)?;
+        let any = SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT));
+        socket.bind(&SockAddr::from(any))?;
+        let socket = socket.into_udp_socket();
+        socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+
+        let bind_ip = bind_addr
+            .parse::<IpAddr>()
+            .ok()
+            .filter(|ip| !ip.is_unspecified());
+        Ok(Discovery {
+            socket,
+            bind_ip,
+            port,
+            camera_names,
+        })
+    }
+
+    pub fn run(self) {
+        let mut buf = vec![0; 65536];
+        loop {
+            let (len, sender) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("ONVIF: Discovery stopped: {}", e);
+                    return;
+                }
+            };
+            let ip = match self.bind_ip.or_else(|| local_ip_towards(sender)) {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let host = match ip {
+                IpAddr::V4(ip) => format!("{}:{}", ip, self.port),
+                IpAddr::V6(ip) => format!("[{}]:{}", ip, self.port),
+            };
+            for reply in probe_matches(&buf[..len], &self.camera_names, &host) {
+                debug!("ONVIF: Answering discovery probe from {}", sender);
+                if let Err(e) = self.socket.send_to(reply.as_bytes(), sender) {
+                    debug!("ONVIF: Could not answer discovery probe: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The address the prober can reach us at, which is the one we would send to it from
+fn local_ip_towards(addr: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(addr).ok()?;
+    socket.local_addr().ok().map(|local| local.ip())
+}
+
+/// The replies to a message, one per camera, if it is a probe for ONVIF devices.  `host` is where
+/// the ONVIF server can be reached.
+fn probe_matches(message: &[u8], camera_names: &[String], host: &str) -> Vec<String> {
+    let probe = match SoapRequest::parse(message) {
+        Ok(probe) if probe.operation() == Some("Probe") => probe,
+        _ => return vec![],
+    };
+    // Probes can ask for devices of particular types, or for any device
+    let wanted = probe.text("Types").unwrap_or("");
+    let wants_us = wanted.split_whitespace().all(|t| {
+        let local_name = t.rsplit(':').next().unwrap_or(t);
+        local_name == "NetworkVideoTransmitter" || local_name == "Device"
+    });
+    if !wants_us {
+        return vec![];
+    }
+    let message_id = probe.text("MessageID").unwrap_or("");
+
+    camera_names
+        .iter()
+        .map(|name| {
+            let header = format!(
+                concat!(
+                    "<a:MessageID>urn:uuid:{}</a:MessageID><a:RelatesTo>{}</a:RelatesTo>",
+                    "<a:To>http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous</a:To>",
+                    "<a:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/ProbeMatches</a:Action>"
+                ),
+                uuid(&[name, message_id, &format!("{:?}", SystemTime::now())]),
+                escape_str_pcdata(message_id)
+            );
+            let body = format!(
+                concat!(
+                    "<d:ProbeMatches><d:ProbeMatch><a:EndpointReference>",
+                    "<a:Address>urn:uuid:{}</a:Address></a:EndpointReference>",
+                    "<d:Types>dn:NetworkVideoTransmitter tds:Device</d:Types>",
+                    "<d:Scopes>{}</d:Scopes><d:XAddrs>{}</d:XAddrs>",
+                    "<d:MetadataVersion>1</d:MetadataVersion></d:ProbeMatch></d:ProbeMatches>"
+                ),
+                uuid(&[name]),
+                escape_str_pcdata(&scopes(name).join(" ")),
+                escape_str_pcdata(&format!("http://{}{}", host, device_path(name)))
+            );
+            envelope(&header, &body)
+        })
+        .collect()
+}
+
+/// A name-based UUID, so that each camera keeps the same one between runs
+fn uuid(names: &[&str]) -> String {
+    let mut bytes = md5::compute(names.join("\0")).0;
+    bytes[6] = (bytes[6] & 0x0f) | 0x30;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[test]
+fn test_probe_matches() {
+    let probe = |types: &str| {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"
+                xmlns:a="http://schemas.xmlsoap.org/ws/2004/08/addressing"
+                xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery"
+                xmlns:dn="http://www.onvif.org/ver10/network/wsdl">
+              <s:Header>
+                <a:MessageID>uuid:0a6dc791-2be6-4991-9af1-454778a1917a</a:MessageID>
+                <a:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</a:Action>
+              </s:Header>
+              <s:Body><d:Probe><d:Types>{}</d:Types></d:Probe></s:Body>
+            </s:Envelope>"#,
+            types
+        )
+    };
+    let cameras = vec!["driveway".to_string(), "storage shed".to_string()];
+
+    let replies = probe_matches(
+        probe("dn:NetworkVideoTransmitter").as_bytes(),
+        &cameras,
+        "192.168.1.10:8000",
+    );
+    assert_eq!(replies.len(), 2);
+    let reply = SoapRequest::parse(replies[1].as_bytes()).unwrap();
+    assert_eq!(reply.operation(), Some("ProbeMatches"));
+    assert_eq!(
+        reply.text("RelatesTo"),
+        Some("uuid:0a6dc791-2be6-4991-9af1-454778a1917a")
+    );
+    assert_eq!(
+        reply.text("XAddrs"),
+        Some("http://192.168.1.10:8000/onvif/storage%20shed/device_service")
+    );
+    assert_eq!(
+        reply.text("Address"),
+        Some(&*format!("urn:uuid:{}", uuid(&["storage shed"])))
+    );
+
+    // Any device at all
+    assert_eq!(probe_matches(probe("").as_bytes(), &cameras, "h").len(), 2);
+    // Printers don't want us
+    assert!(probe_matches(probe("p:Printer").as_bytes(), &cameras, "h").is_empty());
+    // Nor does our own reply
+    assert!(probe_matches(replies[0].as_bytes(), &cameras, "h").is_empty());
+}
@@ -0,0 +1,157 @@
+//! Just enough SOAP to read ONVIF and WS-Discovery requests and write their responses
+use xml::escape::escape_str_pcdata;
+use xml::reader::{EventReader, XmlEvent};
+
+pub const NS_ENVELOPE: &str = "http://www.w3.org/2003/05/soap-envelope";
+
+/// A request, flattened into its elements in document order.  Namespaces are dropped, since
+/// clients disagree on their prefixes and no two ONVIF elements we read share a name.
+pub struct SoapRequest {
+    elements: Vec<Element>,
+    operation: Option<usize>,
+}
+
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl SoapRequest {
+    pub fn parse(body: &[u8]) -> Result<SoapRequest, String> {
+        let mut elements = vec![];
+        let mut open: Vec<usize> = vec![];
+        let mut operation = None;
+        let mut body_depth = None;
+        for event in EventReader::new(body) {
+            match event.map_err(|e| e.to_string())? {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => {
+                    // The operation is the first thing inside the Body
+                    if body_depth == Some(open.len()) && operation.is_none() {
+                        operation = Some(elements.len());
+                    }
+                    if name.local_name == "Body" && name.namespace.as_deref() == Some(NS_ENVELOPE) {
+                        body_depth = Some(open.len() + 1);
+                    }
+                    open.push(elements.len());
+                    elements.push(Element {
+                        name: name.local_name,
+                        attributes: attributes
+                            .into_iter()
+                            .map(|a| (a.name.local_name, a.value))
+                            .collect(),
+                        text: String::new(),
+                    });
+                }
+                XmlEvent::EndElement { .. } => {
+                    open.pop();
+                }
+                XmlEvent::Characters(text) => {
+                    if let Some(&current) = open.last() {
+                        elements[current].text.push_str(&text);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(SoapRequest {
+            elements,
+            operation,
+        })
+    }
+
+    /// The name of the element in the Body, which says what is being asked for
+    pub fn operation(&self) -> Option<&str> {
+        self.operation.map(|i| self.elements[i].name.as_str())
+    }
+
+    /// The first element with this name
+    pub fn find(&self, name: &str) -> Option<&Element> {
+        self.elements.iter().find(|e| e.name == name)
+    }
+
+    /// The trimmed text of the first element with this name
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.find(name).map(|e| e.text.trim())
+    }
+}
+
+/// Wraps `body` in a SOAP envelope declaring the namespaces used by ONVIF responses
+pub fn envelope(header: &str, body: &str) -> String {
+    format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<s:Envelope xmlns:s="{}""#,
+            r#" xmlns:a="http://schemas.xmlsoap.org/ws/2004/08/addressing""#,
+            r#" xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery""#,
+            r#" xmlns:dn="http://www.onvif.org/ver10/network/wsdl""#,
+            r#" xmlns:tds="http://www.onvif.org/ver10/device/wsdl""#,
+            r#" xmlns:trt="http://www.onvif.org/ver10/media/wsdl""#,
+            r#" xmlns:tptz="http://www.onvif.org/ver20/ptz/wsdl""#,
+            r#" xmlns:tt="http://www.onvif.org/ver10/schema""#,
+            r#" xmlns:ter="http://www.onvif.org/ver10/error">"#,
+            "<s:Header>{}</s:Header><s:Body>{}</s:Body></s:Envelope>"
+        ),
+        NS_ENVELOPE, header, body
+    )
+}
+
+/// A SOAP fault.  `sender` says whether the client was at fault, rather than us or the camera.
+pub fn fault(sender: bool, subcode: &str, reason: &str) -> String {
+    let code = if sender { "s:Sender" } else { "s:Receiver" };
+    envelope(
+        "",
+        &format!(
+            concat!(
+                "<s:Fault><s:Code><s:Value>{}</s:Value>",
+                "<s:Subcode><s:Value>{}</s:Value></s:Subcode></s:Code>",
+                r#"<s:Reason><s:Text xml:lang="en">{}</s:Text></s:Reason></s:Fault>"#
+            ),
+            code,
+            subcode,
+            escape_str_pcdata(reason)
+        ),
+    )
+}
+
+#[test]
+fn test_parse_request() {
+    let request = br#"<?xml version="1.0" encoding="utf-8"?>
+        <soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope"
+            xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd"
+            xmlns:tptz="http://www.onvif.org/ver20/ptz/wsdl" xmlns:tt="http://www.onvif.org/ver10/schema">
+          <soap:Header>
+            <wsse:Security><wsse:UsernameToken>
+              <wsse:Username> me </wsse:Username>
+            </wsse:UsernameToken></wsse:Security>
+          </soap:Header>
+          <soap:Body>
+            <tptz:ContinuousMove>
+              <tptz:ProfileToken>mainStream</tptz:ProfileToken>
+              <tptz:Velocity><tt:PanTilt x="-0.5" y="0"/></tptz:Velocity>
+            </tptz:ContinuousMove>
+          </soap:Body>
+        </soap:Envelope>"#;
+    let request = SoapRequest::parse(request).unwrap();
+    assert_eq!(request.operation(), Some("ContinuousMove"));
+    assert_eq!(request.text("Username"), Some("me"));
+    assert_eq!(request.text("ProfileToken"), Some("mainStream"));
+    assert_eq!(
+        request.find("PanTilt").unwrap().attribute("x"),
+        Some("-0.5")
+    );
+    assert!(request.find("Zoom").is_none());
+
+    assert!(SoapRequest::parse(b"<unclosed>").is_err());
+}
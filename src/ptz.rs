@@ -0,0 +1,53 @@
+use crate::cmdline::PresetAction;
+use crate::config::Config;
+use crate::{connect_and_login, find_camera, Error};
+use log::*;
+use neolink::bc_protocol::PtzCommand;
+
+/// Sends a single PTZ command to the named camera, then logs out
+pub fn main(
+    config: &Config,
+    camera_name: &str,
+    command: PtzCommand,
+    speed: u32,
+) -> Result<(), Error> {
+    let camera_config = find_camera(config, camera_name)?;
+    let camera = connect_and_login(camera_config)?;
+
+    info!("{}: Sending PTZ command {}", camera_config.name, command);
+    camera.ptz(camera_config.channel_id, command, speed)?;
+
+    Ok(())
+}
+
+/// Lists the named camera's PTZ presets, or saves or recalls one of them
+pub fn preset_main(
+    config: &Config,
+    camera_name: &str,
+    action: Option<PresetAction>,
+) -> Result<(), Error> {
+    let camera_config = find_camera(config, camera_name)?;
+    let camera = connect_and_login(camera_config)?;
+    let channel_id = camera_config.channel_id;
+
+    match action {
+        None => {
+            for preset in camera.get_ptz_presets(channel_id)? {
+                println!("{}\t{}", preset.id, preset.name.unwrap_or_default());
+            }
+        }
+        Some(PresetAction::Save { id, name }) => {
+            info!(
+                "{}: Saving PTZ preset {} as {}",
+                camera_config.name, id, name
+            );
+            camera.set_ptz_preset(channel_id, id, &name)?;
+        }
+        Some(PresetAction::Goto { id }) => {
+            info!("{}: Moving to PTZ preset {}", camera_config.name, id);
+            camera.goto_ptz_preset(channel_id, id)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,766 @@
+//! A camera's session: one connection and login, shared by its main and sub streams, and by the
+//! jobs that only need doing once per camera such as setting its clock, keeping the connection
+//! alive and forwarding motion events.  Other parts of Neolink, such as ONVIF PTZ, send their
+//! commands over the session too, rather than logging in again.
+use crate::config::CameraConfig;
+use crate::mqtt::Mqtt;
+use crate::Error;
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use log::*;
+use neolink::bc_protocol::{BcCamera, LoginInfo, MotionDataSubscriber, MotionKind};
+use neolink::gst::{GstOutputs, RecordTrigger, StreamDemand};
+use neolink::Never;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the camera is pinged, to keep the session alive and to notice when it goes away
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How often threads waiting on something check whether they should give up
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The wait before the first retry after an error, which doubles with each failure in a row
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(15);
+/// How long a command waits for the session to connect and run it
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct CameraSession<'a> {
+    config: Arc<CameraConfig>,
+    streams: Vec<SessionStream>,
+    mqtt: Option<&'a Mqtt>,
+    commands: CameraCommands,
+    command_rx: Receiver<Command>,
+}
+
+/// Sends commands, such as PTZ moves, to the camera over its session.  Cloning it gives another
+/// handle on the same session.
+#[derive(Clone)]
+pub struct CameraCommands {
+    tx: Sender<Command>,
+    /// Keeps the session connected while a command is waiting to be run
+    demand: StreamDemand,
+}
+
+/// Something to do with the camera once the session is connected
+struct Command {
+    /// Nobody is waiting for the command after this, so it is dropped rather than run late
+    deadline: Instant,
+    run: Box<dyn FnOnce(&BcCamera) + Send>,
+}
+
+/// A stream the session produces, and the outputs it feeds
+struct SessionStream {
+    name: String,
+    outputs: GstOutputs,
+    /// None if the stream runs all the time, rather than only while a client is watching
+    demand: Option<StreamDemand>,
+}
+
+struct SessionErr {
+    connected: bool,
+    err: neolink::Error,
+}
+
+/// How long to wait before reconnecting after an error
+struct Backoff {
+    current: Duration,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff {
+            current: MIN_BACKOFF,
+        }
+    }
+
+    /// Starts over from the shortest wait, once things have gone right
+    fn reset(&mut self) {
+        self.current = MIN_BACKOFF;
+    }
+
+    /// How long to wait before retrying after `session_err`, or None if retrying can't help
+    fn after(&mut self, session_err: &SessionErr) -> Option<Duration> {
+        // Authentication failures are permanent; we retry everything else
+        if let neolink::Error::AuthFailed = session_err.err {
+            return None;
+        }
+        // Having got as far as logging in, the camera was fine until now
+        if session_err.connected {
+            self.reset();
+        }
+        let wait = self.current;
+        self.current = std::cmp::min(MAX_BACKOFF, self.current * 2);
+        Some(wait)
+    }
+}
+
+impl<'a> CameraSession<'a> {
+    pub fn new(config: Arc<CameraConfig>, mqtt: Option<&'a Mqtt>) -> CameraSession<'a> {
+        let (tx, command_rx) = unbounded();
+        CameraSession {
+            config,
+            streams: vec![],
+            mqtt,
+            commands: CameraCommands {
+                tx,
+                demand: StreamDemand::new(),
+            },
+            command_rx,
+        }
+    }
+
+    /// Sends commands to the camera while the session runs
+    pub fn commands(&self) -> CameraCommands {
+        self.commands.clone()
+    }
+
+    /// Streams `stream_name` into `outputs` while the session is connected
+    pub fn add_stream(&mut self, stream_name: &str, mut outputs: GstOutputs) {
+        let (camera_name, stream) = (self.config.name.clone(), stream_name.to_string());
+        outputs.on_stream_info(move |info| {
+            info!(
+                "{}: {} is {}x{} at {} fps",
+                camera_name, stream, info.width, info.height, info.fps
+            )
+        });
+
+        // Recording and pushing need the stream all the time too
+        let demand = if self.config.always_on
+            || self.config.record.is_some()
+            || self.config.push_url(stream_name).is_some()
+        {
+            None
+        } else {
+            Some(outputs.demand().clone())
+        };
+        self.streams.push(SessionStream {
+            name: stream_name.to_string(),
+            outputs,
+            demand,
+        });
+    }
+
+    /// Keeps the session connected whenever it is wanted, reconnecting after errors.  Only
+    /// returns if the camera rejects our credentials.
+    pub fn run(mut self) -> Result<Never, Error> {
+        let mut backoff = Backoff::new();
+
+        // The session starts out disconnected.  This also replaces any stale retained status
+        // left behind by a previous run.
+        if let Some(mqtt) = self.mqtt {
+            mqtt.publish_status(&self.config.name, false);
+        }
+
+        // The first connection is made even if nobody is watching yet, so that the streams'
+        // formats are known by the time a client asks for them
+        loop {
+            let result = self.connect_and_stream();
+            for stream in &mut self.streams {
+                stream.ended();
+            }
+            if let Some(mqtt) = self.mqtt {
+                mqtt.publish_status(&self.config.name, false);
+            }
+
+            let session_err = match result {
+                Ok(()) => {
+                    info!(
+                        "{}: Nobody is watching, disconnected from camera",
+                        self.config.name
+                    );
+                    backoff.reset();
+                    self.wait_for_client();
+                    continue;
+                }
+                Err(session_err) => session_err,
+            };
+            let wait = match backoff.after(&session_err) {
+                Some(wait) => wait,
+                None => {
+                    error!(
+                        "Authentication failed to camera {}, not retrying",
+                        self.config.name
+                    );
+                    return Err(session_err.err.into());
+                }
+            };
+            error!(
+                "Error streaming from camera {}, will retry in {}s: {}",
+                self.config.name,
+                wait.as_secs(),
+                session_err.err
+            );
+
+            std::thread::sleep(wait);
+            self.wait_for_client();
+        }
+    }
+
+    /// What wants the session connected: the demand of each stream, or None for something that
+    /// wants it all the time
+    fn demands(&self) -> Vec<Option<StreamDemand>> {
+        let mut demands: Vec<Option<StreamDemand>> = self
+            .streams
+            .iter()
+            .map(|stream| stream.demand.clone())
+            .collect();
+        demands.push(Some(self.commands.demand.clone()));
+        // Motion can only be published or recorded while connected, whether or not anyone watches
+        let motion_wanted = self.mqtt.is_some()
+            || self
+                .streams
+                .iter()
+                .any(|stream| stream.outputs.record_trigger().is_some());
+        if motion_wanted {
+            demands.push(None);
+        }
+        demands
+    }
+
+    /// Blocks until the session is wanted
+    fn wait_for_client(&self) {
+        let demands = self.demands();
+        if is_wanted(&demands) {
+            return;
+        }
+        info!("{}: Waiting for a client to connect", self.config.name);
+        while !is_wanted(&demands) {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Connects, logs in and streams until there is an error, or until nobody has watched any
+    /// stream for the camera's `idle_timeout`
+    fn connect_and_stream(&mut self) -> Result<(), SessionErr> {
+        // Held apart from self, which the closure below borrows
+        let config = self.config.clone();
+        let config = &*config;
+        let mqtt = self.mqtt;
+        let mut connected = false;
+        (|| {
+            if config.timeout.is_some() {
+                warn!("The undocumented `timeout` config option has been removed and is no longer needed.");
+                warn!("Please update your config file.");
+            }
+
+            info!(
+                "{}: Connecting to camera at {}",
+                config.name,
+                config.address()
+            );
+            let mut camera =
+                BcCamera::connect_with_transport(config.address(), config.transport())?;
+            let login_info = camera.login(&config.username, config.password.as_deref())?;
+
+            connected = true;
+            info!("{}: Connected and logged in", config.name);
+            log_login_info(&config.name, &login_info);
+
+            do_camera_management(&mut camera, config)?;
+
+            if let Some(mqtt) = mqtt {
+                mqtt.publish_status(&config.name, true);
+            }
+            // Motion is needed to publish it, or to record clips of it
+            let triggers: Vec<RecordTrigger> = self
+                .streams
+                .iter()
+                .filter_map(|stream| stream.outputs.record_trigger())
+                .collect();
+            let motion = if mqtt.is_some() || !triggers.is_empty() {
+                Some(camera.subscribe_motion()?)
+            } else {
+                None
+            };
+
+            let camera = &camera;
+            let finished = &AtomicBool::new(false);
+            let first_error = &Mutex::new(None);
+            let fail = |err: neolink::Error| {
+                first_error.lock().unwrap().get_or_insert(err);
+                finished.store(true, Ordering::SeqCst);
+            };
+            let subscribed = motion.is_some();
+            let demands = self.demands();
+            let streams = &mut self.streams;
+            let command_rx = &self.command_rx;
+            crossbeam::scope(|s| {
+                if let Some(motion) = motion {
+                    s.spawn(move |_| forward_motion(motion, config, mqtt, triggers));
+                }
+                s.spawn(move |_| run_commands(command_rx, camera, finished));
+                let mut stream_threads = vec![];
+                for stream in streams.iter_mut() {
+                    stream_threads.push(s.spawn(move |_| {
+                        if let Err(err) = stream.run(camera, config, finished) {
+                            fail(err);
+                        }
+                    }));
+                }
+                let grace = Duration::from_secs(config.idle_timeout);
+                if let Err(err) = keep_alive(&demands, grace, finished, || camera.ping()) {
+                    fail(err);
+                }
+                let mut panicked = false;
+                for thread in stream_threads {
+                    panicked |= thread.join().is_err();
+                }
+                // The motion thread only finishes once it is unsubscribed
+                if subscribed {
+                    camera.unsubscribe_motion();
+                }
+                assert!(!panicked, "Stream thread panicked");
+            })
+            .expect("Camera helper thread panicked");
+
+            let first_error = first_error.lock().unwrap().take();
+            match first_error {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        })()
+        .map_err(|err| SessionErr { connected, err })
+    }
+}
+
+impl CameraCommands {
+    /// Runs `command` on the camera, connecting the session first if nothing else wants it.
+    /// Commands are run one at a time, in the order they are sent, so a PTZ stop can't overtake
+    /// the move it stops.
+    pub fn run<T, F>(&self, command: F) -> Result<T, neolink::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&BcCamera) -> Result<T, neolink::Error> + Send + 'static,
+    {
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        self.demand.watch_until(deadline);
+        let (reply_tx, reply_rx) = bounded(1);
+        let command = Command {
+            deadline,
+            run: Box::new(move |camera| {
+                let _ = reply_tx.send(command(camera));
+            }),
+        };
+        if self.tx.send(command).is_err() {
+            return Err(neolink::Error::Other("The camera's session has ended"));
+        }
+        match reply_rx.recv_timeout(COMMAND_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(neolink::Error::Timeout),
+            // The session dropped the command without running it
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(neolink::Error::Other("The camera's session has ended"))
+            }
+        }
+    }
+}
+
+impl SessionStream {
+    /// Streams whenever a client is watching, until the session is finished
+    fn run(
+        &mut self,
+        camera: &BcCamera,
+        config: &CameraConfig,
+        finished: &AtomicBool,
+    ) -> Result<(), neolink::Error> {
+        let mut first = true;
+        while !finished.load(Ordering::SeqCst) {
+            // Even if nobody is watching, the stream runs when the session starts, to learn its
+            // format; stop_when_done soon stops it again
+            if let (Some(demand), false) = (&self.demand, first) {
+                if !demand.is_watched() {
+                    std::thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            }
+            first = false;
+
+            info!("{}: Starting video stream {}", config.name, self.name);
+            let video_done = &AtomicBool::new(false);
+            let (name, outputs, demand) = (&self.name, &mut self.outputs, &self.demand);
+            crossbeam::scope(|s| {
+                s.spawn(move |_| {
+                    stop_when_done(camera, config, name, demand.as_ref(), finished, video_done)
+                });
+                let result = camera.start_video(outputs, name, config.channel_id);
+                video_done.store(true, Ordering::SeqCst);
+                result
+            })
+            .expect("Camera helper thread panicked")?;
+            self.ended();
+
+            if finished.load(Ordering::SeqCst) {
+                break;
+            }
+            // Otherwise only the idle watcher asks the camera to stop, so the stream ending is as
+            // good as an error
+            if self.demand.is_none() {
+                return Err(neolink::Error::Other("Video stream stopped"));
+            }
+            info!(
+                "{}: Nobody is watching {}, stopped it",
+                config.name, self.name
+            );
+        }
+        Ok(())
+    }
+
+    fn ended(&mut self) {
+        self.outputs.vidsrc.on_stream_error();
+        self.outputs.audsrc.on_stream_error();
+        self.outputs.stream_ended();
+    }
+}
+
+/// Whether anything wants the session connected
+fn is_wanted(demands: &[Option<StreamDemand>]) -> bool {
+    demands.iter().any(|demand| match demand {
+        Some(demand) => demand.is_watched(),
+        None => true,
+    })
+}
+
+/// Whether nothing has wanted the session for `grace`
+fn is_idle(demands: &[Option<StreamDemand>], grace: Duration) -> bool {
+    demands.iter().all(|demand| match demand {
+        Some(demand) => matches!(demand.idle_for(), Some(idle) if idle >= grace),
+        None => false,
+    })
+}
+
+/// Pings the camera while the session lasts, and finishes it once nothing has wanted it for
+/// `grace`
+fn keep_alive(
+    demands: &[Option<StreamDemand>],
+    grace: Duration,
+    finished: &AtomicBool,
+    mut ping: impl FnMut() -> Result<(), neolink::Error>,
+) -> Result<(), neolink::Error> {
+    let mut last_ping = Instant::now();
+    while !finished.load(Ordering::SeqCst) {
+        std::thread::sleep(POLL_INTERVAL);
+        if is_idle(demands, grace) {
+            finished.store(true, Ordering::SeqCst);
+            break;
+        }
+        if last_ping.elapsed() >= PING_INTERVAL {
+            ping()?;
+            last_ping = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+/// Stops the video stream once no client has been watching it for the camera's `idle_timeout`,
+/// or once the session is finished, which makes `start_video` return
+fn stop_when_done(
+    camera: &BcCamera,
+    config: &CameraConfig,
+    stream_name: &str,
+    demand: Option<&StreamDemand>,
+    finished: &AtomicBool,
+    video_done: &AtomicBool,
+) {
+    let grace = Duration::from_secs(config.idle_timeout);
+    while !video_done.load(Ordering::SeqCst) {
+        std::thread::sleep(POLL_INTERVAL);
+        if finished.load(Ordering::SeqCst) {
+            debug!(
+                "{}: Session finished, stopping {}",
+                config.name, stream_name
+            );
+        } else if matches!(demand.and_then(StreamDemand::idle_for), Some(idle) if idle >= grace) {
+            debug!(
+                "{}: Idle for {}s, stopping {}",
+                config.name,
+                grace.as_secs(),
+                stream_name
+            );
+        } else {
+            continue;
+        }
+        match camera.stop_video(stream_name, config.channel_id) {
+            Ok(()) => break,
+            Err(e) => warn!(
+                "{}: Could not stop idle {}: {}",
+                config.name, stream_name, e
+            ),
+        }
+    }
+}
+
+/// Runs the commands sent to the session until it is finished
+fn run_commands(command_rx: &Receiver<Command>, camera: &BcCamera, finished: &AtomicBool) {
+    while !finished.load(Ordering::SeqCst) {
+        if let Ok(command) = command_rx.recv_timeout(POLL_INTERVAL) {
+            if Instant::now() < command.deadline {
+                (command.run)(camera);
+            } else {
+                debug!("Dropping a command that waited too long for the camera");
+            }
+        }
+    }
+}
+
+fn forward_motion(
+    motion: MotionDataSubscriber,
+    camera_config: &CameraConfig,
+    mqtt: Option<&Mqtt>,
+    triggers: Vec<RecordTrigger>,
+) {
+    for event in motion {
+        match event {
+            Ok(event) if event.channel_id == camera_config.channel_id => {
+                debug!("{}: Motion event {:?}", camera_config.name, event);
+                let moving = event.kind == MotionKind::Start;
+                if let Some(mqtt) = mqtt {
+                    mqtt.publish_motion(&camera_config.name, moving);
+                }
+                for trigger in &triggers {
+                    if moving {
+                        trigger.motion_started();
+                    } else {
+                        trigger.motion_stopped();
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(
+                    "{}: Stopped receiving motion events: {}",
+                    camera_config.name, e
+                );
+                break;
+            }
+        }
+    }
+    // We can't see motion any more, so don't leave it stuck on
+    if let Some(mqtt) = mqtt {
+        mqtt.publish_motion(&camera_config.name, false);
+    }
+    for trigger in triggers {
+        trigger.motion_stopped();
+    }
+}
+
+/// Says what the camera told us about itself, and what its streams offer
+fn log_login_info(name: &str, login_info: &LoginInfo) {
+    fn or_unknown<T: ToString>(value: &Option<T>) -> String {
+        value
+            .as_ref()
+            .map_or_else(|| "unknown".to_string(), T::to_string)
+    }
+
+    let device = &login_info.device_info;
+    info!(
+        "{}: {} ({}), firmware {}, {} channel(s), {} audio channel(s), SD card: {}, PTZ: {}, {}",
+        name,
+        or_unknown(&device.type_),
+        or_unknown(&device.type_info),
+        or_unknown(&device.firm_version),
+        or_unknown(&device.channel_num),
+        or_unknown(&device.audio_num),
+        match device.sd_card {
+            Some(0) => "no",
+            Some(_) => "yes",
+            None => "unknown",
+        },
+        or_unknown(&device.ptz_mode),
+        or_unknown(&device.norm),
+    );
+
+    let streams = login_info
+        .stream_info_list
+        .iter()
+        .flat_map(|list| &list.stream_infos)
+        .flat_map(|stream_info| &stream_info.encode_tables);
+    for stream in streams {
+        info!(
+            "{}: {} is {}x{}, {} fps at {} kbps by default; frame rates: {}, bit rates (kbps): {}",
+            name,
+            stream.type_,
+            stream.resolution.width,
+            stream.resolution.height,
+            stream.default_framerate,
+            stream.default_bitrate,
+            stream.framerate_table,
+            stream.bitrate_table,
+        );
+    }
+}
+
+fn do_camera_management(
+    camera: &mut BcCamera,
+    camera_config: &CameraConfig,
+) -> Result<(), neolink::Error> {
+    let cam_time = camera.get_time()?;
+    if let Some(time) = cam_time {
+        info!(
+            "{}: Camera time is already set: {}",
+            camera_config.name, time
+        );
+    } else {
+        use time::OffsetDateTime;
+        // We'd like now_local() but it's deprecated - try to get the local time, but if no
+        // time zone, fall back to UTC.
+        let new_time =
+            OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+
+        warn!(
+            "{}: Camera has no time set, setting to {}",
+            camera_config.name, new_time
+        );
+        camera.set_time(new_time)?;
+        let cam_time = camera.get_time()?;
+        if let Some(time) = cam_time {
+            info!("{}: Camera time is now set: {}", camera_config.name, time);
+        } else {
+            error!(
+                "{}: Camera did not accept new time (is {} an admin?)",
+                camera_config.name, camera_config.username
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_motion_keeps_session() {
+    use crate::config::MqttConfig;
+    use std::net::TcpListener;
+
+    let config: CameraConfig = toml::from_str(
+        r#"
+        name = "driveway"
+        address = "192.168.1.10:9000"
+        username = "admin"
+        "#,
+    )
+    .unwrap();
+    let config = Arc::new(config);
+
+    // A broker that never answers is enough to have MQTT configured
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mqtt = Mqtt::connect(&MqttConfig {
+        broker_addr: "127.0.0.1".to_string(),
+        port: listener.local_addr().unwrap().port(),
+        client_id: "neolink-test".to_string(),
+        topic_prefix: "neolink".to_string(),
+        username: None,
+        password: None,
+        ca: None,
+        client_auth: None,
+    })
+    .unwrap();
+
+    let session = CameraSession::new(config.clone(), Some(&mqtt));
+    let demands = session.demands();
+    assert!(is_wanted(&demands));
+    assert!(!is_idle(&demands, Duration::from_secs(0)));
+
+    // Without anything to publish, nothing wants it
+    let session = CameraSession::new(config, None);
+    let demands = session.demands();
+    assert!(!is_wanted(&demands));
+    assert!(is_idle(&demands, Duration::from_secs(0)));
+}
+
+#[test]
+fn test_commands_want_session() {
+    let config: CameraConfig = toml::from_str(
+        r#"
+        name = "driveway"
+        address = "192.168.1.10:9000"
+        username = "admin"
+        "#,
+    )
+    .unwrap();
+    let session = CameraSession::new(Arc::new(config), None);
+    assert!(!is_wanted(&session.demands()));
+
+    // A command connects the session, and is given up on if the session drops it
+    let commands = session.commands();
+    let command = std::thread::spawn(move || commands.run(|_| Ok(())));
+    let sent = session.command_rx.recv_timeout(COMMAND_TIMEOUT).unwrap();
+    assert!(is_wanted(&session.demands()));
+    drop(sent);
+    assert!(matches!(
+        command.join().unwrap(),
+        Err(neolink::Error::Other(_))
+    ));
+}
+
+#[test]
+fn test_idle_watcher() {
+    let grace = Duration::from_secs(60);
+    let (watched, unwatched) = (StreamDemand::new(), StreamDemand::new());
+    let _client = watched.watch();
+
+    // One watched stream is enough to want the session, and to keep it from going idle
+    let demands = vec![Some(watched.clone()), Some(unwatched.clone())];
+    assert!(is_wanted(&demands));
+    assert!(!is_idle(&demands, Duration::from_secs(0)));
+
+    // Unwatched streams only go idle after the grace period
+    let demands = vec![Some(unwatched.clone())];
+    assert!(!is_wanted(&demands));
+    assert!(!is_idle(&demands, grace));
+    assert!(is_idle(&demands, Duration::from_secs(0)));
+    unwatched.watch_until(Instant::now() + grace);
+    assert!(is_wanted(&demands));
+    assert!(!is_idle(&demands, Duration::from_secs(0)));
+
+    // The watcher finishes an idle session, without pinging it
+    let demands = vec![Some(StreamDemand::new())];
+    let finished = AtomicBool::new(false);
+    keep_alive(&demands, Duration::from_secs(0), &finished, || {
+        panic!("Pinged an idle session")
+    })
+    .unwrap();
+    assert!(finished.load(Ordering::SeqCst));
+
+    // But leaves a watched one running until something else finishes it
+    let demands = vec![Some(watched)];
+    let finished = AtomicBool::new(false);
+    let stopped = AtomicBool::new(false);
+    crossbeam::scope(|s| {
+        s.spawn(|_| {
+            keep_alive(&demands, Duration::from_secs(0), &finished, || Ok(())).unwrap();
+            stopped.store(true, Ordering::SeqCst);
+        });
+        std::thread::sleep(POLL_INTERVAL * 2);
+        assert!(!stopped.load(Ordering::SeqCst));
+        finished.store(true, Ordering::SeqCst);
+    })
+    .unwrap();
+    assert!(stopped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_backoff() {
+    let failed = |connected| SessionErr {
+        connected,
+        err: neolink::Error::Other("Camera went away"),
+    };
+    let mut backoff = Backoff::new();
+
+    // Failing to connect again and again waits longer each time, up to a limit
+    let waits: Vec<u64> = (0..6)
+        .map(|_| backoff.after(&failed(false)).unwrap().as_secs())
+        .collect();
+    assert_eq!(waits, [1, 2, 4, 8, 15, 15]);
+
+    // Losing a session that did connect starts over
+    assert_eq!(backoff.after(&failed(true)), Some(MIN_BACKOFF));
+    assert_eq!(backoff.after(&failed(false)), Some(MIN_BACKOFF * 2));
+    backoff.reset();
+    assert_eq!(backoff.after(&failed(false)), Some(MIN_BACKOFF));
+
+    // Wrong credentials are never retried, even after connecting
+    let auth_failed = |connected| SessionErr {
+        connected,
+        err: neolink::Error::AuthFailed,
+    };
+    assert_eq!(backoff.after(&auth_failed(false)), None);
+    assert_eq!(backoff.after(&auth_failed(true)), None);
+}
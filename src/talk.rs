@@ -0,0 +1,139 @@
+use crate::config::Config;
+use crate::{connect_and_login, find_camera, Error};
+use log::*;
+use neolink::bc::xml::{AudioConfig, TalkAbility, TalkConfig};
+use std::path::Path;
+
+/// Plays a WAV file through the named camera's speaker, then logs out
+pub fn main(config: &Config, camera_name: &str, file: &Path) -> Result<(), Error> {
+    let camera_config = find_camera(config, camera_name)?;
+
+    // Read the file first, so that a bad file doesn't leave the speaker open
+    let reader = hound::WavReader::open(file)?;
+
+    let camera = connect_and_login(camera_config)?;
+    let channel_id = camera_config.channel_id;
+
+    let talk_ability = camera.talk_ability(channel_id)?;
+    let talk_config = choose_talk_config(channel_id, talk_ability).ok_or(neolink::Error::Other(
+        "Camera does not offer any supported talk audio format",
+    ))?;
+    let samples = read_wav(reader, talk_config.audio_config.sample_rate)?;
+
+    info!(
+        "{}: Playing {} ({:.1}s)",
+        camera_config.name,
+        file.display(),
+        samples.len() as f32 / talk_config.audio_config.sample_rate as f32
+    );
+    camera.talk_start(&talk_config)?;
+    let result = camera.talk(&talk_config, &samples);
+    // Always try to close the speaker, even if sending the audio failed part way
+    camera.talk_stop(channel_id)?;
+    result?;
+
+    Ok(())
+}
+
+fn choose_talk_config(channel_id: u32, talk_ability: TalkAbility) -> Option<TalkConfig> {
+    // Lower numbers are the camera's preference
+    let audio_config: AudioConfig = talk_ability
+        .audio_config_list
+        .audio_config
+        .into_iter()
+        .filter(|c| c.audio_type == "adpcm" && c.sound_track == "mono")
+        .min_by_key(|c| c.priority.unwrap_or(0))?;
+    Some(TalkConfig {
+        version: neolink::bc::xml::xml_ver(),
+        channel_id,
+        duplex: talk_ability
+            .duplex_list
+            .duplex
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| "FDX".to_string()),
+        audio_stream_mode: talk_ability
+            .audio_stream_mode_list
+            .audio_stream_mode
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| "followVideoStream".to_string()),
+        audio_config: AudioConfig {
+            priority: None,
+            ..audio_config
+        },
+        ..Default::default()
+    })
+}
+
+/// Reads any WAV file as 16 bit mono samples at `sample_rate`
+fn read_wav<R: std::io::Read>(
+    reader: hound::WavReader<R>,
+    sample_rate: u32,
+) -> Result<Vec<i16>, Error> {
+    let spec = reader.spec();
+    let samples: Vec<i16> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .map(|s| s.map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16))
+            .collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let bits = spec.bits_per_sample as i32;
+            reader
+                .into_samples::<i32>()
+                .map(|s| {
+                    s.map(|s| {
+                        if bits > 16 {
+                            (s >> (bits - 16)) as i16
+                        } else {
+                            (s << (16 - bits)) as i16
+                        }
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let mono = downmix(&samples, spec.channels as usize);
+    Ok(resample(&mono, spec.sample_rate, sample_rate))
+}
+
+fn downmix(samples: &[i16], channels: usize) -> Vec<i16> {
+    if channels <= 1 {
+        return samples.to_vec();
+    }
+    samples
+        .chunks(channels)
+        .map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / frame.len() as i32) as i16)
+        .collect()
+}
+
+/// Linear interpolation is crude, but speech through a camera speaker is hardly hi-fi
+fn resample(samples: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let out_len = (samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    (0..out_len)
+        .map(|i| {
+            let pos = i as f64 * from_rate as f64 / to_rate as f64;
+            let idx = pos as usize;
+            let frac = pos - idx as f64;
+            let a = samples[idx] as f64;
+            let b = *samples.get(idx + 1).unwrap_or(&samples[idx]) as f64;
+            (a + (b - a) * frac) as i16
+        })
+        .collect()
+}
+
+#[test]
+fn test_downmix_and_resample() {
+    assert_eq!(downmix(&[100, 300, -50, 50], 2), vec![200, 0]);
+    assert_eq!(downmix(&[1, 2, 3], 1), vec![1, 2, 3]);
+
+    assert_eq!(
+        resample(&[0, 100, 200, 300], 8000, 16000),
+        vec![0, 50, 100, 150, 200, 250, 300, 300]
+    );
+    assert_eq!(resample(&[0, 100, 200, 300], 16000, 8000), vec![0, 200]);
+    assert_eq!(resample(&[1, 2], 16000, 16000), vec![1, 2]);
+}